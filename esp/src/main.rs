@@ -8,6 +8,7 @@ use crate::sta::connection;
 use core::str::FromStr;
 use embassy_executor::Spawner;
 use embassy_net::{Config, ConfigV6, Ipv6Address, Ipv6Cidr, Stack, StackResources, StaticConfigV6};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_time::{Duration, Timer};
 use esp_backtrace as _;
 use esp_hal::timer::timg::TimerGroup;
@@ -18,15 +19,28 @@ use esp_println::println;
 use esp_wifi::wifi::WifiDevice;
 use esp_wifi::{initialize, wifi::WifiStaDevice, EspWifiInitFor};
 use log::info;
-use mqtt_server::config::InnerDistributorMutex;
+use mqtt_server::config::{subscriber_words, InnerDistributorMutex};
 use mqtt_server::distributor::InnerDistributor;
 use static_cell::make_static;
 
-use mqtt_server::socket::listen;
+use mqtt_server::acceptor::{ListenerConfig, DEFAULT_SOCKET_BUFFER_SIZE};
 
 const SSID: &str = env!("SSID");
 const PASSWORD: &str = env!("PASSWORD");
 const MAX_CONNECTIONS: usize = 14;
+// memory budget of the broker, see `mqtt_server::config` for the defaults
+const QUEUE_LEN: usize = 1;
+const TREE_SIZE: usize = 64;
+const MAX_MESSAGE_SIZE: usize = 1024;
+type Distributor = InnerDistributorMutex<
+    NoopRawMutex,
+    MAX_CONNECTIONS,
+    { subscriber_words(MAX_CONNECTIONS) },
+    QUEUE_LEN,
+    TREE_SIZE,
+    MAX_MESSAGE_SIZE,
+>;
+type Acceptor = mqtt_server::acceptor::Acceptor<NoopRawMutex, MAX_CONNECTIONS, MAX_CONNECTIONS>;
 
 #[main]
 async fn main(spawner: Spawner) -> ! {
@@ -96,10 +110,21 @@ async fn main(spawner: Spawner) -> ! {
         }
         Timer::after(Duration::from_millis(500)).await;
     }
-    let distributor = &*make_static!(InnerDistributorMutex::new(InnerDistributor::default()));
-    // spawn listeners for concurrent connections
-    for i in 0..MAX_CONNECTIONS {
-        spawner.spawn(listen_task(stack, i, 1883, distributor)).ok();
+    let distributor: &'static Distributor =
+        make_static!(InnerDistributorMutex::new(InnerDistributor::default()));
+    // every accepted connection leases a distributor slot and socket buffers from the acceptor
+    let buffers = make_static!(
+        [([0; DEFAULT_SOCKET_BUFFER_SIZE], [0; DEFAULT_SOCKET_BUFFER_SIZE]); MAX_CONNECTIONS]
+    );
+    let listener = ListenerConfig {
+        port: 1883,
+        ..Default::default()
+    };
+    let acceptor: &'static Acceptor =
+        make_static!(Acceptor::new(0, buffers).with_listener(listener).unwrap());
+    // spawn workers for concurrent connections
+    for _ in 0..MAX_CONNECTIONS {
+        spawner.spawn(listen_task(stack, acceptor, distributor)).ok();
     }
 
     println!("Waiting to get IPv4 address...");
@@ -133,9 +158,8 @@ async fn net_task(stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>) {
 #[embassy_executor::task(pool_size = MAX_CONNECTIONS)]
 async fn listen_task(
     stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>,
-    id: usize,
-    port: u16,
-    distributor: &'static InnerDistributorMutex<MAX_CONNECTIONS>,
+    acceptor: &'static Acceptor,
+    distributor: &'static Distributor,
 ) {
-    listen(stack, id, port, distributor).await
+    acceptor.work(stack, distributor).await
 }
@@ -0,0 +1,21 @@
+//! Compares the subscription tree with the linear index it replaced
+//! `cargo bench --features benchmarks,log`, needs a nightly toolchain
+#![feature(test)]
+extern crate test;
+
+use mqtt_server::bench::Indexes;
+use test::Bencher;
+
+const TOPIC: &str = "/home/sensors/7";
+
+#[bench]
+fn bench_tree(b: &mut Bencher) {
+    let indexes = Indexes::default();
+    b.iter(|| indexes.tree(test::black_box(TOPIC)));
+}
+
+#[bench]
+fn bench_list(b: &mut Bencher) {
+    let indexes = Indexes::default();
+    b.iter(|| indexes.list(test::black_box(TOPIC)));
+}
@@ -0,0 +1,495 @@
+//! Connection acceptor shared by all connection workers
+//!
+//! Instead of giving every listen task a fixed distributor slot and its own socket buffers, the
+//! workers lease a free slot from the acceptor once they accepted a connection. Only one worker
+//! listens at a time, with a pair of buffers from a pool that is sized independently of the slots,
+//! so idle workers hold neither. The accept policy lives in one place and the acceptor knows how
+//! many slots are in use.
+//!
+//! If all connections are in use, `reject_surplus` answers new connections with a CONNACK instead of
+//! letting them time out.
+//!
+//! Every acceptor serves one listener. To serve several ports with different settings from one
+//! distributor, create an acceptor per listener on separate ranges of distributor slots.
+//! The acceptor speaks MQTT directly on TCP, a listener with another [`Transport`] is refused by
+//! [`Acceptor::with_listener`]. TLS or WebSocket streams can be served by wrapping the socket and
+//! calling [`serve_connection`] directly.
+//!
+//! ```no_run
+//! let acceptor: &'static Acceptor<NoopRawMutex, 4, 4> =
+//!     make_static!(Acceptor::new(0, make_static!([([0; 1600], [0; 1600]); 4])));
+//! let secure: &'static Acceptor<NoopRawMutex, 2, 2> = make_static!(
+//!     Acceptor::new(4, make_static!([([0; 1600], [0; 1600]); 2])).with_listener(ListenerConfig {
+//!         port: 1884,
+//!         connection: ConnectionConfig {
+//!             authenticate: Some(check_password),
+//!             ..Default::default()
+//!         },
+//!         ..Default::default()
+//!     })
+//!     .unwrap()
+//! );
+//! for _ in 0..4 {
+//!     spawner.spawn(worker(stack, acceptor, distributor)).ok();
+//! }
+//! for _ in 0..2 {
+//!     spawner.spawn(secure_worker(stack, secure, distributor)).ok();
+//! }
+//! ```
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::task::Poll;
+use embassy_futures::select::{select, Either};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::Stack;
+use embassy_net_driver::Driver;
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::waitqueue::MultiWakerRegistration;
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::Write;
+use heapless::Vec;
+use mqtt_format::v5::packets::MqttPacket;
+
+use crate::codec::{write_connack, MqttCodecDecoder, PacketWriter};
+use crate::config::BrokerConfig;
+use crate::distributor::Distributor;
+use crate::errors::ListenerError;
+use crate::log::{info, warn};
+use crate::socket::{serve_connection, ConnectionConfig};
+
+/// Size of the rx and tx buffer of every socket
+pub const DEFAULT_SOCKET_BUFFER_SIZE: usize = 1600;
+
+/// Protocol spoken on the connections of a listener
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Transport {
+    /// MQTT directly on TCP
+    Plain,
+    /// MQTT over TLS, not supported by the acceptor yet
+    Tls,
+    /// MQTT over WebSocket, not supported by the acceptor yet
+    WebSocket,
+}
+
+/// Settings of a listener, the connection settings apply to all of its clients
+#[derive(Debug, Clone)]
+pub struct ListenerConfig {
+    pub port: u16,
+    pub transport: Transport,
+    /// connections served at the same time, at most the amount of slots of the acceptor
+    pub max_connections: usize,
+    pub connection: ConnectionConfig,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        Self {
+            port: 1883,
+            transport: Transport::Plain,
+            max_connections: usize::MAX,
+            connection: ConnectionConfig::default(),
+        }
+    }
+}
+
+/// Size of the buffers used to reject connections, a CONNECT that does not fit is not answered
+const REJECT_BUFFER_SIZE: usize = 256;
+
+/// Reason code sent to clients that are rejected because all slots are in use
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    ServerBusy,
+    QuotaExceeded,
+    /// the client should use the server reference, temporarily
+    UseAnotherServer,
+}
+
+impl RejectReason {
+    fn code(self) -> u8 {
+        match self {
+            RejectReason::ServerBusy => 0x89,
+            RejectReason::QuotaExceeded => 0x97,
+            RejectReason::UseAnotherServer => 0x9C,
+        }
+    }
+}
+
+/// How surplus connections are rejected, see [`Acceptor::reject_surplus`]
+#[derive(Debug, Clone)]
+pub struct RejectConfig {
+    pub reason: RejectReason,
+    /// other broker the clients are pointed to, e.g. `broker2.local:1883`
+    pub server_reference: Option<&'static str>,
+    /// how long to wait for the CONNECT packet
+    pub connect_timeout: Duration,
+}
+
+impl Default for RejectConfig {
+    fn default() -> Self {
+        Self {
+            reason: RejectReason::ServerBusy,
+            server_reference: None,
+            connect_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Receive and transmit buffer of a socket
+struct Buffers<const BUFFER_SIZE: usize> {
+    rx: &'static mut [u8; BUFFER_SIZE],
+    tx: &'static mut [u8; BUFFER_SIZE],
+}
+
+struct State<const SLOTS: usize, const BUFFERS: usize, const BUFFER_SIZE: usize> {
+    /// distributor slots without a connection
+    slots: Vec<usize, SLOTS>,
+    /// buffers neither used by a connection nor by the listening worker
+    buffers: Vec<Buffers<BUFFER_SIZE>, BUFFERS>,
+    /// slots with an established connection
+    connected: usize,
+    /// workers and `reject_surplus` waiting for a connection to start or end
+    wakers: MultiWakerRegistration<SLOTS>,
+}
+
+/// Hands out distributor slots and socket buffers to connection workers
+/// a slot is only leased for an accepted connection, the buffers of the listening socket come
+/// from a pool of `BUFFERS` pairs, so idle workers hold neither
+pub struct Acceptor<
+    M: RawMutex,
+    const SLOTS: usize,
+    const BUFFERS: usize,
+    const BUFFER_SIZE: usize = DEFAULT_SOCKET_BUFFER_SIZE,
+> {
+    state: BlockingMutex<M, RefCell<State<SLOTS, BUFFERS, BUFFER_SIZE>>>,
+    /// held by the worker that is listening
+    listening: Mutex<M, ()>,
+    listener: ListenerConfig,
+    first_id: usize,
+}
+
+impl<M: RawMutex, const SLOTS: usize, const BUFFERS: usize, const BUFFER_SIZE: usize>
+    Acceptor<M, SLOTS, BUFFERS, BUFFER_SIZE>
+{
+    /// uses the distributor slots `first_id..first_id + SLOTS`, the others can be used by bridges,
+    /// gateways or federation links
+    /// the range has to lie within the `CONNECTIONS` of the distributor, `work` panics otherwise
+    /// every connection needs one pair of `buffers`, at most `SLOTS.min(BUFFERS)` are served
+    pub fn new(
+        first_id: usize,
+        buffers: &'static mut [([u8; BUFFER_SIZE], [u8; BUFFER_SIZE]); BUFFERS],
+    ) -> Self {
+        // slots are taken from the back, so the first slot is used first
+        let slots = (first_id..first_id + SLOTS).rev().collect();
+        let buffers = buffers
+            .iter_mut()
+            .map(|(rx, tx)| Buffers { rx, tx })
+            .collect();
+        Self {
+            state: BlockingMutex::new(RefCell::new(State {
+                slots,
+                buffers,
+                connected: 0,
+                wakers: MultiWakerRegistration::new(),
+            })),
+            listening: Mutex::new(()),
+            listener: ListenerConfig::default(),
+            first_id,
+        }
+    }
+
+    /// sets the port and the settings used for every connection
+    /// fails if the acceptor can not speak the transport of the listener
+    pub fn with_listener(self, listener: ListenerConfig) -> Result<Self, ListenerError> {
+        match listener.transport {
+            Transport::Plain => Ok(Self { listener, ..self }),
+            Transport::Tls | Transport::WebSocket => Err(ListenerError::UnsupportedTransport),
+        }
+    }
+
+    pub fn listener(&self) -> &ListenerConfig {
+        &self.listener
+    }
+
+    /// panics if the slots of the acceptor are not slots of a distributor with `connections`
+    fn check_slots(&self, connections: usize) {
+        assert!(
+            self.first_id + SLOTS <= connections,
+            "acceptor slots {}..{} exceed the {} connections of the distributor",
+            self.first_id,
+            self.first_id + SLOTS,
+            connections
+        );
+    }
+
+    /// amount of slots serving a connection
+    pub fn in_use(&self) -> usize {
+        SLOTS - self.state.lock(|state| state.borrow().slots.len())
+    }
+
+    /// true if no more connections may be accepted
+    fn is_full(&self, state: &State<SLOTS, BUFFERS, BUFFER_SIZE>) -> bool {
+        state.connected >= SLOTS.min(BUFFERS).min(self.listener.max_connections)
+    }
+
+    /// waits till all connections are in use, or if `full` is false till one is free
+    async fn wait_for_full(&self, full: bool) {
+        poll_fn(|cx| {
+            self.state.lock(|state| {
+                let mut state = state.borrow_mut();
+                if self.is_full(&state) == full {
+                    Poll::Ready(())
+                } else {
+                    state.wakers.register(cx.waker());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+
+    /// buffers for the listening socket, none if no more connections may be accepted
+    fn listen(&self) -> Option<Buffers<BUFFER_SIZE>> {
+        self.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            if self.is_full(&state) {
+                return None;
+            }
+            state.buffers.pop()
+        })
+    }
+
+    fn give_back(&self, buffers: Buffers<BUFFER_SIZE>) {
+        self.state.lock(|state| {
+            // there is space for all buffers
+            let _ = state.borrow_mut().buffers.push(buffers);
+        });
+    }
+
+    /// leases a slot for an accepted connection
+    fn connect(&self) -> Option<usize> {
+        self.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            let id = state.slots.pop()?;
+            state.connected += 1;
+            // `reject_surplus` waits for the last connection to be established
+            state.wakers.wake();
+            Some(id)
+        })
+    }
+
+    fn disconnect(&self, id: usize) {
+        self.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            // there is space for every slot
+            let _ = state.slots.push(id);
+            state.connected -= 1;
+            state.wakers.wake();
+        });
+    }
+
+    /// Runs a connection worker forever
+    /// the worker waits for its turn to accept a connection, leases a free slot and serves it
+    /// spawn as many workers as connections should be served at the same time
+    pub async fn work<T, B: BrokerConfig>(
+        &self,
+        stack: &'static Stack<T>,
+        distributor: &'static B,
+    ) -> !
+    where
+        T: Driver,
+    {
+        self.check_slots(B::CONNECTIONS);
+        let port = self.listener.port;
+        loop {
+            // `reject_surplus` listens while all connections are in use
+            self.wait_for_full(false).await;
+            let listening = self.listening.lock().await;
+            // another worker may have taken the last connection in the meantime
+            let Some(buffers) = self.listen() else {
+                continue;
+            };
+
+            let mut socket = TcpSocket::new(stack, &mut buffers.rx[..], &mut buffers.tx[..]);
+            socket.set_timeout(Some(Duration::from_secs(60)));
+            socket.set_keep_alive(Some(Duration::from_secs(10)));
+            info!("ACCEPTOR: Listening on TCP:{}...", port);
+            let accepted = socket.accept(port).await;
+            match (accepted, socket.remote_endpoint()) {
+                (Ok(()), Some(addr)) => match self.connect() {
+                    Some(id) => {
+                        // let the next worker listen
+                        drop(listening);
+                        info!("SOCKET {}: Received connection from {}", id, addr);
+                        let distributor = Distributor::new(distributor, id);
+                        // cleanup settings of a previous run
+                        distributor.cleanup().await;
+                        distributor.set_remote(addr).await;
+                        let (reader, writer) = socket.split();
+                        let config = &self.listener.connection;
+                        serve_connection(reader, writer, &distributor, config).await;
+                        self.disconnect(id);
+                    }
+                    None => {
+                        warn!("ACCEPTOR: no free slot for {}", addr);
+                        socket.abort();
+                    }
+                },
+                (Err(e), _) => warn!("accept error: {:?}", e),
+                // sometimes this fails since connection is closed immediately after accept
+                (Ok(()), None) => warn!("ACCEPTOR: could not get remote endpoint"),
+            }
+            drop(socket);
+            self.give_back(buffers);
+        }
+    }
+
+    /// Answers connections with an unsuccessful CONNACK while all connections are in use
+    /// runs forever and uses its own small socket buffers, spawn it next to the workers
+    /// rejected clients are counted in the stats of `distributor`
+    pub async fn reject_surplus<T, B: BrokerConfig>(
+        &self,
+        stack: &'static Stack<T>,
+        distributor: &'static B,
+        config: &RejectConfig,
+    ) -> !
+    where
+        T: Driver,
+    {
+        let port = self.listener.port;
+        let mut rx_buffer = [0; REJECT_BUFFER_SIZE];
+        let mut tx_buffer = [0; REJECT_BUFFER_SIZE];
+        loop {
+            self.wait_for_full(true).await;
+            let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+            socket.set_timeout(Some(config.connect_timeout));
+            let accepted = {
+                let _listening = self.listening.lock().await;
+                // stop listening as soon as a worker can take the connection
+                match select(socket.accept(port), self.wait_for_full(false)).await {
+                    Either::First(accepted) => accepted,
+                    Either::Second(()) => {
+                        socket.abort();
+                        continue;
+                    }
+                }
+            };
+            if let Err(e) = accepted {
+                warn!("accept error: {:?}", e);
+                continue;
+            }
+            info!("ACCEPTOR: all slots in use, rejecting connection");
+            distributor.reject_client().await;
+
+            let (reader, mut writer) = socket.split();
+            let mut parser = MqttCodecDecoder::new(reader, [0; REJECT_BUFFER_SIZE]);
+            if let Ok(Ok(Some(MqttPacket::Connect(_)))) =
+                with_timeout(config.connect_timeout, parser.next()).await
+            {
+                let mut connack = PacketWriter::<REJECT_BUFFER_SIZE>::default();
+                if write_connack(
+                    &mut connack,
+                    false,
+                    config.reason.code(),
+                    None,
+                    config.server_reference,
+                )
+                .is_ok()
+                {
+                    let _ = writer.write_all(connack.get_written_data()).await;
+                }
+            }
+            socket.close();
+            let _ = socket.flush().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use static_cell::make_static;
+
+    type TestAcceptor<const SLOTS: usize, const BUFFERS: usize> =
+        Acceptor<NoopRawMutex, SLOTS, BUFFERS, 16>;
+
+    #[test]
+    fn test_idle_worker() {
+        let acceptor = TestAcceptor::<2, 2>::new(0, make_static!([([0; 16], [0; 16]); 2]));
+
+        // the first worker listens without holding a slot
+        let first = acceptor.listen().unwrap();
+        assert_eq!(acceptor.in_use(), 0);
+        assert_eq!(acceptor.connect(), Some(0));
+
+        // the next worker listens while the first one is busy and accepts the next client
+        let second = acceptor.listen().unwrap();
+        assert_eq!(acceptor.connect(), Some(1));
+        assert_eq!(acceptor.in_use(), 2);
+        assert!(acceptor.listen().is_none());
+
+        acceptor.disconnect(0);
+        acceptor.give_back(first);
+        assert_eq!(acceptor.in_use(), 1);
+        let third = acceptor.listen().unwrap();
+        assert_eq!(acceptor.connect(), Some(0));
+
+        acceptor.disconnect(1);
+        acceptor.disconnect(0);
+        acceptor.give_back(second);
+        acceptor.give_back(third);
+        assert_eq!(acceptor.in_use(), 0);
+    }
+
+    #[test]
+    fn test_limits() {
+        // fewer buffers than slots
+        let acceptor = TestAcceptor::<4, 1>::new(4, make_static!([([0; 16], [0; 16]); 1]));
+        let _buffers = acceptor.listen().unwrap();
+        assert_eq!(acceptor.connect(), Some(4));
+        assert!(acceptor.listen().is_none());
+        acceptor.check_slots(8);
+
+        let acceptor = TestAcceptor::<2, 2>::new(0, make_static!([([0; 16], [0; 16]); 2]))
+            .with_listener(ListenerConfig {
+                max_connections: 1,
+                ..Default::default()
+            })
+            .unwrap();
+        let _buffers = acceptor.listen().unwrap();
+        assert_eq!(acceptor.connect(), Some(0));
+        assert!(acceptor.listen().is_none());
+    }
+
+    #[test]
+    fn test_transport() {
+        let tls = TestAcceptor::<2, 2>::new(0, make_static!([([0; 16], [0; 16]); 2]))
+            .with_listener(ListenerConfig {
+                transport: Transport::Tls,
+                ..Default::default()
+            });
+        assert!(matches!(tls, Err(ListenerError::UnsupportedTransport)));
+
+        let web_socket = TestAcceptor::<2, 2>::new(0, make_static!([([0; 16], [0; 16]); 2]))
+            .with_listener(ListenerConfig {
+                transport: Transport::WebSocket,
+                ..Default::default()
+            });
+        assert!(matches!(
+            web_socket,
+            Err(ListenerError::UnsupportedTransport)
+        ));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_slots_out_of_range() {
+        let acceptor = TestAcceptor::<4, 1>::new(4, make_static!([([0; 16], [0; 16]); 1]));
+        acceptor.check_slots(6);
+    }
+}
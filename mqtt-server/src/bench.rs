@@ -0,0 +1,41 @@
+//! Entry points for the benchmarks in `benches/`
+//! they are not part of the API and only available with the `benchmarks` feature
+use core::fmt::Write;
+
+use crate::config::Topic;
+use crate::topics::Tree;
+use crate::topics_list::TopicsList;
+
+/// The same subscriptions in the subscription tree and in the linear reference index
+pub struct Indexes {
+    tree: Tree<64>,
+    list: TopicsList<64>,
+}
+
+impl Default for Indexes {
+    /// 40 subscriptions from 20 clients, most of them not matching
+    fn default() -> Self {
+        let mut tree = Tree::default();
+        let mut list = TopicsList::default();
+        for i in 0..40 {
+            let mut topic = Topic::new();
+            let level = ["sensors", "lights", "+"][i % 3];
+            write!(topic, "/home/{}/{}", level, i / 3).unwrap();
+            tree.insert(&topic, i % 20).unwrap();
+            list.insert(&topic, i % 20).unwrap();
+        }
+        Self { tree, list }
+    }
+}
+
+impl Indexes {
+    /// subscribers of `topic` according to the tree
+    pub fn tree(&self, topic: &str) -> impl Sized {
+        self.tree.get_subscribed(topic)
+    }
+
+    /// subscribers of `topic` according to the list
+    pub fn list(&self, topic: &str) -> impl Sized {
+        self.list.get_subscribed(topic)
+    }
+}
@@ -0,0 +1,53 @@
+//! Runs the broker on the host for testing with regular MQTT clients
+//!
+//! ```bash
+//! RUST_LOG=info cargo run --features std -- 0.0.0.0:1883
+//! ```
+use std::rc::Rc;
+
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use mqtt_server::config::{subscriber_words, InnerDistributorMutex};
+use mqtt_server::distributor::InnerDistributor;
+use tokio::net::TcpListener;
+use tokio::task::LocalSet;
+
+const MAX_CONNECTIONS: usize = 16;
+const QUEUE_LEN: usize = 16;
+const TREE_SIZE: usize = 64;
+const MAX_MESSAGE_SIZE: usize = 4096;
+
+type Distributor = InnerDistributorMutex<
+    NoopRawMutex,
+    MAX_CONNECTIONS,
+    { subscriber_words(MAX_CONNECTIONS) },
+    QUEUE_LEN,
+    TREE_SIZE,
+    MAX_MESSAGE_SIZE,
+>;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> std::io::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let addr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:1883".into());
+    let listener = Rc::new(TcpListener::bind(&addr).await?);
+    log::info!("listening on {}", addr);
+
+    // the distributor lives as long as the program, like on the microcontrollers
+    let distributor: &'static Distributor = Box::leak(Box::new(InnerDistributorMutex::new(
+        InnerDistributor::default(),
+    )));
+
+    // all connections share a single thread so the distributor does not need a real mutex
+    let tasks = LocalSet::new();
+    for id in 0..MAX_CONNECTIONS {
+        let listener = listener.clone();
+        tasks.spawn_local(async move {
+            mqtt_server::host::listen(&listener, id, distributor).await;
+        });
+    }
+    tasks.await;
+    Ok(())
+}
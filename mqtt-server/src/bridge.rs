@@ -0,0 +1,390 @@
+//! Bridge to an upstream broker
+//!
+//! The bridge connects to a remote broker as a regular MQTT client and uses one distributor
+//! slot on the local side. Local topics are exported to the remote broker and remote topics
+//! are imported, both with their prefixes remapped.
+//!
+//! [`bridge`] uses a TCP socket of the embassy stack, `host::bridge` one of the host. Both
+//! reconnect with exponential backoff and stop if the topics of the `BridgeConfig` can not be
+//! forwarded.
+use core::fmt;
+use core::future::pending;
+use embassy_futures::select::select3;
+use embassy_futures::select::Either3::{First, Second, Third};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{IpEndpoint, Stack};
+use embassy_net_driver::Driver;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_io_async::{Read, Write};
+use mqtt_format::v5::packets::connack::ConnackReasonCode;
+use mqtt_format::v5::packets::connect::{ConnectProperties, MConnect};
+use mqtt_format::v5::packets::pingreq::MPingreq;
+use mqtt_format::v5::packets::publish::MPublish;
+use mqtt_format::v5::packets::MqttPacket;
+use mqtt_format::v5::qos::QualityOfService;
+
+use crate::codec::{write_subscriptions, MqttCodecDecoder, MqttCodecEncoder, SliceWriter};
+use crate::config::BrokerConfig;
+use crate::distributor::Distributor;
+use crate::errors::BridgeError;
+use crate::log::{info, warn};
+use crate::topics::{is_valid_filter, matches};
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BridgeDirection {
+    /// local messages are published on the remote broker
+    Out,
+    /// remote messages are published on the local broker
+    In,
+    /// the remote broker has to honor No Local, otherwise the exported messages come back
+    Both,
+}
+
+impl BridgeDirection {
+    fn exports(self) -> bool {
+        matches!(self, BridgeDirection::Out | BridgeDirection::Both)
+    }
+    fn imports(self) -> bool {
+        matches!(self, BridgeDirection::In | BridgeDirection::Both)
+    }
+}
+
+/// A topic pattern that is forwarded by the bridge
+/// `sensors/#` with local prefix `home/` and remote prefix `edge1/` exports `home/sensors/temp`
+/// as `edge1/sensors/temp`
+pub struct BridgeTopic {
+    /// topic filter relative to the prefixes
+    pub pattern: &'static str,
+    pub direction: BridgeDirection,
+    pub local_prefix: &'static str,
+    pub remote_prefix: &'static str,
+}
+
+impl BridgeTopic {
+    fn to_remote<S: Default + fmt::Write>(&self, topic: &str) -> Option<S> {
+        remap(topic, self.pattern, self.local_prefix, self.remote_prefix)
+    }
+    fn to_local<S: Default + fmt::Write>(&self, topic: &str) -> Option<S> {
+        remap(topic, self.pattern, self.remote_prefix, self.local_prefix)
+    }
+}
+
+pub struct BridgeConfig {
+    /// address of the upstream broker
+    pub remote: IpEndpoint,
+    pub client_id: &'static str,
+    pub username: Option<&'static str>,
+    pub password: Option<&'static [u8]>,
+    /// keep alive in seconds, 0 disables pings
+    pub keep_alive: u16,
+    pub topics: &'static [BridgeTopic],
+    /// delay before the first reconnect, doubled after every failed attempt
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+/// Replaces `from` prefix of `topic` with `to` if the rest matches `pattern`
+fn remap<S: Default + fmt::Write>(topic: &str, pattern: &str, from: &str, to: &str) -> Option<S> {
+    let rest = topic.strip_prefix(from)?;
+    if !matches(pattern, rest) {
+        return None;
+    }
+    join(to, rest)
+}
+
+fn join<S: Default + fmt::Write>(prefix: &str, topic: &str) -> Option<S> {
+    let mut joined = S::default();
+    joined.write_str(prefix).ok()?;
+    joined.write_str(topic).ok()?;
+    Some(joined)
+}
+
+/// Runs the bridge, reconnecting with exponential backoff
+/// returns on errors in the `config`, e.g. a topic that is too long once remapped
+pub async fn bridge<T, B: BrokerConfig>(
+    stack: &'static Stack<T>,
+    id: usize,
+    config: BridgeConfig,
+    distributor: &'static B,
+) where
+    T: Driver,
+{
+    let mut rx_buffer = [0; 1600];
+    let mut tx_buffer = [0; 1600];
+    let distributor = Distributor::new(distributor, id);
+    let mut backoff = config.min_backoff;
+
+    loop {
+        // local subscriptions are only active while connected, otherwise the queue would fill up
+        distributor.cleanup().await;
+
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(60)));
+        info!("BRIDGE: connecting to {}...", config.remote);
+        let mut connected = false;
+        let result = match socket.connect(config.remote).await {
+            Ok(()) => {
+                let (reader, writer) = socket.split();
+                session(reader, writer, &config, &distributor, &mut connected).await
+            }
+            Err(_) => Err(BridgeError::ConnectionFailed),
+        };
+        socket.abort();
+        if !retry(result, connected, &mut backoff, &config).await {
+            distributor.cleanup().await;
+            return;
+        }
+    }
+}
+
+/// Waits before the next connection attempt
+/// returns false if the error is permanent and the bridge has to stop
+pub(crate) async fn retry(
+    result: Result<(), BridgeError>,
+    connected: bool,
+    backoff: &mut Duration,
+    config: &BridgeConfig,
+) -> bool {
+    if let Err(e) = result {
+        if e.is_permanent() {
+            warn!("BRIDGE: {:?}, stopping", e);
+            return false;
+        }
+        warn!("BRIDGE: {:?}", e);
+    }
+    if connected {
+        *backoff = config.min_backoff;
+    }
+    info!("BRIDGE: reconnecting in {}ms", backoff.as_millis());
+    Timer::after(*backoff).await;
+    *backoff = (*backoff * 2).min(config.max_backoff);
+    true
+}
+
+/// Runs a single connection to the remote broker until it fails
+pub(crate) async fn session<T, U, B: BrokerConfig>(
+    reader: T,
+    writer: U,
+    config: &BridgeConfig,
+    distributor: &Distributor<B>,
+    connected: &mut bool,
+) -> Result<(), BridgeError>
+where
+    T: Read,
+    U: Write,
+{
+    let mut parser = MqttCodecDecoder::new(reader, B::buffer());
+    let mut encoder = MqttCodecEncoder::new(writer, B::buffer());
+
+    handshake(
+        &mut parser,
+        &mut encoder,
+        config.client_id,
+        config.username,
+        config.password,
+        config.keep_alive,
+    )
+    .await?;
+    *connected = true;
+    info!("BRIDGE: connected to {}", config.remote);
+
+    let mut buffer = B::buffer();
+    let mut writer = SliceWriter::new(buffer.as_mut());
+    if write_subscribe::<B::Topic>(&mut writer, config)? {
+        let subscribe = MqttPacket::parse_complete(writer.get_written_data())
+            .map_err(|_| BridgeError::ProtocolError)?;
+        encoder.write(subscribe).await?;
+    }
+    for topic in config.topics.iter().filter(|t| t.direction.exports()) {
+        let filter =
+            join::<B::Topic>(topic.local_prefix, topic.pattern).ok_or(BridgeError::TopicTooLong)?;
+        distributor.subscribe(&filter).await?;
+    }
+
+    forward(&mut parser, &mut encoder, config, distributor).await
+}
+
+/// Sends CONNECT and waits for a successful CONNACK
+pub(crate) async fn handshake<T, U, D, E>(
+    parser: &mut MqttCodecDecoder<T, D>,
+    encoder: &mut MqttCodecEncoder<U, E>,
+    client_id: &str,
+    username: Option<&str>,
+    password: Option<&[u8]>,
+    keep_alive: u16,
+) -> Result<(), BridgeError>
+where
+    T: Read,
+    U: Write,
+    D: AsRef<[u8]> + AsMut<[u8]>,
+    E: AsMut<[u8]>,
+{
+    let connect = MqttPacket::Connect(MConnect {
+        client_identifier: client_id,
+        username,
+        password,
+        clean_start: true,
+        will: None,
+        properties: ConnectProperties::new(),
+        keep_alive,
+    });
+    encoder.write(connect).await?;
+    match with_timeout(Duration::from_secs(10), parser.next()).await {
+        Ok(Ok(Some(MqttPacket::Connack(connack)))) => {
+            if !matches!(connack.reason_code, ConnackReasonCode::Success) {
+                return Err(BridgeError::ConnectionRefused);
+            }
+            Ok(())
+        }
+        Ok(Ok(Some(_))) => Err(BridgeError::ProtocolError),
+        _ => Err(BridgeError::ConnectionFailed),
+    }
+}
+
+/// Writes a SUBSCRIBE for all imported topics
+/// returns false if nothing needs to be imported
+fn write_subscribe<S: Clone + Default + fmt::Write + AsRef<str>>(
+    writer: &mut SliceWriter<'_>,
+    config: &BridgeConfig,
+) -> Result<bool, BridgeError> {
+    let filters = || {
+        config
+            .topics
+            .iter()
+            .filter(|t| t.direction.imports())
+            .map(|t| join::<S>(t.remote_prefix, t.pattern))
+    };
+    for filter in filters() {
+        let filter = filter.ok_or(BridgeError::TopicTooLong)?;
+        if !is_valid_filter(filter.as_ref()) {
+            return Err(BridgeError::InvalidTopicFilter);
+        }
+    }
+    if filters().next().is_none() {
+        return Ok(false);
+    }
+    // QoS 0 and no local, so exported messages are not sent back to us
+    write_subscriptions(writer, true, 1, filters().flatten(), 0b0000_0100)
+        .map_err(|_| BridgeError::TopicTooLong)?;
+    Ok(true)
+}
+
+async fn forward<T, U, B: BrokerConfig>(
+    parser: &mut MqttCodecDecoder<T, B::Buffer>,
+    encoder: &mut MqttCodecEncoder<U, B::Buffer>,
+    config: &BridgeConfig,
+    distributor: &Distributor<B>,
+) -> Result<(), BridgeError>
+where
+    T: Read,
+    U: Write,
+{
+    let ping_interval = Duration::from_secs(config.keep_alive as u64 / 2 + 1);
+    let mut next_ping = Instant::now() + ping_interval;
+    loop {
+        // unlock after processing packet
+        distributor.unlock().await;
+        let ping = async move {
+            if config.keep_alive == 0 {
+                pending::<()>().await
+            } else {
+                Timer::at(next_ping).await
+            }
+        };
+        let selected = select3(distributor.next(), distributor.lock(parser.next()), ping).await;
+        match selected {
+            First(Err(_)) => return Err(BridgeError::Overflow),
+            First(Ok(msg)) => {
+                let publish = match MqttPacket::parse_complete(msg.message()) {
+                    Ok(MqttPacket::Publish(publish)) => publish,
+                    _ => continue,
+                };
+                let Some(topic) = config
+                    .topics
+                    .iter()
+                    .filter(|t| t.direction.exports())
+                    .find_map(|t| t.to_remote::<B::Topic>(publish.topic_name))
+                else {
+                    continue;
+                };
+                let packet = MqttPacket::Publish(MPublish {
+                    duplicate: false,
+                    quality_of_service: QualityOfService::AtMostOnce,
+                    packet_identifier: None,
+                    topic_name: &topic,
+                    ..publish
+                });
+                encoder.write(packet).await?;
+                next_ping = Instant::now() + ping_interval;
+            }
+            Second(Ok(Some(MqttPacket::Publish(publish)))) => {
+                let Some(topic) = config
+                    .topics
+                    .iter()
+                    .filter(|t| t.direction.imports())
+                    .find_map(|t| t.to_local::<B::Topic>(publish.topic_name))
+                else {
+                    continue;
+                };
+                let publish = MPublish {
+                    duplicate: false,
+                    quality_of_service: QualityOfService::AtMostOnce,
+                    packet_identifier: None,
+                    topic_name: &topic,
+                    ..publish
+                };
+                if let Err(e) = distributor.forward(&topic, &publish).await {
+                    warn!("BRIDGE: could not import {}: {:?}", &*topic, e);
+                }
+            }
+            // brokers like this one acknowledge every PUBLISH, even with QoS 0
+            Second(Ok(Some(
+                MqttPacket::Suback(_) | MqttPacket::Puback(_) | MqttPacket::Pingresp(_),
+            ))) => {}
+            Second(Ok(Some(MqttPacket::Disconnect(_)))) | Second(Ok(None)) => {
+                return Err(BridgeError::ConnectionClosed)
+            }
+            Second(Ok(Some(_))) => return Err(BridgeError::ProtocolError),
+            Second(Err(e)) => return Err(e.into()),
+            Third(()) => {
+                encoder.write(MqttPacket::Pingreq(MPingreq {})).await?;
+                next_ping = Instant::now() + ping_interval;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heapless::String;
+
+    type Topic = String<64>;
+
+    #[test]
+    fn test_remap() {
+        let topic = BridgeTopic {
+            pattern: "sensors/#",
+            direction: BridgeDirection::Both,
+            local_prefix: "home/",
+            remote_prefix: "edge1/",
+        };
+        assert_eq!(
+            topic
+                .to_remote::<Topic>("home/sensors/temp")
+                .unwrap()
+                .as_str(),
+            "edge1/sensors/temp"
+        );
+        assert_eq!(
+            topic
+                .to_local::<Topic>("edge1/sensors/temp")
+                .unwrap()
+                .as_str(),
+            "home/sensors/temp"
+        );
+        assert!(topic.to_remote::<Topic>("home/lights/kitchen").is_none());
+        assert!(topic.to_remote::<Topic>("edge1/sensors/temp").is_none());
+    }
+}
@@ -1,46 +1,113 @@
 use crate::errors::MqttCodecError;
 use embedded_io_async::{Read, Write};
 use crate::log::{error, warn};
+use crate::stats::TrafficCounters;
 use mqtt_format::v5::packets::MqttPacket;
 use mqtt_format::v5::write::{MqttWriteError, WResult, WriteMqttPacket};
 use winnow::Partial;
 
 /// Decodes MQTT Packets into a stream
-/// packets that are bigger than N will throw an error
-pub(crate) struct MqttCodecDecoder<T, const N: usize>
+/// packets that are bigger than the buffer will throw an error, unless streaming is enabled
+pub(crate) struct MqttCodecDecoder<T, B>
 where
     T: Read,
+    B: AsRef<[u8]> + AsMut<[u8]>,
 {
     stream: T,
-    buf: [u8; N],
+    buf: B,
     read: usize,
     write: usize,
+    streaming: bool,
+    /// bytes at the end of the buffer the first chunk of a streamed packet leaves out
+    headroom: usize,
+    /// bytes of a streamed packet that have not been returned yet
+    chunk_remaining: usize,
+    counters: Option<&'static TrafficCounters>,
 }
 
 /// Encodes MQTT packets into a stream
-/// packets that are bigger than N will throw an error
-pub(crate) struct MqttCodecEncoder<T, const N: usize>
+/// packets that are bigger than the buffer will throw an error
+pub(crate) struct MqttCodecEncoder<T, B>
 where
     T: Write,
+    B: AsMut<[u8]>,
 {
     stream: T,
+    buf: B,
+    /// bytes of a packet written with `write_chunk` that have not been written yet
+    chunk_remaining: usize,
+    counters: Option<&'static TrafficCounters>,
 }
 
-impl<T, const N: usize> MqttCodecDecoder<T, N>
+/// Result of [`MqttCodecDecoder::next_frame`]
+#[derive(Debug)]
+pub(crate) enum Frame<'a> {
+    Packet(MqttPacket<'a>),
+    /// raw bytes of a PUBLISH that does not fit into the buffer
+    /// the first chunk fills the buffer except for the headroom, so it contains the topic if
+    /// the topic fits
+    Chunk {
+        data: &'a [u8],
+        first: bool,
+        /// bytes of the packet that follow in later chunks
+        remaining: usize,
+    },
+}
+
+impl<T, B> MqttCodecDecoder<T, B>
 where
     T: Read,
+    B: AsRef<[u8]> + AsMut<[u8]>,
 {
-    pub fn new(stream: T) -> MqttCodecDecoder<T, N> {
+    /// packets are read into `buf`, e.g. `[0; 1024]`
+    pub fn new(stream: T, buf: B) -> MqttCodecDecoder<T, B> {
         MqttCodecDecoder {
             stream,
-            buf: [0u8; N],
+            buf,
             read: 0,
             write: 0,
+            streaming: false,
+            headroom: 0,
+            chunk_remaining: 0,
+            counters: None,
         }
     }
 
+    /// counts the received packets and bytes
+    pub fn with_counters(mut self, counters: &'static TrafficCounters) -> Self {
+        self.counters = Some(counters);
+        self
+    }
+
+    /// PUBLISH packets larger than the buffer are returned as [`Frame::Chunk`] instead of
+    /// being rejected, so they can be forwarded without holding them in memory
+    /// the first chunk is `headroom` bytes shorter than the buffer, so its header can grow by
+    /// that much when it is forwarded
+    pub fn with_streaming(mut self, headroom: usize) -> Self {
+        assert!(
+            headroom < self.buf.as_ref().len(),
+            "headroom has to be smaller than the buffer"
+        );
+        self.streaming = true;
+        self.headroom = headroom;
+        self
+    }
+
+    /// Moves unread data to the start of the buffer
+    fn compact(&mut self) {
+        self.buf.as_mut().copy_within(self.read..self.write, 0);
+        self.write -= self.read;
+        self.read = 0;
+    }
+
     async fn read_stream(&mut self) -> Result<Option<usize>, MqttCodecError> {
-        let n = match self.stream.read(&mut self.buf[self.write..]).await {
+        if self.write == self.buf.as_ref().len() {
+            self.compact();
+        }
+        if self.write == self.buf.as_ref().len() {
+            return Err(MqttCodecError::BufferTooSmall);
+        }
+        let n = match self.stream.read(&mut self.buf.as_mut()[self.write..]).await {
             Ok(0) => {
                 return Ok(None);
             }
@@ -54,11 +121,23 @@ where
             }
         };
         self.write += n;
-        assert!(self.write <= self.buf.len());
+        assert!(self.write <= self.buf.as_ref().len());
+        if let Some(counters) = self.counters {
+            counters.received_bytes(n);
+        }
         Ok(Some(n))
     }
 
     pub async fn next(&mut self) -> Result<Option<MqttPacket>, MqttCodecError> {
+        match self.next_frame().await? {
+            Some(Frame::Packet(packet)) => Ok(Some(packet)),
+            // only happens if streaming is enabled
+            Some(Frame::Chunk { .. }) => Err(MqttCodecError::InvalidLength),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn next_frame(&mut self) -> Result<Option<Frame>, MqttCodecError> {
         // if buffer empty, reset and read from stream
         if self.read == self.write {
             self.read = 0;
@@ -68,71 +147,102 @@ where
             }
         }
 
-        loop {
-            let packet_len = match get_pkg_len(&self.buf[self.read..self.write]) {
-                Ok(Some(len)) => len, // enough in buffer to read next packet
-                Ok(None) => {
-                    // receive more from socket
-                    if self.read_stream().await?.is_none() {
-                        return Ok(None);
+        if self.chunk_remaining > 0 {
+            let start = self.read;
+            let len = (self.write - self.read).min(self.chunk_remaining);
+            self.read += len;
+            self.chunk_remaining -= len;
+            return Ok(Some(Frame::Chunk {
+                data: &self.buf.as_ref()[start..self.read],
+                first: false,
+                remaining: self.chunk_remaining,
+            }));
+        }
+
+        let packet_len = loop {
+            match get_pkg_len(&self.buf.as_ref()[self.read..self.write]) {
+                Ok(Some(len)) if len > self.buf.as_ref().len() => {
+                    // only publishes can be forwarded without looking at the whole packet
+                    if !self.streaming || self.buf.as_ref()[self.read] & 0xF0 != 0x30 {
+                        error!(
+                            "packet too long! {}bytes buffer size: {}",
+                            len,
+                            self.buf.as_ref().len()
+                        );
+                        return Err(MqttCodecError::InvalidLength);
+                    }
+                    if self.read == 0 && self.write == self.buf.as_ref().len() {
+                        break len;
                     }
-                    self.read_stream().await?;
-                    continue;
                 }
+                // enough in buffer to read next packet
+                Ok(Some(len)) if self.write - self.read >= len => break len,
+                // not enough data has been received yet
+                Ok(_) => {}
                 // error parsing packet length
                 Err(_) => return Err(MqttCodecError::InvalidLength),
-            };
-
-            if packet_len >= self.buf.len() {
-                // todo copy stuff to location 0 to increase buffer size
-                error!(
-                    "packet too long! {}bytes buffer size: {}",
-                    packet_len,
-                    self.buf.len()
-                );
-                return Err(MqttCodecError::InvalidLength);
             }
-            // if not enough data has been received yet
-            if self.write - self.read < packet_len {
-                self.read_stream().await?;
-                continue;
+            // receive more from socket
+            if self.read_stream().await?.is_none() {
+                return Ok(None);
             }
+        };
 
-            let start = self.read;
-            self.read += packet_len;
-            if self.read > self.buf.len() {
-                error!("read index out of bounds");
-                return Err(MqttCodecError::BufferTooSmall);
-            }
+        let start = self.read;
+        if let Some(counters) = self.counters {
+            counters.received_packet(self.buf.as_ref()[start]);
+        }
+        if packet_len > self.buf.as_ref().len() {
+            self.read = self.write - self.headroom;
+            self.chunk_remaining = packet_len - self.read;
+            return Ok(Some(Frame::Chunk {
+                data: &self.buf.as_ref()[start..self.read],
+                first: true,
+                remaining: self.chunk_remaining,
+            }));
+        }
+        self.read += packet_len;
 
-            let packet = MqttPacket::parse_complete(&self.buf[start..self.read]);
-            if let Ok(packet) = packet {
-                return Ok(Some(packet));
-            }
-            #[cfg(features = "log")]
-            error!("error parsing packet {:?}", packet);
-            return Err(MqttCodecError::Invalid);
+        let packet = MqttPacket::parse_complete(&self.buf.as_ref()[start..self.read]);
+        if let Ok(packet) = packet {
+            return Ok(Some(Frame::Packet(packet)));
         }
+        #[cfg(features = "log")]
+        error!("error parsing packet {:?}", packet);
+        Err(MqttCodecError::Invalid)
     }
 }
-impl<T, const N: usize> MqttCodecEncoder<T, N>
+impl<T, B> MqttCodecEncoder<T, B>
 where
     T: Write,
+    B: AsMut<[u8]>,
 {
-    pub fn new(stream: T) -> MqttCodecEncoder<T, N> {
-        MqttCodecEncoder { stream }
+    /// packets are encoded in `buf`, e.g. `[0; 1024]`
+    pub fn new(stream: T, buf: B) -> MqttCodecEncoder<T, B> {
+        MqttCodecEncoder {
+            stream,
+            buf,
+            chunk_remaining: 0,
+            counters: None,
+        }
+    }
+
+    /// counts the sent packets and bytes
+    pub fn with_counters(mut self, counters: &'static TrafficCounters) -> Self {
+        self.counters = Some(counters);
+        self
     }
     pub async fn write<'a>(&mut self, packet: MqttPacket<'a>) -> Result<(), MqttCodecError> {
-        if packet.binary_size() > N as u32 {
+        let buf = self.buf.as_mut();
+        if packet.binary_size() > buf.len() as u32 {
             error!(
                 "packet too large to write ({}/{} Bytes)",
                 packet.binary_size(),
-                N
+                buf.len()
             );
             return Err(MqttCodecError::BufferTooSmall);
         }
-        // create a packet writer with the same size as the parser
-        let mut writer = PacketWriter::<N>::default();
+        let mut writer = SliceWriter::new(buf);
 
         if let Err(e) = packet.write(&mut writer) {
             #[cfg(features = "log")]
@@ -144,6 +254,65 @@ where
             warn!("codec sending to socket {:?}", e);
             return Err(MqttCodecError::ConnectionReset);
         }
+        if let Some(counters) = self.counters {
+            let written = writer.get_written_data();
+            counters.sent_packet(written[0]);
+            counters.sent_bytes(written.len());
+        }
+        Ok(())
+    }
+
+    /// Writes a [`Frame::Chunk`] of a streamed packet unchanged
+    /// a packet can be split into several chunks, but every chunk belongs to a single packet
+    pub async fn write_chunk(&mut self, data: &[u8]) -> Result<(), MqttCodecError> {
+        Self::send(
+            &mut self.stream,
+            self.counters,
+            &mut self.chunk_remaining,
+            data,
+        )
+        .await
+    }
+
+    /// Writes the header of a PUBLISH, its properties and payload of `len` bytes have to
+    /// follow with [`Self::write_chunk`]
+    pub async fn write_publish_header(
+        &mut self,
+        retain: bool,
+        topic: &str,
+        len: usize,
+    ) -> Result<(), MqttCodecError> {
+        let mut writer = SliceWriter::new(self.buf.as_mut());
+        write_publish_header(&mut writer, retain, topic, len)
+            .map_err(|_| MqttCodecError::BufferTooSmall)?;
+        Self::send(
+            &mut self.stream,
+            self.counters,
+            &mut self.chunk_remaining,
+            writer.get_written_data(),
+        )
+        .await
+    }
+
+    async fn send(
+        stream: &mut T,
+        counters: Option<&TrafficCounters>,
+        chunk_remaining: &mut usize,
+        data: &[u8],
+    ) -> Result<(), MqttCodecError> {
+        stream
+            .write_all(data)
+            .await
+            .map_err(|_| MqttCodecError::ConnectionReset)?;
+        if let Some(counters) = counters {
+            if *chunk_remaining == 0 && !data.is_empty() {
+                // the chunk starts a new packet
+                counters.sent_packet(data[0]);
+                *chunk_remaining = get_pkg_len(data).ok().flatten().unwrap_or(0);
+            }
+            *chunk_remaining = chunk_remaining.saturating_sub(data.len());
+            counters.sent_bytes(data.len());
+        }
         Ok(())
     }
 }
@@ -190,6 +359,41 @@ impl<const N: usize> WriteMqttPacket for PacketWriter<N> {
     }
 }
 
+/// Same as [`PacketWriter`] but encodes into a borrowed buffer
+pub(crate) struct SliceWriter<'a> {
+    buffer: &'a mut [u8],
+    write_index: usize,
+}
+
+impl<'a> SliceWriter<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self {
+            buffer,
+            write_index: 0,
+        }
+    }
+    pub fn get_written_data(&self) -> &[u8] {
+        &self.buffer[..self.write_index]
+    }
+}
+impl WriteMqttPacket for SliceWriter<'_> {
+    type Error = MqttWriteError;
+
+    #[inline]
+    fn write_byte(&mut self, u: u8) -> WResult<Self> {
+        self.write_slice(&[u])
+    }
+
+    fn write_slice(&mut self, u: &[u8]) -> WResult<Self> {
+        if self.write_index + u.len() > self.buffer.len() {
+            return Err(MqttWriteError::Invariant);
+        }
+        self.buffer[self.write_index..self.write_index + u.len()].copy_from_slice(u);
+        self.write_index += u.len();
+        Ok(())
+    }
+}
+
 /// Some(usize) if enough data to read a packet
 /// None if not enough data
 /// Err() if data is not in the correct format
@@ -211,3 +415,266 @@ fn get_pkg_len(buf: &[u8]) -> Result<Option<usize>, ()> {
         + remaining_length;
     Ok(Some(total_packet_length))
 }
+
+/// Writes `value` as MQTT variable byte integer
+pub(crate) fn write_variable_u32<W: WriteMqttPacket>(writer: &mut W, mut value: u32) -> WResult<W> {
+    loop {
+        let byte = (value % 128) as u8;
+        value /= 128;
+        if value == 0 {
+            return writer.write_byte(byte);
+        }
+        writer.write_byte(byte | 0x80)?;
+    }
+}
+
+/// Writes a length prefixed UTF-8 string
+pub(crate) fn write_string<W: WriteMqttPacket>(writer: &mut W, value: &str) -> WResult<W> {
+    writer.write_slice(&(value.len() as u16).to_be_bytes())?;
+    writer.write_slice(value.as_bytes())
+}
+
+/// Start of a PUBLISH, parsed from the first chunk of a packet that does not fit into the buffer
+#[derive(Debug)]
+pub(crate) struct PublishStart<'a> {
+    pub retain: bool,
+    pub topic: &'a str,
+    /// `None` for QoS 0
+    pub packet_identifier: Option<u16>,
+    /// the properties and the payload, as far as they are part of the chunk
+    pub rest: &'a [u8],
+    /// length of the properties and the payload of the whole packet
+    pub len: usize,
+}
+
+/// Parses the fixed header, the topic and the packet identifier of a PUBLISH
+/// returns `None` if they are not complete
+pub(crate) fn publish_start(data: &[u8]) -> Option<PublishStart<'_>> {
+    let (&first, rest) = data.split_first()?;
+    if first & 0xF0 != 0x30 {
+        return None;
+    }
+    let remaining = mqtt_format::v5::integers::parse_variable_u32(&mut Partial::new(rest)).ok()?;
+    let data =
+        rest.get(mqtt_format::v5::integers::variable_u32_binary_size(remaining) as usize..)?;
+    let topic_len = u16::from_be_bytes([*data.first()?, *data.get(1)?]) as usize;
+    let topic = core::str::from_utf8(data.get(2..2 + topic_len)?).ok()?;
+    let mut offset = 2 + topic_len;
+    // QoS 1 and 2 have a packet identifier
+    let packet_identifier = if first & 0x06 != 0 {
+        let identifier = u16::from_be_bytes([*data.get(offset)?, *data.get(offset + 1)?]);
+        offset += 2;
+        Some(identifier)
+    } else {
+        None
+    };
+    Some(PublishStart {
+        retain: first & 0x01 != 0,
+        topic,
+        packet_identifier,
+        rest: data.get(offset..)?,
+        len: (remaining as usize).checked_sub(offset)?,
+    })
+}
+
+/// Writes the header of a PUBLISH with QoS 0 whose properties and payload of `len` bytes
+/// follow separately
+pub(crate) fn write_publish_header<W: WriteMqttPacket>(
+    writer: &mut W,
+    retain: bool,
+    topic: &str,
+    len: usize,
+) -> WResult<W> {
+    writer.write_byte(0x30 | retain as u8)?;
+    write_variable_u32(writer, (2 + topic.len() + len) as u32)?;
+    write_string(writer, topic)
+}
+
+/// Writes a CONNACK with optional server keep alive and server reference
+/// mqtt-format can not encode these properties, so the packet is encoded by hand
+pub(crate) fn write_connack<const N: usize>(
+    writer: &mut PacketWriter<N>,
+    session_present: bool,
+    reason_code: u8,
+    server_keep_alive: Option<u16>,
+    server_reference: Option<&str>,
+) -> WResult<PacketWriter<N>> {
+    let properties_length = server_keep_alive.map_or(0, |_| 1 + 2)
+        + server_reference.map_or(0, |r| 1 + 2 + r.len() as u32);
+    writer.write_byte(0x20)?;
+    write_variable_u32(
+        writer,
+        2 + mqtt_format::v5::integers::variable_u32_binary_size(properties_length)
+            + properties_length,
+    )?;
+    writer.write_byte(session_present as u8)?;
+    writer.write_byte(reason_code)?;
+    write_variable_u32(writer, properties_length)?;
+    if let Some(keep_alive) = server_keep_alive {
+        writer.write_byte(0x13)?;
+        writer.write_slice(&keep_alive.to_be_bytes())?;
+    }
+    if let Some(server_reference) = server_reference {
+        writer.write_byte(0x1C)?;
+        write_string(writer, server_reference)?;
+    }
+    Ok(())
+}
+
+/// Writes a SUBSCRIBE or, if `subscribe` is false, an UNSUBSCRIBE packet for all `filters`
+/// mqtt-format can only parse subscription lists, so the packet is encoded by hand and can be
+/// parsed into a [`MqttPacket`] afterwards
+pub(crate) fn write_subscriptions<W: WriteMqttPacket>(
+    writer: &mut W,
+    subscribe: bool,
+    packet_identifier: u16,
+    filters: impl Iterator<Item = impl AsRef<str>> + Clone,
+    options: u8,
+) -> WResult<W> {
+    // packet identifier and empty properties
+    let mut remaining_length = 3;
+    for filter in filters.clone() {
+        remaining_length += 2 + filter.as_ref().len() as u32 + subscribe as u32;
+    }
+    writer.write_byte(if subscribe { 0x82 } else { 0xA2 })?;
+    write_variable_u32(writer, remaining_length)?;
+    writer.write_slice(&packet_identifier.to_be_bytes())?;
+    writer.write_byte(0)?;
+    for filter in filters {
+        write_string(writer, filter.as_ref())?;
+        if subscribe {
+            writer.write_byte(options)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_futures::block_on;
+    use mqtt_format::v5::packets::connack::ConnackReasonCode;
+
+    /// PUBLISH on topic `a` with the given payload
+    fn publish(payload: &[u8]) -> PacketWriter<64> {
+        let mut writer = PacketWriter::<64>::default();
+        writer.write_byte(0x30).unwrap();
+        write_variable_u32(&mut writer, 4 + payload.len() as u32).unwrap();
+        write_string(&mut writer, "a").unwrap();
+        writer.write_byte(0).unwrap();
+        writer.write_slice(payload).unwrap();
+        writer
+    }
+
+    #[test]
+    fn test_compaction() {
+        // the second packet starts near the end of the buffer
+        let mut input = [0u8; 20];
+        input[..10].copy_from_slice(publish(b"1234").get_written_data());
+        input[10..].copy_from_slice(publish(b"5678").get_written_data());
+        let mut decoder = MqttCodecDecoder::new(&input[..], [0; 16]);
+        block_on(async {
+            for payload in [b"1234", b"5678"] {
+                match decoder.next().await {
+                    Ok(Some(MqttPacket::Publish(publish))) => assert_eq!(publish.payload, payload),
+                    _ => panic!("expected publish"),
+                }
+            }
+            assert!(matches!(decoder.next().await, Ok(None)));
+        });
+    }
+
+    #[test]
+    fn test_streaming() {
+        let large = publish(&[7; 30]);
+        let mut input = [0u8; 46];
+        input[..36].copy_from_slice(large.get_written_data());
+        input[36..].copy_from_slice(publish(b"1234").get_written_data());
+
+        // packets larger than the buffer are rejected without streaming
+        let mut decoder = MqttCodecDecoder::new(&input[..], [0; 16]);
+        assert!(matches!(
+            block_on(decoder.next()),
+            Err(MqttCodecError::InvalidLength)
+        ));
+
+        let mut decoder = MqttCodecDecoder::new(&input[..], [0; 16]).with_streaming(4);
+        block_on(async {
+            let mut streamed = PacketWriter::<64>::default();
+            loop {
+                match decoder.next_frame().await {
+                    Ok(Some(Frame::Chunk {
+                        data,
+                        first,
+                        remaining,
+                    })) => {
+                        assert_eq!(first, streamed.write_index == 0);
+                        if first {
+                            // the headroom is left out
+                            assert_eq!(data.len(), 12);
+                        }
+                        streamed.write_slice(data).unwrap();
+                        if remaining == 0 {
+                            break;
+                        }
+                    }
+                    _ => panic!("expected chunk"),
+                }
+            }
+            assert_eq!(streamed.get_written_data(), large.get_written_data());
+            match decoder.next().await {
+                Ok(Some(MqttPacket::Publish(publish))) => assert_eq!(publish.payload, b"1234"),
+                _ => panic!("expected publish"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_publish_start() {
+        let large = publish(&[7; 30]);
+        let start = publish_start(&large.get_written_data()[..12]).unwrap();
+        assert_eq!(start.topic, "a");
+        assert!(!start.retain);
+        assert_eq!(start.packet_identifier, None);
+        // empty properties and the first bytes of the payload
+        assert_eq!(start.rest, [0, 7, 7, 7, 7, 7, 7]);
+        assert_eq!(start.len, 31);
+        assert!(publish_start(&large.get_written_data()[..4]).is_none());
+
+        // the header can be rewritten with another topic
+        let mut header = PacketWriter::<64>::default();
+        write_publish_header(&mut header, false, "b/a", start.len).unwrap();
+        header.write_slice(&large.get_written_data()[5..]).unwrap();
+        match MqttPacket::parse_complete(header.get_written_data()) {
+            Ok(MqttPacket::Publish(publish)) => {
+                assert_eq!(publish.topic_name, "b/a");
+                assert_eq!(publish.payload, [7; 30]);
+            }
+            _ => panic!("expected publish"),
+        }
+    }
+
+    #[test]
+    fn test_connack() {
+        let mut writer = PacketWriter::<64>::default();
+        write_connack(&mut writer, false, 0x89, None, Some("broker2:1883")).unwrap();
+        match MqttPacket::parse_complete(writer.get_written_data()) {
+            Ok(MqttPacket::Connack(connack)) => {
+                assert!(matches!(connack.reason_code, ConnackReasonCode::ServerBusy));
+                assert!(!connack.session_present);
+            }
+            _ => panic!("expected CONNACK"),
+        }
+
+        let mut writer = PacketWriter::<64>::default();
+        write_connack(&mut writer, false, 0, Some(60), None).unwrap();
+        assert_eq!(
+            writer.get_written_data(),
+            [0x20, 0x06, 0x00, 0x00, 0x03, 0x13, 0x00, 0x3C]
+        );
+        assert!(matches!(
+            MqttPacket::parse_complete(writer.get_written_data()),
+            Ok(MqttPacket::Connack(_))
+        ));
+    }
+}
@@ -1,23 +1,62 @@
 use crate::bitset::BitSet;
-use crate::distributor::InnerDistributor;
-use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use embassy_sync::mutex::Mutex;
 use heapless::String;
 
+// The following values are only defaults, every firmware can choose its own memory budget with
+// the const generics of `InnerDistributor`, e.g.
+// `InnerDistributor<CONNECTIONS, { subscriber_words(CONNECTIONS) }, 4, 32, 512>`
+// tasks are generic over the `BrokerConfig` of the distributor instead of all of its parameters
+
+/// How many 32 bit words are used to store the subscribers of a topic, this limits the amount of
+/// connections to 64, use `subscriber_words` to size it for more connections
+/// a distributor with too few words for its connections does not compile
+pub const DEFAULT_SUBSCRIBER_WORDS: usize = 2;
+
 /// How many messages can be queued simultaneously
-/// If queue is full, all other sockets are halted until all messages are sent
-/// one should be ok for most cases
-pub const QUEUE_LEN: usize = 1;
-/// How many subscriptions can be saved simultaneously
-pub const TREE_SIZE: usize = 64;
+/// every socket reads the queue at its own pace, so slow subscribers only halt the other sockets
+/// once they are this many messages behind
+pub const DEFAULT_QUEUE_LEN: usize = 1;
+/// How many topic levels can be saved simultaneously in the subscription tree
+/// subscriptions share the nodes of their common prefix
+pub const DEFAULT_TREE_SIZE: usize = 64;
 /// How many bytes can a single message be
-pub const MAX_MESSAGE_SIZE: usize = 1024;
-/// How many bytes a will can be
-pub const MAX_WILL_LENGTH: usize = 128;
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024;
 /// Maximum length of a topic
-pub const MAX_TOPIC_LENGTH: usize = 64;
+pub const DEFAULT_MAX_TOPIC_LENGTH: usize = 64;
+/// How many bytes a will can be
+pub const DEFAULT_MAX_WILL_LENGTH: usize = 128;
+/// How many QoS 1 and 2 messages are kept for a persistent session while its client is offline,
+/// see `InnerDistributor::with_offline_queue_len`
+pub const DEFAULT_OFFLINE_QUEUE_LEN: usize = 8;
+/// For how many offline sessions a single message can be queued, the others miss it
+pub const MAX_OFFLINE_SUBSCRIBERS: usize = 8;
+/// How many persistent sessions can have queued messages at the same time, messages for other
+/// sessions are dropped
+pub const MAX_QUEUED_SESSIONS: usize = 8;
+
+/// Client ids longer than this are truncated in `ClientInfo`, persistent sessions of longer
+/// client ids do not fit into the keys of the storage
+pub const MAX_CLIENT_ID_LENGTH: usize = 32;
+/// Maximum length of a key of the storage for topics of the default length
+pub const DEFAULT_KEY_LENGTH: usize = key_length(DEFAULT_MAX_TOPIC_LENGTH);
+/// Client id of a connected client, see `InnerDistributor::clients`
+pub type ClientId = String<MAX_CLIENT_ID_LENGTH>;
+
+/// Topic of the default length, tasks use the `Topic` of their `BrokerConfig`
+pub type Topic = String<DEFAULT_MAX_TOPIC_LENGTH>;
+/// Amount of words the subscriber bitsets need for `connections` sockets
+pub const fn subscriber_words(connections: usize) -> usize {
+    connections.div_ceil(32)
+}
+
+/// Length of the keys of the storage for topics of `max_topic_length` bytes
+/// the longest key is that of a message queued for a session, a prefix, the mount point, which
+/// is part of every topic of its clients, a separator, the client id, another separator and the
+/// sequence number in hex
+pub const fn key_length(max_topic_length: usize) -> usize {
+    1 + max_topic_length + 1 + MAX_CLIENT_ID_LENGTH + 1 + 8
+}
 
-pub type Topic = String<MAX_TOPIC_LENGTH>;
 /// This defines how many socket connections are supported by the underlying datastructures
-pub(crate) type SubscriberBitSet = BitSet;
-pub type InnerDistributorMutex<const N: usize> = Mutex<NoopRawMutex, InnerDistributor<N>>;
+pub(crate) type SubscriberBitSet<const WORDS: usize = DEFAULT_SUBSCRIBER_WORDS> = BitSet<WORDS>;
+/// the distributor shared by all sockets
+pub use crate::distributor::{BrokerConfig, InnerDistributorMutex};
@@ -0,0 +1,191 @@
+//! Administration over reserved `$CONTROL/<action>` topics
+//!
+//! Publishes to these topics are handled by the broker instead of being sent to subscribers.
+//! Only clients accepted by [`ConnectionConfig::authorize_control`](crate::socket::ConnectionConfig::authorize_control)
+//! may use them, requests of other clients are dropped without a response. If the request carries
+//! a response topic, the result is published there as a JSON object together with the correlation
+//! data of the request, e.g. `{"ok":true,"kicked":1}` or `{"ok":false,"error":"unknown action"}`.
+//!
+//! Like every other topic, `$CONTROL/<action>` is prefixed with the mount point of a client, so
+//! clients of a listener with a mount point can not administer the broker.
+//!
+//! | action           | payload                              | result                       |
+//! |------------------|--------------------------------------|------------------------------|
+//! | `kick`           | client id                            | disconnected clients         |
+//! | `clear_retained` | topic filter, empty clears all       | removed retained messages    |
+//! | `reload_acl`     | -                                    | -                            |
+//! | `stats`          | -                                    | counters of the broker       |
+use core::fmt::{self, Write as _};
+use heapless::String;
+use mqtt_format::v5::integers::variable_u32_binary_size;
+use mqtt_format::v5::packets::publish::MPublish;
+use mqtt_format::v5::write::{MqttWriteError, WriteMqttPacket};
+
+use crate::codec::{write_string, write_variable_u32, PacketWriter};
+use crate::stats::Stats;
+
+/// Topic prefix of the control actions
+pub const CONTROL_PREFIX: &str = "$CONTROL/";
+/// Maximum size of the JSON payload of a response
+pub(crate) const RESPONSE_SIZE: usize = 256;
+
+pub(crate) type Response = String<RESPONSE_SIZE>;
+
+/// Action requested by a publish to a `$CONTROL` topic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command<'a> {
+    /// disconnects every client with this client id
+    Kick(&'a str),
+    /// removes the retained messages matching this topic filter
+    ClearRetained(&'a str),
+    /// calls the hook set with `InnerDistributor::with_reload_acl`
+    ReloadAcl,
+    /// responds with the counters of the broker
+    Stats,
+}
+
+/// Returns the action of a `$CONTROL` topic, `None` for every other topic
+pub fn action(topic: &str) -> Option<&str> {
+    if topic == "$CONTROL" {
+        return Some("");
+    }
+    topic.strip_prefix(CONTROL_PREFIX)
+}
+
+impl<'a> Command<'a> {
+    /// parses the request, fails with the error message of the response
+    pub fn parse(action: &str, payload: &'a [u8]) -> Result<Self, &'static str> {
+        let payload = core::str::from_utf8(payload).map_err(|_| "payload is not UTF-8")?;
+        match action {
+            "kick" if payload.is_empty() => Err("client id missing"),
+            "kick" => Ok(Command::Kick(payload)),
+            "clear_retained" if payload.is_empty() => Ok(Command::ClearRetained("#")),
+            "clear_retained" => Ok(Command::ClearRetained(payload)),
+            "reload_acl" => Ok(Command::ReloadAcl),
+            "stats" => Ok(Command::Stats),
+            _ => Err("unknown action"),
+        }
+    }
+}
+
+/// Builds the JSON payload of a successful response, fields are appended with [`field`]
+pub(crate) fn success() -> Response {
+    let mut response = Response::new();
+    // fits, the response is empty
+    let _ = response.push_str("{\"ok\":true");
+    response
+}
+
+/// Appends a numeric field to a response built with [`success`]
+/// fields that do not fit are left out, space for the closing brace is kept
+pub(crate) fn field(response: &mut Response, name: &str, value: impl fmt::Display) {
+    let mut entry = String::<64>::new();
+    if write!(entry, ",\"{}\":{}", name, value).is_err() {
+        return;
+    }
+    if response.len() + entry.len() < RESPONSE_SIZE {
+        // fits, checked above
+        let _ = response.push_str(&entry);
+    }
+}
+
+/// Completes the JSON payload of `result`, error messages are constant and need no escaping
+pub(crate) fn finish(result: Result<Response, &'static str>) -> Response {
+    match result {
+        Ok(mut response) => {
+            // fits, fields leave space for it
+            let _ = response.push('}');
+            response
+        }
+        Err(error) => {
+            let mut response = Response::new();
+            let _ = write!(response, "{{\"ok\":false,\"error\":\"{}\"}}", error);
+            response
+        }
+    }
+}
+
+/// Appends the counters of `stats` to a response
+pub(crate) fn stats_fields<const N: usize>(response: &mut Response, stats: &Stats<N>) {
+    field(response, "connected", stats.connected());
+    field(response, "connections_accepted", stats.connections_accepted);
+    field(response, "connections_rejected", stats.connections_rejected);
+    field(response, "bytes_in", stats.traffic.bytes_in);
+    field(response, "bytes_out", stats.traffic.bytes_out);
+    field(response, "packets_in", stats.traffic.total_packets_in());
+    field(response, "packets_out", stats.traffic.total_packets_out());
+    field(response, "dropped", stats.dropped);
+    field(response, "queue_used", stats.queue_used);
+    field(response, "queue_high_water", stats.queue_high_water);
+    field(response, "tree_used", stats.tree_used);
+}
+
+/// Writes the PUBLISH of a response with the correlation data of the request
+/// mqtt-format can not encode publish properties, so the packet is encoded by hand
+pub(crate) fn write_response<const S: usize>(
+    writer: &mut PacketWriter<S>,
+    request: &MPublish,
+    topic: &str,
+    payload: &[u8],
+) -> Result<(), MqttWriteError> {
+    let correlation_data = request.properties.correlation_data().map(|c| c.0);
+    let properties_length = correlation_data.map_or(0, |data| 1 + 2 + data.len() as u32);
+    let remaining_length = 2
+        + topic.len() as u32
+        + variable_u32_binary_size(properties_length)
+        + properties_length
+        + payload.len() as u32;
+
+    writer.write_byte(0x30)?;
+    write_variable_u32(writer, remaining_length)?;
+    write_string(writer, topic)?;
+    write_variable_u32(writer, properties_length)?;
+    if let Some(data) = correlation_data {
+        // correlation data identifier
+        writer.write_byte(0x09)?;
+        writer.write_slice(&(data.len() as u16).to_be_bytes())?;
+        writer.write_slice(data)?;
+    }
+    writer.write_slice(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(action("$CONTROL/kick"), Some("kick"));
+        assert_eq!(action("sensors/$CONTROL/kick"), None);
+        assert_eq!(
+            Command::parse("kick", b"sensor"),
+            Ok(Command::Kick("sensor"))
+        );
+        assert_eq!(Command::parse("kick", b""), Err("client id missing"));
+        assert_eq!(
+            Command::parse("clear_retained", b""),
+            Ok(Command::ClearRetained("#"))
+        );
+        assert_eq!(Command::parse("stats", b""), Ok(Command::Stats));
+        assert_eq!(Command::parse("reboot", b""), Err("unknown action"));
+    }
+
+    #[test]
+    fn test_response() {
+        let mut response = success();
+        field(&mut response, "kicked", 1);
+        assert_eq!(finish(Ok(response)), "{\"ok\":true,\"kicked\":1}");
+        assert_eq!(
+            finish(Err("unknown action")),
+            "{\"ok\":false,\"error\":\"unknown action\"}"
+        );
+
+        // fields that do not fit are dropped
+        let mut response = success();
+        for _ in 0..RESPONSE_SIZE {
+            field(&mut response, "a", 1);
+        }
+        let response = finish(Ok(response));
+        assert!(response.ends_with(",\"a\":1}"));
+    }
+}
@@ -1,53 +1,511 @@
-use crate::codec::PacketWriter;
+use crate::codec::{write_publish_header, PacketWriter};
 use crate::config::{
-    InnerDistributorMutex, SubscriberBitSet, MAX_MESSAGE_SIZE, MAX_WILL_LENGTH, QUEUE_LEN,
-    TREE_SIZE,
+    key_length, subscriber_words, ClientId, SubscriberBitSet, DEFAULT_KEY_LENGTH,
+    DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_MAX_TOPIC_LENGTH, DEFAULT_MAX_WILL_LENGTH,
+    DEFAULT_OFFLINE_QUEUE_LEN, DEFAULT_QUEUE_LEN, DEFAULT_SUBSCRIBER_WORDS, DEFAULT_TREE_SIZE,
+    MAX_OFFLINE_SUBSCRIBERS, MAX_QUEUED_SESSIONS,
 };
+use crate::control::{self, Command, Response};
 use crate::errors::DistributorError;
-use crate::topics_list::TopicsList;
+use crate::federation::{DuplicateCache, DUPLICATE_CACHE_SIZE};
+use crate::log::{info, warn};
+use crate::stats::{ClientStats, DistributorCounters, Stats, TrafficCounters};
+use crate::storage::{
+    queued_key, retained_key, session_key, stored_session_key, Key, Storage, QUEUED_PREFIX,
+    RETAINED_PREFIX, SESSION_PREFIX,
+};
+use crate::topics::{matches, Tree};
+use core::cell::{RefCell, UnsafeCell};
+use core::fmt;
 use core::future::{poll_fn, Future};
-use core::task::{Context, Poll, Waker};
-use heapless::Deque;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Poll, Waker};
+use embassy_net::IpEndpoint;
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::mutex::{Mutex, MutexGuard, TryLockError};
+use embassy_sync::waitqueue::MultiWakerRegistration;
+use embassy_time::Instant;
+use heapless::{Deque, String, Vec};
+use mqtt_format::v5::packets::disconnect::DisconnectReasonCode;
 use mqtt_format::v5::packets::publish::MPublish;
 use mqtt_format::v5::packets::MqttPacket;
+use mqtt_format::v5::qos::QualityOfService;
+use mqtt_format::v5::write::{MqttWriteError, WriteMqttPacket};
+
+#[derive(Debug)]
+pub struct MessageInQueue<const SUBSCRIBER_WORDS: usize> {
+    id: u32,
+    /// slot of the pool holding the encoded message
+    slot: usize,
+    /// subscribers that have not read the message yet, each of them holds a reference to the slot
+    subscribers: SubscriberBitSet<SUBSCRIBER_WORDS>,
+    /// published with QoS 1 or 2, kept for persistent sessions that disconnect before reading it
+    persistent: bool,
+    /// set if the message is a part of a streamed publish
+    chunk: Option<Chunk>,
+}
+
+/// Position of a queued message in a publish that is too large for the queue, see
+/// [`Distributor::publish_chunk`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunk {
+    /// id of the streamed publish
+    stream: u32,
+    /// the first chunk starts with the header of the publish
+    pub first: bool,
+    pub last: bool,
+}
+
+/// Header of a publish that is forwarded chunk by chunk, see [`Distributor::publish_chunk`]
+#[derive(Debug, Clone, Copy)]
+pub struct StreamStart<'a> {
+    pub topic: &'a str,
+    pub retain: bool,
+    /// length of the properties and the payload of the whole packet
+    pub len: usize,
+}
 
+/// A streamed publish of a socket whose last chunk has not been queued yet
 #[derive(Debug)]
-pub struct MessageInQueue {
-    message: Message,
-    subscribers: SubscriberBitSet,
+struct Stream<const SUBSCRIBER_WORDS: usize> {
+    /// id of the publish, its chunks get ids of their own
+    id: u32,
+    /// sockets that receive the publish, fixed by the first chunk
+    subscribers: SubscriberBitSet<SUBSCRIBER_WORDS>,
 }
+
+/// Messages queued in the storage for an offline persistent session, their keys end with the
+/// sequences `head..tail`
+#[derive(Debug)]
+struct OfflineQueue<const KEY_LENGTH: usize> {
+    session: Key<KEY_LENGTH>,
+    head: u32,
+    tail: u32,
+}
+
+impl<const KEY_LENGTH: usize> OfflineQueue<KEY_LENGTH> {
+    fn len(&self) -> usize {
+        self.tail.wrapping_sub(self.head) as usize
+    }
+}
+
+/// A client that completed the handshake, see `InnerDistributor::clients`
 #[derive(Debug, Clone)]
-pub struct Message {
-    buf: PacketWriter<MAX_MESSAGE_SIZE>,
+pub struct ClientInfo {
+    /// truncated to `MAX_CLIENT_ID_LENGTH`
+    pub client_id: ClientId,
+    /// `None` if the connection is not served by a TCP socket of the broker
+    pub remote: Option<IpEndpoint>,
+    pub connected_at: Instant,
+}
+
+/// A message read from the queue
+/// the encoded packet stays in the shared slot until every subscriber dropped its message, so
+/// it can be written to the socket without copying it
+pub struct Message<'a> {
+    id: u32,
+    data: &'a [u8],
+    slot: usize,
+    chunk: Option<Chunk>,
+    pool: &'a dyn ReleaseSlot,
 }
 
-impl Message {
+impl<'a> Message<'a> {
+    /// the encoded PUBLISH, always with QoS 0 and without packet identifier
+    #[inline]
+    pub fn message(&self) -> &'a [u8] {
+        self.data
+    }
+    /// id assigned by the distributor, used to detect duplicates between federated brokers
+    #[inline]
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+    /// set if the message is a part of a streamed publish, it is not a whole packet then
+    /// the chunks of a publish are read one after another, without other messages in between
     #[inline]
-    pub fn message(&self) -> &[u8] {
-        &self.buf.get_written_data()
+    pub fn chunk(&self) -> Option<Chunk> {
+        self.chunk
+    }
+}
+
+impl Drop for Message<'_> {
+    fn drop(&mut self) {
+        self.pool.release(self.slot);
+    }
+}
+
+impl fmt::Debug for Message<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Message")
+            .field("id", &self.id)
+            .field("slot", &self.slot)
+            .field("chunk", &self.chunk)
+            .finish()
+    }
+}
+
+trait ReleaseSlot {
+    /// drops a reference to the slot, it can be reused once nobody references it
+    fn release(&self, slot: usize);
+}
+
+struct Slot<const MAX_MESSAGE_SIZE: usize> {
+    /// how many subscribers still need the message, the slot is free if this is 0
+    refs: AtomicUsize,
+    buf: UnsafeCell<PacketWriter<MAX_MESSAGE_SIZE>>,
+}
+
+// the buffer is only written while nobody references the slot and the distributor is locked,
+// afterwards it is only read until the last reference has been released
+unsafe impl<const MAX_MESSAGE_SIZE: usize> Sync for Slot<MAX_MESSAGE_SIZE> {}
+
+/// Buffers of the queued messages, every message is stored once for all of its subscribers
+/// the pool lives outside of the distributor mutex so sockets can send messages without locking
+pub(crate) struct SlotPool<
+    M: RawMutex,
+    const N: usize,
+    const QUEUE_LEN: usize,
+    const MAX_MESSAGE_SIZE: usize,
+> {
+    slots: [Slot<MAX_MESSAGE_SIZE>; QUEUE_LEN],
+    /// publishers waiting for a free slot
+    freed: BlockingMutex<M, RefCell<MultiWakerRegistration<N>>>,
+}
+
+impl<M: RawMutex, const N: usize, const QUEUE_LEN: usize, const MAX_MESSAGE_SIZE: usize>
+    SlotPool<M, N, QUEUE_LEN, MAX_MESSAGE_SIZE>
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            slots: core::array::from_fn(|_| Slot {
+                refs: AtomicUsize::new(0),
+                buf: UnsafeCell::new(PacketWriter::default()),
+            }),
+            freed: BlockingMutex::new(RefCell::new(MultiWakerRegistration::new())),
+        }
+    }
+    fn free_slot(&self) -> Option<usize> {
+        self.slots
+            .iter()
+            .position(|slot| slot.refs.load(Ordering::Acquire) == 0)
+    }
+    fn free_slots(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| slot.refs.load(Ordering::Acquire) == 0)
+            .count()
+    }
+    /// wakes `waker` once a slot has been freed
+    fn register(&self, waker: &Waker) {
+        self.freed
+            .lock(|wakers| wakers.borrow_mut().register(waker));
+    }
+    /// encodes `packet` into the free `slot` and hands out `refs` references to it
+    /// must only be called while the distributor is locked
+    fn store(&self, slot: usize, packet: &MqttPacket, refs: usize) -> Result<(), DistributorError> {
+        self.store_with(slot, refs, |buf| packet.write(buf))
+    }
+    /// same as [`Self::store`], but `write` encodes the message
+    fn store_with(
+        &self,
+        slot: usize,
+        refs: usize,
+        write: impl FnOnce(&mut PacketWriter<MAX_MESSAGE_SIZE>) -> Result<(), MqttWriteError>,
+    ) -> Result<(), DistributorError> {
+        let slot = &self.slots[slot];
+        assert_eq!(slot.refs.load(Ordering::Acquire), 0, "slot in use");
+        // SAFETY: nobody references the slot and the locked distributor prevents other writers
+        let buf = unsafe { &mut *slot.buf.get() };
+        buf.write_index = 0;
+        write(buf).map_err(|_| DistributorError::MessageTooLong)?;
+        slot.refs.store(refs, Ordering::Release);
+        Ok(())
+    }
+    /// turns a reference to `slot` held by the queue into a message
+    fn message(&self, slot: usize, id: u32, chunk: Option<Chunk>) -> Message<'_> {
+        // SAFETY: the slot is referenced, so it is not written until the message is dropped
+        let buf = unsafe { &*self.slots[slot].buf.get() };
+        Message {
+            id,
+            data: buf.get_written_data(),
+            slot,
+            chunk,
+            pool: self,
+        }
+    }
+}
+
+impl<M: RawMutex, const N: usize, const QUEUE_LEN: usize, const MAX_MESSAGE_SIZE: usize> ReleaseSlot
+    for SlotPool<M, N, QUEUE_LEN, MAX_MESSAGE_SIZE>
+{
+    fn release(&self, slot: usize) {
+        if self.slots[slot].refs.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.freed.lock(|wakers| wakers.borrow_mut().wake());
+        }
     }
 }
-pub struct InnerDistributor<const N: usize> {
-    queue: Deque<MessageInQueue, QUEUE_LEN>,
-    tree: TopicsList<TREE_SIZE, N>,
+
+/// What happens when a message is published while the queue is full
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// the new message is dropped
+    DropNewest,
+    /// the oldest message is dropped, subscribers that did not read it yet miss it
+    DropOldest,
+    /// subscribers that did not read the oldest message yet are disconnected
+    /// with `ReceiveMaximumExceeded`
+    Disconnect,
+    /// publishers wait until there is space in the queue
+    #[default]
+    Block,
+}
+
+/// N is the amount of sockets, SUBSCRIBER_WORDS has to be at least `subscriber_words(N)`
+/// the other parameters define the memory budget of the broker, see `config` for their defaults
+pub struct InnerDistributor<
+    const N: usize,
+    const SUBSCRIBER_WORDS: usize = DEFAULT_SUBSCRIBER_WORDS,
+    const QUEUE_LEN: usize = DEFAULT_QUEUE_LEN,
+    const TREE_SIZE: usize = DEFAULT_TREE_SIZE,
+    const MAX_MESSAGE_SIZE: usize = DEFAULT_MAX_MESSAGE_SIZE,
+    const MAX_TOPIC_LENGTH: usize = DEFAULT_MAX_TOPIC_LENGTH,
+    const MAX_WILL_LENGTH: usize = DEFAULT_MAX_WILL_LENGTH,
+    const KEY_LENGTH: usize = DEFAULT_KEY_LENGTH,
+> {
+    queue: Deque<MessageInQueue<SUBSCRIBER_WORDS>, QUEUE_LEN>,
+    tree: Tree<TREE_SIZE, SUBSCRIBER_WORDS, MAX_TOPIC_LENGTH>,
+    /// will of every socket, published when the connection is lost
+    wills: [Option<PacketWriter<MAX_WILL_LENGTH>>; N],
     wakers: [Option<Waker>; N],
     lock_wakers: [Option<Waker>; N],
-    lock: SubscriberBitSet,
+    lock: SubscriberBitSet<SUBSCRIBER_WORDS>,
+    /// sockets connected to federated brokers
+    peers: SubscriberBitSet<SUBSCRIBER_WORDS>,
+    broker_id: Option<&'static str>,
+    /// messages imported from federated brokers, shared by all links
+    duplicates: DuplicateCache<DUPLICATE_CACHE_SIZE>,
+    next_message_id: u32,
+    overflow_policy: OverflowPolicy,
+    /// sockets that have been dropped from the queue because they were too slow
+    overflowed: SubscriberBitSet<SUBSCRIBER_WORDS>,
+    /// messages that could not be delivered to all of their subscribers
+    dropped: u32,
+    /// changes whenever a subscription is added or removed
+    interest_version: u32,
+    /// publish every socket is streaming, see `publish_chunk`
+    streams: [Option<Stream<SUBSCRIBER_WORDS>>; N],
+    /// streamed publish every socket is writing to its client, it does not read other messages
+    /// till the last chunk
+    receiving: [Option<u32>; N],
+    /// sockets that can write streamed publishes to their clients
+    streaming: SubscriberBitSet<SUBSCRIBER_WORDS>,
+    /// retained messages and persistent sessions
+    storage: Option<&'static dyn Storage>,
+    /// key of the persistent session of every socket, messages for sessions without socket are
+    /// queued in the storage
+    sessions: [Option<Key<KEY_LENGTH>>; N],
+    /// sockets whose session may still have queued messages
+    queued: SubscriberBitSet<SUBSCRIBER_WORDS>,
+    /// sockets sending the oldest queued message of their session, it is removed from the
+    /// storage once the socket asks for the next message
+    sending_queued: SubscriberBitSet<SUBSCRIBER_WORDS>,
+    /// sessions with messages in the storage
+    offline_queues: Vec<OfflineQueue<KEY_LENGTH>, MAX_QUEUED_SESSIONS>,
+    offline_queue_len: usize,
+    counters: DistributorCounters<N>,
+    /// client connected to every socket
+    clients: [Option<ClientInfo>; N],
+    /// address of the peer of every socket, known before its client completed the handshake
+    remotes: [Option<IpEndpoint>; N],
+    /// reason sent to the clients that are disconnected by the server, see `disconnect`
+    disconnects: [Option<DisconnectReasonCode>; N],
+    /// sockets whose clients may use the `$CONTROL` topics
+    control_allowed: SubscriberBitSet<SUBSCRIBER_WORDS>,
+    reload_acl: Option<fn() -> bool>,
 }
 
-impl<const N: usize> Default for InnerDistributor<N> {
+impl<
+        const N: usize,
+        const SUBSCRIBER_WORDS: usize,
+        const QUEUE_LEN: usize,
+        const TREE_SIZE: usize,
+        const MAX_MESSAGE_SIZE: usize,
+        const MAX_TOPIC_LENGTH: usize,
+        const MAX_WILL_LENGTH: usize,
+        const KEY_LENGTH: usize,
+    > Default
+    for InnerDistributor<
+        N,
+        SUBSCRIBER_WORDS,
+        QUEUE_LEN,
+        TREE_SIZE,
+        MAX_MESSAGE_SIZE,
+        MAX_TOPIC_LENGTH,
+        MAX_WILL_LENGTH,
+        KEY_LENGTH,
+    >
+{
     fn default() -> Self {
+        const {
+            assert!(
+                SUBSCRIBER_WORDS >= subscriber_words(N),
+                "SUBSCRIBER_WORDS too small for the amount of sockets"
+            );
+            assert!(
+                KEY_LENGTH >= key_length(MAX_TOPIC_LENGTH),
+                "KEY_LENGTH too small for the topics"
+            )
+        };
         const NONE_WAKER: Option<Waker> = None;
         Self {
             queue: Default::default(),
             tree: Default::default(),
+            wills: core::array::from_fn(|_| None),
             wakers: [NONE_WAKER; N],
             lock_wakers: [NONE_WAKER; N],
             lock: Default::default(),
+            peers: Default::default(),
+            broker_id: None,
+            duplicates: Default::default(),
+            next_message_id: 0,
+            overflow_policy: OverflowPolicy::default(),
+            overflowed: Default::default(),
+            dropped: 0,
+            interest_version: 0,
+            streams: core::array::from_fn(|_| None),
+            receiving: [None; N],
+            streaming: Default::default(),
+            storage: None,
+            sessions: core::array::from_fn(|_| None),
+            queued: Default::default(),
+            sending_queued: Default::default(),
+            offline_queues: Vec::new(),
+            offline_queue_len: DEFAULT_OFFLINE_QUEUE_LEN,
+            counters: DistributorCounters::new(),
+            clients: core::array::from_fn(|_| None),
+            remotes: [None; N],
+            disconnects: core::array::from_fn(|_| None),
+            control_allowed: Default::default(),
+            reload_acl: None,
         }
     }
 }
-impl<const N: usize> InnerDistributor<N> {
+impl<
+        const N: usize,
+        const SUBSCRIBER_WORDS: usize,
+        const QUEUE_LEN: usize,
+        const TREE_SIZE: usize,
+        const MAX_MESSAGE_SIZE: usize,
+        const MAX_TOPIC_LENGTH: usize,
+        const MAX_WILL_LENGTH: usize,
+        const KEY_LENGTH: usize,
+    >
+    InnerDistributor<
+        N,
+        SUBSCRIBER_WORDS,
+        QUEUE_LEN,
+        TREE_SIZE,
+        MAX_MESSAGE_SIZE,
+        MAX_TOPIC_LENGTH,
+        MAX_WILL_LENGTH,
+        KEY_LENGTH,
+    >
+{
+    /// creates a distributor that can be part of a federation, `broker_id` has to be unique
+    /// between all federated brokers
+    pub fn with_broker_id(broker_id: &'static str) -> Self {
+        Self {
+            broker_id: Some(broker_id),
+            ..Default::default()
+        }
+    }
+    /// sets what happens when the queue is full, publishers are blocked by default
+    pub fn with_overflow_policy(self, overflow_policy: OverflowPolicy) -> Self {
+        Self {
+            overflow_policy,
+            ..self
+        }
+    }
+    /// keeps retained messages and persistent sessions in `storage`, without storage they are
+    /// not supported
+    pub fn with_storage(self, storage: &'static dyn Storage) -> Self {
+        let mut offline_queues = Vec::<OfflineQueue<KEY_LENGTH>, MAX_QUEUED_SESSIONS>::new();
+        storage.for_each(QUEUED_PREFIX, &mut |key, _| {
+            let Some((session, sequence)) = key.rsplit_once('\0') else {
+                return;
+            };
+            let (Some(session), Ok(sequence)) = (
+                stored_session_key::<KEY_LENGTH>(session),
+                u32::from_str_radix(sequence, 16),
+            ) else {
+                return;
+            };
+            match offline_queues.iter_mut().find(|q| q.session == session) {
+                Some(queue) => {
+                    queue.head = u32::min(queue.head, sequence);
+                    queue.tail = u32::max(queue.tail, sequence.wrapping_add(1));
+                }
+                None => {
+                    let queue = OfflineQueue {
+                        session,
+                        head: sequence,
+                        tail: sequence.wrapping_add(1),
+                    };
+                    if offline_queues.push(queue).is_err() {
+                        warn!("DISTRIBUTOR: too many sessions with queued messages");
+                    }
+                }
+            }
+        });
+        Self {
+            storage: Some(storage),
+            offline_queues,
+            ..self
+        }
+    }
+    /// sets how many QoS 1 and 2 messages are queued for a persistent session while its client
+    /// is offline, 0 disables queueing
+    pub fn with_offline_queue_len(self, offline_queue_len: usize) -> Self {
+        Self {
+            offline_queue_len,
+            ..self
+        }
+    }
+    /// sets the hook called for `$CONTROL/reload_acl`, e.g. to reload the credentials checked by
+    /// `ConnectionConfig::authenticate`, it returns false if reloading failed
+    pub fn with_reload_acl(self, reload_acl: fn() -> bool) -> Self {
+        Self {
+            reload_acl: Some(reload_acl),
+            ..self
+        }
+    }
+    /// space in the queue is only reserved if publishers are blocked, for every locked
+    /// publisher and for the streams of the other sockets than `id`
+    fn has_space_for(&self, free_slots: usize, id: usize) -> bool {
+        self.overflow_policy != OverflowPolicy::Block
+            || free_slots > self.lock.count_ones() + self.reserved_for_streams(id)
+    }
+    /// amount of streams of other sockets than `id` that wait for a slot for their next chunk
+    /// their subscribers skip all other messages, so other messages must not take that slot
+    fn reserved_for_streams(&self, id: usize) -> usize {
+        self.streams
+            .iter()
+            .enumerate()
+            .filter(|&(publisher, _)| publisher != id && !self.lock.get(publisher))
+            .filter_map(|(_, stream)| stream.as_ref())
+            .filter(|stream| {
+                !stream.subscribers.is_empty()
+                    && !self
+                        .queue
+                        .iter()
+                        .any(|msg| msg.chunk.is_some_and(|chunk| chunk.stream == stream.id))
+            })
+            .count()
+    }
     fn lock_for_publishing(&mut self, id: usize) -> Result<(), DistributorError> {
         assert!(!self.lock.get(id), "Lock already set");
         self.lock.set(id);
@@ -61,23 +519,61 @@ impl<const N: usize> InnerDistributor<N> {
             }
         });
     }
-    fn publish(&mut self, topic: &str, publish: &MPublish) -> Result<(), DistributorError> {
-        let subscribers = self.tree.get_subscribed(topic);
+    fn publish<M: RawMutex>(
+        &mut self,
+        pool: &SlotPool<M, N, QUEUE_LEN, MAX_MESSAGE_SIZE>,
+        topic: &str,
+        publish: &MPublish,
+        skip: Option<usize>,
+    ) -> Result<(), DistributorError> {
+        if publish.retain {
+            self.retain(topic, publish);
+        }
+        let persistent = !matches!(publish.quality_of_service, QualityOfService::AtMostOnce);
+        if persistent {
+            self.queue_for_offline_sessions(topic, publish);
+        }
+        let mut subscribers = self.tree.get_subscribed(topic);
+        if let Some(id) = skip {
+            subscribers.unset(id);
+        }
         if subscribers.is_empty() {
             return Ok(());
         }
 
-        let mut writer = PacketWriter::default();
-        let packet = MqttPacket::Publish(publish.clone());
-        packet
-            .write(&mut writer)
-            .map_err(|_| DistributorError::MessageTooLong)?;
+        // the broker only sends QoS 0, so the same packet can be sent to every subscriber
+        let packet = MqttPacket::Publish(MPublish {
+            duplicate: false,
+            quality_of_service: QualityOfService::AtMostOnce,
+            packet_identifier: None,
+            ..publish.clone()
+        });
+        if packet.binary_size() as usize > MAX_MESSAGE_SIZE {
+            return Err(DistributorError::MessageTooLong);
+        }
 
-        let message = Message { buf: writer };
+        self.make_room(pool);
+        let Some(slot) = pool.free_slot() else {
+            self.count_dropped(&subscribers);
+            return Ok(());
+        };
+        // slow sockets do not receive anything till they noticed the overflow
+        for id in self.overflowed.iter_ones() {
+            subscribers.unset(id);
+        }
+        if subscribers.is_empty() {
+            return Ok(());
+        }
 
+        pool.store(slot, &packet, subscribers.count_ones())?;
+        self.count_slots_in_use(pool);
+        self.next_message_id = self.next_message_id.wrapping_add(1);
         let msg = MessageInQueue {
+            id: self.next_message_id,
+            slot,
             subscribers,
-            message,
+            persistent,
+            chunk: None,
         };
 
         for i in msg.subscribers.iter_ones() {
@@ -86,234 +582,2014 @@ impl<const N: usize> InnerDistributor<N> {
             }
         }
 
-        if let Err(e) = self.queue.push_back(msg) {
-            panic!("Queue full {:?}\nDied on topic {}", e, topic);
-        }
+        // every queued message references its own slot, so there is space if a slot is free
+        let _ = self.queue.push_back(msg);
         Ok(())
     }
-    fn subscribe(&mut self, subscription: &str, id: usize) -> Result<(), DistributorError> {
-        self.tree.insert(subscription, id).map_err(|e| e.into())
-    }
-    fn unsubscribe(&mut self, subscription: &str, id: usize) {
-        self.tree.remove(subscription, id)
-    }
-    fn unsubscribe_all_topics(&mut self, id: usize) {
-        self.tree.remove_all_subscriptions(id);
-        let cleanup_necessary = self.queue.iter_mut().any(|msg| {
-            let cleanup_necessary = msg.subscribers.get(id);
-            msg.subscribers.unset(id);
-            cleanup_necessary
-        });
-        if cleanup_necessary {
-            for _ in 0..self.queue.len() {
-                let e = self.queue.pop_back().unwrap();
-                if !e.subscribers.is_empty() {
-                    self.queue.push_front(e).unwrap()
+    /// frees a slot as the overflow policy says if the queue is full
+    fn make_room<M: RawMutex>(&mut self, pool: &SlotPool<M, N, QUEUE_LEN, MAX_MESSAGE_SIZE>) {
+        if pool.free_slot().is_some() {
+            return;
+        }
+        match self.overflow_policy {
+            // blocking only works if the publisher locked the distributor beforehand
+            OverflowPolicy::DropNewest | OverflowPolicy::Block => {}
+            OverflowPolicy::DropOldest => {
+                // at most one message is dropped, if its slot is still sent by some socket
+                // the new message is dropped as well instead of emptying the queue
+                if let Some(oldest) = self.queue.pop_front() {
+                    self.count_dropped(&oldest.subscribers);
+                    for id in oldest.subscribers.iter_ones() {
+                        pool.release(oldest.slot);
+                        // a streamed publish can not be completed without the chunk
+                        if oldest.chunk.is_some() {
+                            self.overflowed.set(id);
+                            if let Some(w) = self.wakers[id].as_ref() {
+                                w.wake_by_ref()
+                            }
+                        }
+                    }
+                }
+            }
+            OverflowPolicy::Disconnect => {
+                // only the subscribers that did not read the oldest message are disconnected, if
+                // its slot is still sent by some socket the new message is dropped instead
+                if let Some(oldest) = self.queue.front() {
+                    let slow = oldest.subscribers.clone();
+                    self.count_dropped(&slow);
+                    for id in slow.iter_ones() {
+                        self.overflowed.set(id);
+                        self.remove_from_queue(pool, id);
+                        if let Some(w) = self.wakers[id].as_ref() {
+                            w.wake_by_ref()
+                        }
+                    }
                 }
             }
         }
     }
 
-    #[allow(unused)]
-    fn get_last(&self, id: usize) -> Option<&MessageInQueue> {
-        self.queue.back().filter(|last| last.subscribers.get(id))
-    }
-    #[allow(unused)]
-    fn get_last_mut(&mut self, id: usize) -> Option<&mut MessageInQueue> {
-        self.queue
-            .back_mut()
-            .filter(|last| last.subscribers.get(id))
-    }
-}
-
-pub struct Distributor<const N: usize> {
-    id: usize,
-    inner: &'static InnerDistributorMutex<N>,
-    will: Option<PacketWriter<MAX_WILL_LENGTH>>,
-}
-
-impl<const N: usize> Distributor<N> {
-    /// use this function so the server only processes n MQTT messages at a time
-    /// were: n = QUEUE_LEN
-    /// it should be used as follows
-    /// ```no_run
-    /// loop {
-    ///     let msg = match select(distributor.next(), distributor.lock(parser.next())).await {
-    ///         ...
-    ///     }
-    ///     distributor.unlock();
-    /// }
-    /// ```
-    pub async fn lock<T>(&self, feature: impl Future<Output = T>) -> T {
-        let res = feature.await;
-
-        // delay till there is enough space
-        poll_fn(move |cx| {
-            let mut inner = self.inner.try_lock().unwrap();
-
-            let available = QUEUE_LEN - inner.queue.len();
-
-            return if available > inner.lock.count_ones() {
-                inner.lock_wakers[self.id] = None;
-                inner.lock_for_publishing(self.id).unwrap();
-                Poll::Ready(())
-            } else {
-                inner.lock_wakers[self.id] = Some(cx.waker().clone());
-                Poll::Pending
+    /// queues a chunk of a publish of socket `id` that does not fit into the queue
+    /// the first chunk comes with the `start` of the publish and fixes its subscribers, sockets
+    /// that can not send chunks and federated brokers miss it; streamed publishes are neither
+    /// retained nor queued for offline sessions and do not reach the `$CONTROL` topics
+    /// a chunk that can not be queued aborts the publish
+    fn publish_chunk<M: RawMutex>(
+        &mut self,
+        pool: &SlotPool<M, N, QUEUE_LEN, MAX_MESSAGE_SIZE>,
+        id: usize,
+        start: Option<StreamStart>,
+        data: &[u8],
+        last: bool,
+    ) -> Result<(), DistributorError> {
+        if let Some(start) = start {
+            // an unfinished publish is not continued
+            self.abort_stream(pool, id);
+            let mut subscribers = match control::action(start.topic) {
+                Some(_) => SubscriberBitSet::default(),
+                None => self.tree.get_subscribed(start.topic),
             };
-        })
-        .await;
+            subscribers.unset(id);
+            let mut missed = SubscriberBitSet::default();
+            for i in subscribers.clone().iter_ones() {
+                if !self.streaming.get(i) || self.peers.get(i) {
+                    subscribers.unset(i);
+                    missed.set(i);
+                }
+            }
+            if !missed.is_empty() {
+                self.count_dropped(&missed);
+            }
+            self.next_message_id = self.next_message_id.wrapping_add(1);
+            self.streams[id] = Some(Stream {
+                id: self.next_message_id,
+                subscribers,
+            });
+        }
+        // the rest of an aborted publish is ignored
+        let Some(stream) = &self.streams[id] else {
+            return Ok(());
+        };
+        let chunk = Chunk {
+            stream: stream.id,
+            first: start.is_some(),
+            last,
+        };
+        if stream.subscribers.is_empty() {
+            if last {
+                self.streams[id] = None;
+            }
+            return Ok(());
+        }
 
-        res
+        self.make_room(pool);
+        let Some(slot) = pool.free_slot() else {
+            self.abort_stream(pool, id);
+            return Ok(());
+        };
+        let Some(stream) = self.streams[id].as_mut() else {
+            return Ok(());
+        };
+        // slow sockets do not receive anything till they noticed the overflow
+        for i in self.overflowed.iter_ones() {
+            stream.subscribers.unset(i);
+        }
+        let subscribers = stream.subscribers.clone();
+        if subscribers.is_empty() {
+            if last {
+                self.streams[id] = None;
+            }
+            return Ok(());
+        }
+        let stored = pool.store_with(slot, subscribers.count_ones(), |buf| {
+            if let Some(start) = start {
+                write_publish_header(buf, start.retain, start.topic, start.len)?;
+            }
+            buf.write_slice(data)
+        });
+        if let Err(e) = stored {
+            self.abort_stream(pool, id);
+            return Err(e);
+        }
+        self.count_slots_in_use(pool);
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+        for i in subscribers.iter_ones() {
+            if let Some(w) = self.wakers[i].as_ref() {
+                w.wake_by_ref()
+            }
+        }
+        // every queued message references its own slot, so there is space if a slot is free
+        let _ = self.queue.push_back(MessageInQueue {
+            id: self.next_message_id,
+            slot,
+            subscribers,
+            persistent: false,
+            chunk: Some(chunk),
+        });
+        if last {
+            self.streams[id] = None;
+        }
+        Ok(())
     }
-    /// unlocks previously locked with `lock` function
-    pub fn unlock(&self) {
-        self.inner
-            .try_lock()
-            .unwrap()
-            .unlock_for_publishing(self.id);
+    /// drops the unfinished publish of socket `id` and its queued chunks, sockets that already
+    /// sent a part of it are disconnected
+    fn abort_stream<M: RawMutex>(
+        &mut self,
+        pool: &SlotPool<M, N, QUEUE_LEN, MAX_MESSAGE_SIZE>,
+        id: usize,
+    ) {
+        let Some(stream) = self.streams[id].take() else {
+            return;
+        };
+        self.count_dropped(&stream.subscribers);
+        for i in 0..N {
+            if self.receiving[i] == Some(stream.id) {
+                self.receiving[i] = None;
+                self.overflowed.set(i);
+                if let Some(w) = self.wakers[i].as_ref() {
+                    w.wake_by_ref()
+                }
+            }
+        }
+        for msg in self.queue.iter_mut() {
+            if msg.chunk.is_some_and(|chunk| chunk.stream == stream.id) {
+                for _ in msg.subscribers.iter_ones() {
+                    pool.release(msg.slot);
+                }
+                msg.subscribers = SubscriberBitSet::default();
+            }
+        }
+        self.remove_delivered();
     }
-}
-
-impl<const N: usize> Distributor<N> {
-    pub fn new(inner: &'static InnerDistributorMutex<N>, id: usize) -> Self {
-        Self {
-            id,
-            inner,
-            will: None,
+    /// aborts the unfinished publish of socket `id` and removes the socket from the streamed
+    /// publishes of the others
+    fn leave_streams<M: RawMutex>(
+        &mut self,
+        pool: &SlotPool<M, N, QUEUE_LEN, MAX_MESSAGE_SIZE>,
+        id: usize,
+    ) {
+        self.abort_stream(pool, id);
+        for stream in self.streams.iter_mut().flatten() {
+            stream.subscribers.unset(id);
         }
+        self.receiving[id] = None;
     }
-    /// gets the socket id
-    pub fn get_id(&self) -> usize {
-        self.id
+    /// counts a message that could not be delivered to `subscribers`
+    fn count_dropped(&mut self, subscribers: &SubscriberBitSet<SUBSCRIBER_WORDS>) {
+        self.dropped = self.dropped.wrapping_add(1);
+        for id in subscribers.iter_ones() {
+            self.counters.dropped[id] = self.counters.dropped[id].wrapping_add(1);
+        }
     }
-
-    /// Publishes a message to all subscribers of a topic
-    pub fn publish(&self, topic: &str, publish: &MPublish) -> Result<(), DistributorError> {
-        self.inner.try_lock().unwrap().publish(topic, publish)
+    /// updates the high water mark of the queue after a slot has been taken
+    fn count_slots_in_use<M: RawMutex>(
+        &mut self,
+        pool: &SlotPool<M, N, QUEUE_LEN, MAX_MESSAGE_SIZE>,
+    ) {
+        let in_use = QUEUE_LEN - pool.free_slots();
+        self.counters.queue_high_water = usize::max(self.counters.queue_high_water, in_use);
     }
-
-    /// Subscribes to a topic
-    pub fn subscribe(&self, subscription: &str) -> Result<(), DistributorError> {
-        self.inner
-            .try_lock()
-            .unwrap()
-            .subscribe(subscription, self.id)
+    /// counts a client that completed the handshake on socket `id`
+    fn connection_accepted(&mut self, id: usize, client_id: &str) {
+        self.counters.connections_accepted = self.counters.connections_accepted.wrapping_add(1);
+        self.counters.connected[id] = true;
+        let mut truncated = ClientId::new();
+        for c in client_id.chars() {
+            if truncated.push(c).is_err() {
+                break;
+            }
+        }
+        self.clients[id] = Some(ClientInfo {
+            client_id: truncated,
+            remote: self.remotes[id],
+            connected_at: Instant::now(),
+        });
     }
-
-    /// should always be called when socket connection is closed.
-    /// cleans up all previous subscriptions and unlocks distributor for new messages to be received
-    pub fn cleanup(&self) {
-        let mut inner = self.inner.try_lock().unwrap();
-        inner.unsubscribe_all_topics(self.id);
-        inner.unlock_for_publishing(self.id);
+    /// counts a client that has been refused
+    pub(crate) fn connection_rejected(&mut self) {
+        self.counters.connections_rejected = self.counters.connections_rejected.wrapping_add(1);
     }
-
-    /// fulfill will and publish will message to defined topic
-    pub async fn fulfill_will(&mut self) {
-        if let Some(will) = &self.will {
-            let packet = MqttPacket::parse_complete(will.get_written_data()).unwrap();
-            let packet = match packet {
-                MqttPacket::Publish(ref publish) => publish,
-                _ => unreachable!(),
-            };
-            // wait till there is time to publish message
-            self.lock(async {}).await;
-            let _ = self.publish(packet.topic_name, packet);
-            self.unlock();
-        }
-        self.will = None;
+    /// resets the counters of socket `id` after its connection has been closed
+    fn connection_closed(&mut self, id: usize, traffic: &TrafficCounters) {
+        self.counters.close(&traffic.take());
+        self.counters.connected[id] = false;
+        self.counters.dropped[id] = 0;
+        self.clients[id] = None;
+        self.remotes[id] = None;
+        self.disconnects[id] = None;
     }
-
-    pub fn set_will(&mut self, will: MPublish) -> Result<(), DistributorError> {
-        let mut writer = PacketWriter::default();
-        MqttPacket::Publish(will)
-            .write(&mut writer)
-            .map_err(|_| DistributorError::MessageTooLong)?;
-        self.will = Some(writer);
-        Ok(())
+    /// clients that completed the handshake with the id of their socket
+    pub fn clients(&self) -> impl Iterator<Item = (usize, &ClientInfo)> {
+        self.clients
+            .iter()
+            .enumerate()
+            .filter_map(|(id, client)| Some((id, client.as_ref()?)))
     }
-
-    pub fn unset_will(&mut self) {
-        self.will = None;
+    /// calls `f` for every topic filter the client of socket `id` subscribed to
+    /// filters are rebuilt from the subscription tree, so they include the mount point
+    pub fn subscriptions(&self, id: usize, mut f: impl FnMut(&str)) {
+        self.tree.for_each_subscription(|filter, subscribers| {
+            if subscribers.get(id) {
+                f(filter)
+            }
+        });
     }
-
-    pub fn unsubscribe(&self, subscription: &str) {
-        self.inner
-            .try_lock()
-            .unwrap()
-            .unsubscribe(subscription, self.id);
+    /// makes socket `id` send a DISCONNECT with `reason` and close the connection
+    /// returns false if no client is connected to the socket
+    pub fn disconnect(&mut self, id: usize, reason: DisconnectReasonCode) -> bool {
+        if self.clients.get(id).map_or(true, |client| client.is_none()) {
+            return false;
+        }
+        self.disconnects[id] = Some(reason);
+        if let Some(w) = self.wakers[id].as_ref() {
+            w.wake_by_ref()
+        }
+        true
     }
-
-    pub fn next(&self) -> impl Future<Output = Message> + '_ {
-        poll_fn(move |cx| self.poll_next(cx, self.id))
+    fn stats(&self, traffic: &[TrafficCounters; N], queue_used: usize) -> Stats<N> {
+        Stats {
+            connections_accepted: self.counters.connections_accepted,
+            connections_rejected: self.counters.connections_rejected,
+            traffic: self.counters.total(traffic.iter().map(|t| t.snapshot())),
+            dropped: self.dropped,
+            queue_used,
+            queue_high_water: self.counters.queue_high_water,
+            queue_capacity: QUEUE_LEN,
+            tree_used: self.tree.len(),
+            tree_capacity: TREE_SIZE,
+            clients: core::array::from_fn(|id| {
+                ClientStats::new(
+                    self.counters.connected[id],
+                    &traffic[id].snapshot(),
+                    self.counters.dropped[id],
+                    self.tree.subscriptions(id),
+                )
+            }),
+        }
     }
-
-    fn poll_next(&self, _cx: &mut Context, id: usize) -> Poll<Message> {
-        // todo maybe needs to be changed? Does the task wake up again?
-        let mut inner = self.inner.try_lock().unwrap();
-
-        let message = match inner.get_last_mut(id) {
-            // last message is not ment for this subscriber
-            None => {
-                inner.wakers[id] = Some(_cx.waker().clone());
-                return Poll::Pending;
-            }
-            Some(last) => {
-                last.subscribers.unset(id);
-                if last.subscribers.is_empty() {
-                    // return the value itself if this was the last subscriber
-                    inner.queue.pop_back().unwrap().message
-                } else {
-                    // return a clone since we still need the original for the other subscribers
-                    last.message.clone()
-                }
+    /// replaces the retained message of `topic`, an empty payload removes it
+    fn retain(&self, topic: &str, publish: &MPublish) {
+        let Some(storage) = self.storage else {
+            return;
+        };
+        let Some(key) = retained_key::<KEY_LENGTH>(topic) else {
+            warn!("DISTRIBUTOR: topic too long to retain");
+            return;
+        };
+        let mut writer = PacketWriter::<MAX_MESSAGE_SIZE>::default();
+        if !publish.payload.is_empty() {
+            // sent to new subscribers with the retain flag
+            let packet = MqttPacket::Publish(MPublish {
+                duplicate: false,
+                quality_of_service: QualityOfService::AtMostOnce,
+                packet_identifier: None,
+                retain: true,
+                topic_name: topic,
+                ..publish.clone()
+            });
+            if packet.write(&mut writer).is_err() {
+                return;
             }
+        }
+        if let Err(e) = storage.set(&key, writer.get_written_data()) {
+            warn!("DISTRIBUTOR: could not retain message {:?}", e);
+        }
+    }
+    /// removes the retained messages of the topics matching `filter`, returns how many
+    fn clear_retained(&self, filter: &str) -> usize {
+        let Some(storage) = self.storage else {
+            return 0;
         };
-        if inner.queue.is_empty() {
-            inner.lock_wakers.iter().for_each(|w| {
-                if let Some(w) = w.as_ref() {
-                    w.wake_by_ref()
+        let mut cleared = 0;
+        // the storage can not be changed while iterating over it
+        loop {
+            let mut matching = None;
+            storage.for_each(RETAINED_PREFIX, &mut |topic, _| {
+                if matching.is_none() && matches(filter, topic) {
+                    matching = retained_key::<KEY_LENGTH>(topic);
                 }
             });
+            let Some(key) = matching else {
+                return cleared;
+            };
+            if let Err(e) = storage.set(&key, &[]) {
+                warn!("DISTRIBUTOR: could not clear retained message {:?}", e);
+                return cleared;
+            }
+            cleared += 1;
         }
-        inner.wakers[id] = None;
-        Poll::Ready(message)
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use log;
-    use mqtt_format::v5::packets::publish::PublishProperties;
-    use mqtt_format::v5::qos::QualityOfService;
-    use static_cell::make_static;
-
-    #[test]
-    fn test_cleanup() {
-        let inner = &*make_static!(InnerDistributorMutex::new(InnerDistributor::<10>::default()));
-        let dist0 = Distributor::new(&inner, 0);
-        let dist1 = Distributor::new(&inner, 1);
-        dist0.subscribe("/a/b/c").unwrap();
-        dist0.subscribe("/a/b/d").unwrap();
-        dist0.subscribe("/a/b/e").unwrap();
-        dist0.subscribe("/a/b/f").unwrap();
-        dist1.subscribe("/a").unwrap();
-        let publish = MPublish {
-            duplicate: false,
-            quality_of_service: QualityOfService::AtMostOnce,
-            retain: false,
-            topic_name: "",
-            packet_identifier: None,
-            properties: PublishProperties::new(),
-            payload: &[],
+    /// handles a publish of socket `id` to a `$CONTROL` topic, the result is published to the
+    /// response topic of the request, see [`crate::control`]
+    fn control<M: RawMutex>(
+        &mut self,
+        pool: &SlotPool<M, N, QUEUE_LEN, MAX_MESSAGE_SIZE>,
+        traffic: &[TrafficCounters; N],
+        id: usize,
+        action: &str,
+        request: &MPublish,
+    ) {
+        // no response either, otherwise any client could publish messages in the name of the
+        // broker to the response topic
+        if !self.control_allowed.get(id) {
+            warn!(
+                "DISTRIBUTOR: socket {} is not allowed to use {}",
+                id, action
+            );
+            return;
+        }
+        info!("DISTRIBUTOR: control action {} of socket {}", action, id);
+        let result = Command::parse(action, request.payload)
+            .and_then(|command| self.run_command(pool, traffic, command));
+        let Some(topic) = request.properties.response_topic().map(|t| t.0) else {
+            return;
         };
-        dist0.publish("/a", &publish).unwrap();
-        assert_eq!(dist0.inner.try_lock().unwrap().queue.len(), 1);
-        dist0.cleanup();
-        assert_eq!(dist0.inner.try_lock().unwrap().queue.len(), 1);
-        dist1.cleanup();
-        assert_eq!(dist0.inner.try_lock().unwrap().queue.len(), 0);
+        let response = control::finish(result);
+        let mut writer = PacketWriter::<MAX_MESSAGE_SIZE>::default();
+        if control::write_response(&mut writer, request, topic, response.as_bytes()).is_err() {
+            warn!("DISTRIBUTOR: control response too long");
+            return;
+        }
+        let Ok(MqttPacket::Publish(publish)) =
+            MqttPacket::parse_complete(writer.get_written_data())
+        else {
+            return;
+        };
+        if let Err(e) = self.publish(pool, topic, &publish, None) {
+            warn!("DISTRIBUTOR: could not send control response {:?}", e);
+        }
+    }
+    fn run_command<M: RawMutex>(
+        &mut self,
+        pool: &SlotPool<M, N, QUEUE_LEN, MAX_MESSAGE_SIZE>,
+        traffic: &[TrafficCounters; N],
+        command: Command,
+    ) -> Result<Response, &'static str> {
+        let mut response = control::success();
+        match command {
+            Command::Kick(client_id) => {
+                let mut kicked = 0;
+                for id in 0..N {
+                    let matches = self.clients[id]
+                        .as_ref()
+                        .is_some_and(|client| client.client_id == client_id);
+                    if matches && self.disconnect(id, DisconnectReasonCode::AdministrativeAction) {
+                        kicked += 1;
+                    }
+                }
+                if kicked == 0 {
+                    return Err("client not connected");
+                }
+                control::field(&mut response, "kicked", kicked);
+            }
+            Command::ClearRetained(filter) => {
+                if self.storage.is_none() {
+                    return Err("retained messages not supported");
+                }
+                control::field(&mut response, "cleared", self.clear_retained(filter));
+            }
+            Command::ReloadAcl => match self.reload_acl {
+                Some(reload_acl) if reload_acl() => {}
+                Some(_) => return Err("reloading failed"),
+                None => return Err("not supported"),
+            },
+            Command::Stats => {
+                let stats = self.stats(traffic, QUEUE_LEN - pool.free_slots());
+                control::stats_fields(&mut response, &stats);
+            }
+        }
+        Ok(response)
+    }
+    /// queues the retained messages matching `subscription` for socket `id`
+    /// messages that do not fit into the queue are skipped
+    fn send_retained<M: RawMutex>(
+        &mut self,
+        pool: &SlotPool<M, N, QUEUE_LEN, MAX_MESSAGE_SIZE>,
+        subscription: &str,
+        id: usize,
+    ) {
+        let Some(storage) = self.storage else {
+            return;
+        };
+        storage.for_each(RETAINED_PREFIX, &mut |topic, message| {
+            if !matches(subscription, topic) {
+                return;
+            }
+            // slots reserved for publishers are not used
+            if !self.has_space_for(pool.free_slots(), id) {
+                return;
+            }
+            let Some(slot) = pool.free_slot() else {
+                return;
+            };
+            let Ok(packet) = MqttPacket::parse_complete(message) else {
+                return;
+            };
+            if pool.store(slot, &packet, 1).is_err() {
+                return;
+            }
+            self.count_slots_in_use(pool);
+            self.next_message_id = self.next_message_id.wrapping_add(1);
+            let mut subscribers = SubscriberBitSet::default();
+            subscribers.set(id);
+            let _ = self.queue.push_back(MessageInQueue {
+                id: self.next_message_id,
+                slot,
+                subscribers,
+                persistent: false,
+                chunk: None,
+            });
+            if let Some(w) = self.wakers[id].as_ref() {
+                w.wake_by_ref()
+            }
+        });
+    }
+    /// queues a QoS 1 or 2 message for the persistent sessions whose client is offline
+    fn queue_for_offline_sessions(&mut self, topic: &str, publish: &MPublish) {
+        let Some(storage) = self.storage else {
+            return;
+        };
+        if self.offline_queue_len == 0 {
+            return;
+        }
+        let mut offline = Vec::<Key<KEY_LENGTH>, MAX_OFFLINE_SUBSCRIBERS>::new();
+        storage.for_each(SESSION_PREFIX, &mut |session, subscriptions| {
+            let subscribed = subscriptions
+                .split(|b| *b == 0)
+                .filter_map(|subscription| core::str::from_utf8(subscription).ok())
+                .any(|subscription| !subscription.is_empty() && matches(subscription, topic));
+            let Some(key) = stored_session_key::<KEY_LENGTH>(session) else {
+                return;
+            };
+            if !subscribed || self.sessions.iter().flatten().any(|s| *s == key) {
+                return;
+            }
+            if offline.push(key).is_err() {
+                warn!("DISTRIBUTOR: too many offline sessions for {}", topic);
+            }
+        });
+        if offline.is_empty() {
+            return;
+        }
+        // stored the way it is sent from the queue
+        let mut writer = PacketWriter::<MAX_MESSAGE_SIZE>::default();
+        let packet = MqttPacket::Publish(MPublish {
+            duplicate: false,
+            quality_of_service: QualityOfService::AtMostOnce,
+            packet_identifier: None,
+            ..publish.clone()
+        });
+        if packet.write(&mut writer).is_err() {
+            return;
+        }
+        for key in offline.iter() {
+            if !self.queue_offline(key, writer.get_written_data()) {
+                self.dropped = self.dropped.wrapping_add(1);
+            }
+        }
+    }
+    /// stores `message` in the queue of the persistent session `key`
+    /// returns false if the queue of the session is full
+    fn queue_offline(&mut self, key: &str, message: &[u8]) -> bool {
+        let Some(storage) = self.storage else {
+            return false;
+        };
+        let index = match self.offline_queues.iter().position(|q| q.session == key) {
+            Some(index) => index,
+            None => {
+                let Ok(session) = Key::<KEY_LENGTH>::try_from(key) else {
+                    return false;
+                };
+                let queue = OfflineQueue {
+                    session,
+                    head: 0,
+                    tail: 0,
+                };
+                if self.offline_queues.push(queue).is_err() {
+                    warn!("DISTRIBUTOR: too many sessions with queued messages");
+                    return false;
+                }
+                self.offline_queues.len() - 1
+            }
+        };
+        let queue = &mut self.offline_queues[index];
+        if queue.len() >= self.offline_queue_len {
+            return false;
+        }
+        let Some(queued_key) = queued_key::<KEY_LENGTH>(key, queue.tail) else {
+            warn!("DISTRIBUTOR: client id too long to queue messages");
+            self.remove_offline_queue_if_empty(index);
+            return false;
+        };
+        match storage.set(&queued_key, message) {
+            Ok(()) => {
+                queue.tail = queue.tail.wrapping_add(1);
+                true
+            }
+            Err(e) => {
+                warn!("DISTRIBUTOR: could not queue message {:?}", e);
+                self.remove_offline_queue_if_empty(index);
+                false
+            }
+        }
+    }
+    fn remove_offline_queue_if_empty(&mut self, index: usize) {
+        if self.offline_queues[index].len() == 0 {
+            self.offline_queues.swap_remove(index);
+        }
+    }
+    /// removes the oldest message queued for the session `key` from the storage
+    fn pop_queued(&mut self, key: &str) {
+        let Some(index) = self.offline_queues.iter().position(|q| q.session == key) else {
+            return;
+        };
+        let queue = &mut self.offline_queues[index];
+        if let (Some(storage), Some(queued_key)) =
+            (self.storage, queued_key::<KEY_LENGTH>(key, queue.head))
+        {
+            let _ = storage.set(&queued_key, &[]);
+        }
+        queue.head = queue.head.wrapping_add(1);
+        self.remove_offline_queue_if_empty(index);
+    }
+    /// copies the oldest message queued for the session of socket `id` into a free slot
+    /// it stays in the storage till the socket asks for the next message, so it is sent again
+    /// if the connection is lost before it has been written
+    /// fails if there is no free slot, returns `None` once the session has no queued messages
+    fn next_queued<'a, M: RawMutex>(
+        &mut self,
+        pool: &'a SlotPool<M, N, QUEUE_LEN, MAX_MESSAGE_SIZE>,
+        id: usize,
+    ) -> Result<Option<Message<'a>>, ()> {
+        let (Some(storage), Some(key)) = (self.storage, self.sessions[id].clone()) else {
+            self.queued.unset(id);
+            return Ok(None);
+        };
+        if self.sending_queued.get(id) {
+            // the previous message has been written to the socket
+            self.sending_queued.unset(id);
+            self.pop_queued(&key);
+        }
+        loop {
+            let Some(queue) = self.offline_queues.iter().find(|q| q.session == key) else {
+                self.queued.unset(id);
+                return Ok(None);
+            };
+            // slots reserved for publishers are not used
+            if !self.has_space_for(pool.free_slots(), id) {
+                return Err(());
+            }
+            let Some(slot) = pool.free_slot() else {
+                return Err(());
+            };
+            let mut stored = false;
+            // fits, it has been stored with this key
+            if let Some(queued_key) = queued_key::<KEY_LENGTH>(&key, queue.head) {
+                storage.get(&queued_key, &mut |message| {
+                    stored = MqttPacket::parse_complete(message)
+                        .is_ok_and(|packet| pool.store(slot, &packet, 1).is_ok());
+                });
+            }
+            if stored {
+                self.sending_queued.set(id);
+                self.count_slots_in_use(pool);
+                self.next_message_id = self.next_message_id.wrapping_add(1);
+                return Ok(Some(pool.message(slot, self.next_message_id, None)));
+            }
+            // messages that can not be read are skipped
+            self.pop_queued(&key);
+        }
+    }
+    /// moves the unread QoS 1 and 2 messages of socket `id` to the queue of its persistent
+    /// session, afterwards the session is offline
+    fn park_session<M: RawMutex>(
+        &mut self,
+        pool: &SlotPool<M, N, QUEUE_LEN, MAX_MESSAGE_SIZE>,
+        id: usize,
+    ) {
+        self.queued.unset(id);
+        // the message being sent stays queued
+        self.sending_queued.unset(id);
+        let Some(key) = self.sessions[id].take() else {
+            return;
+        };
+        loop {
+            let unread = self.queue.iter().find(|msg| msg.subscribers.get(id));
+            let Some(persistent) = unread.map(|msg| msg.persistent) else {
+                break;
+            };
+            let Some(message) = self.next_message(pool, id) else {
+                break;
+            };
+            if persistent && !self.queue_offline(&key, message.message()) {
+                self.dropped = self.dropped.wrapping_add(1);
+            }
+        }
+    }
+    /// subscribes socket `id` to the topics of the session `key`, messages queued while the
+    /// session was offline are sent before any other message
+    /// returns false if there is no such session
+    fn restore_session(&mut self, key: &str, id: usize) -> bool {
+        let Some(storage) = self.storage else {
+            return false;
+        };
+        let found = storage.get(key, &mut |subscriptions| {
+            for subscription in subscriptions.split(|b| *b == 0) {
+                let Ok(subscription) = core::str::from_utf8(subscription) else {
+                    continue;
+                };
+                if !subscription.is_empty() {
+                    let _ = self.subscribe(subscription, id);
+                }
+            }
+        });
+        if found {
+            self.sessions[id] = Key::<KEY_LENGTH>::try_from(key).ok();
+            self.queued.set(id);
+        }
+        found
+    }
+    /// saves the subscriptions of socket `id` as session `key`, the socket owns the session
+    /// from now on, see [`Self::park_session`]
+    fn save_session(&mut self, key: &str, id: usize) {
+        let Some(storage) = self.storage else {
+            return;
+        };
+        // topic filters separated by zeros, a session without subscriptions is a single zero
+        let mut writer = PacketWriter::<MAX_MESSAGE_SIZE>::default();
+        let mut complete = true;
+        self.tree.for_each_subscription(|topic, subscribers| {
+            if subscribers.get(id) {
+                complete &= writer.write_slice(topic.as_bytes()).is_ok();
+                complete &= writer.write_byte(0).is_ok();
+            }
+        });
+        if writer.write_index == 0 {
+            complete &= writer.write_byte(0).is_ok();
+        }
+        if !complete {
+            warn!("DISTRIBUTOR: too many subscriptions to save session");
+            return;
+        }
+        match storage.set(key, writer.get_written_data()) {
+            Ok(()) => self.sessions[id] = Key::<KEY_LENGTH>::try_from(key).ok(),
+            Err(e) => warn!("DISTRIBUTOR: could not save session {:?}", e),
+        }
+    }
+    /// removes the session `key` and the messages queued for it
+    fn remove_session(&mut self, key: &str) {
+        let Some(storage) = self.storage else {
+            return;
+        };
+        let _ = storage.set(key, &[]);
+        while self.offline_queues.iter().any(|q| q.session == key) {
+            self.pop_queued(key);
+        }
+    }
+    fn subscribe(&mut self, subscription: &str, id: usize) -> Result<(), DistributorError> {
+        self.interest_version = self.interest_version.wrapping_add(1);
+        self.tree.insert(subscription, id).map_err(|e| e.into())
+    }
+    fn unsubscribe(&mut self, subscription: &str, id: usize) {
+        self.interest_version = self.interest_version.wrapping_add(1);
+        self.tree.remove(subscription, id)
+    }
+    fn unsubscribe_all_topics<M: RawMutex>(
+        &mut self,
+        pool: &SlotPool<M, N, QUEUE_LEN, MAX_MESSAGE_SIZE>,
+        id: usize,
+    ) {
+        self.interest_version = self.interest_version.wrapping_add(1);
+        self.tree.remove_all_subscriptions(id);
+        self.remove_from_queue(pool, id);
+    }
+    /// removes the socket from all queued messages
+    fn remove_from_queue<M: RawMutex>(
+        &mut self,
+        pool: &SlotPool<M, N, QUEUE_LEN, MAX_MESSAGE_SIZE>,
+        id: usize,
+    ) {
+        let mut removed = false;
+        for msg in self.queue.iter_mut() {
+            if msg.subscribers.get(id) {
+                msg.subscribers.unset(id);
+                pool.release(msg.slot);
+                removed = true;
+            }
+        }
+        if removed {
+            self.remove_delivered();
+        }
+    }
+
+    /// collects the topic filters of all sockets that are not federated brokers
+    fn local_interest<const M: usize>(&self, interest: &mut Vec<String<MAX_TOPIC_LENGTH>, M>) {
+        self.tree.for_each_subscription(|topic, subscribers| {
+            let local = subscribers.iter_ones().any(|id| !self.peers.get(id));
+            if !local || interest.iter().any(|t| t == topic) {
+                return;
+            }
+            // topics that do not fit are not propagated
+            if let Ok(topic) = String::try_from(topic.as_str()) {
+                let _ = interest.push(topic);
+            }
+        });
+    }
+
+    /// returns the oldest message that has not been read by the socket yet
+    /// every socket reads the queue at its own pace, a message is removed once all
+    /// subscribers have read it
+    /// once a socket read the first chunk of a streamed publish, it only gets the chunks of
+    /// that publish till the last one
+    fn next_message<'a, M: RawMutex>(
+        &mut self,
+        pool: &'a SlotPool<M, N, QUEUE_LEN, MAX_MESSAGE_SIZE>,
+        id: usize,
+    ) -> Option<Message<'a>> {
+        let receiving = self.receiving[id];
+        let msg = self.queue.iter_mut().find(|msg| {
+            msg.subscribers.get(id)
+                && (receiving.is_none() || msg.chunk.map(|chunk| chunk.stream) == receiving)
+        })?;
+        msg.subscribers.unset(id);
+        // the reference of the socket is handed over to the message
+        let message = pool.message(msg.slot, msg.id, msg.chunk);
+        if msg.subscribers.is_empty() {
+            self.remove_delivered();
+        }
+        if let Some(chunk) = message.chunk {
+            self.receiving[id] = (!chunk.last).then_some(chunk.stream);
+        }
+        Some(message)
+    }
+    /// removes the messages that have been read by everyone
+    fn remove_delivered(&mut self) {
+        for _ in 0..self.queue.len() {
+            let msg = self.queue.pop_back().unwrap();
+            if !msg.subscribers.is_empty() {
+                self.queue.push_front(msg).unwrap()
+            }
+        }
+        self.lock_wakers.iter().for_each(|w| {
+            if let Some(w) = w.as_ref() {
+                w.wake_by_ref()
+            }
+        });
+    }
+}
+
+/// the distributor shared by all sockets, use `NoopRawMutex` if all of them run on the same
+/// executor and e.g. `CriticalSectionRawMutex` if they run on different executors
+pub struct InnerDistributorMutex<
+    M: RawMutex,
+    const N: usize,
+    const SUBSCRIBER_WORDS: usize = DEFAULT_SUBSCRIBER_WORDS,
+    const QUEUE_LEN: usize = DEFAULT_QUEUE_LEN,
+    const TREE_SIZE: usize = DEFAULT_TREE_SIZE,
+    const MAX_MESSAGE_SIZE: usize = DEFAULT_MAX_MESSAGE_SIZE,
+    const MAX_TOPIC_LENGTH: usize = DEFAULT_MAX_TOPIC_LENGTH,
+    const MAX_WILL_LENGTH: usize = DEFAULT_MAX_WILL_LENGTH,
+    const KEY_LENGTH: usize = DEFAULT_KEY_LENGTH,
+> {
+    mutex: Mutex<
+        M,
+        InnerDistributor<
+            N,
+            SUBSCRIBER_WORDS,
+            QUEUE_LEN,
+            TREE_SIZE,
+            MAX_MESSAGE_SIZE,
+            MAX_TOPIC_LENGTH,
+            MAX_WILL_LENGTH,
+            KEY_LENGTH,
+        >,
+    >,
+    pool: SlotPool<M, N, QUEUE_LEN, MAX_MESSAGE_SIZE>,
+    /// traffic of every socket, counted by the codecs without locking
+    traffic: [TrafficCounters; N],
+}
+
+impl<
+        M: RawMutex,
+        const N: usize,
+        const SUBSCRIBER_WORDS: usize,
+        const QUEUE_LEN: usize,
+        const TREE_SIZE: usize,
+        const MAX_MESSAGE_SIZE: usize,
+        const MAX_TOPIC_LENGTH: usize,
+        const MAX_WILL_LENGTH: usize,
+        const KEY_LENGTH: usize,
+    >
+    InnerDistributorMutex<
+        M,
+        N,
+        SUBSCRIBER_WORDS,
+        QUEUE_LEN,
+        TREE_SIZE,
+        MAX_MESSAGE_SIZE,
+        MAX_TOPIC_LENGTH,
+        MAX_WILL_LENGTH,
+        KEY_LENGTH,
+    >
+{
+    pub fn new(
+        inner: InnerDistributor<
+            N,
+            SUBSCRIBER_WORDS,
+            QUEUE_LEN,
+            TREE_SIZE,
+            MAX_MESSAGE_SIZE,
+            MAX_TOPIC_LENGTH,
+            MAX_WILL_LENGTH,
+            KEY_LENGTH,
+        >,
+    ) -> Self {
+        Self {
+            mutex: Mutex::new(inner),
+            pool: SlotPool::new(),
+            traffic: core::array::from_fn(|_| TrafficCounters::default()),
+        }
+    }
+
+    /// snapshot of the counters of the broker
+    pub async fn stats(&self) -> Stats<N> {
+        let inner = self.lock().await;
+        inner.stats(&self.traffic, QUEUE_LEN - self.pool.free_slots())
+    }
+
+    pub async fn lock(
+        &self,
+    ) -> MutexGuard<
+        '_,
+        M,
+        InnerDistributor<
+            N,
+            SUBSCRIBER_WORDS,
+            QUEUE_LEN,
+            TREE_SIZE,
+            MAX_MESSAGE_SIZE,
+            MAX_TOPIC_LENGTH,
+            MAX_WILL_LENGTH,
+            KEY_LENGTH,
+        >,
+    > {
+        self.mutex.lock().await
+    }
+
+    pub fn try_lock(
+        &self,
+    ) -> Result<
+        MutexGuard<
+            '_,
+            M,
+            InnerDistributor<
+                N,
+                SUBSCRIBER_WORDS,
+                QUEUE_LEN,
+                TREE_SIZE,
+                MAX_MESSAGE_SIZE,
+                MAX_TOPIC_LENGTH,
+                MAX_WILL_LENGTH,
+                KEY_LENGTH,
+            >,
+        >,
+        TryLockError,
+    > {
+        self.mutex.try_lock()
+    }
+
+    /// calls `f` till it returns a value, `f` has to register the waker it gets in the inner
+    /// distributor, it is called again once the waker is woken
+    /// the mutex is not held while waiting, so other executors can make progress
+    async fn wait_for<R>(
+        &self,
+        mut f: impl FnMut(
+            &mut InnerDistributor<
+                N,
+                SUBSCRIBER_WORDS,
+                QUEUE_LEN,
+                TREE_SIZE,
+                MAX_MESSAGE_SIZE,
+                MAX_TOPIC_LENGTH,
+                MAX_WILL_LENGTH,
+                KEY_LENGTH,
+            >,
+            &Waker,
+        ) -> Option<R>,
+    ) -> R {
+        loop {
+            let waker = poll_fn(|cx| Poll::Ready(cx.waker().clone())).await;
+            if let Some(res) = f(&mut *self.lock().await, &waker) {
+                return res;
+            }
+            // a wake up that happened after unlocking makes the task get polled again
+            let mut woken = false;
+            poll_fn(|_| {
+                if woken {
+                    Poll::Ready(())
+                } else {
+                    woken = true;
+                    Poll::Pending
+                }
+            })
+            .await;
+        }
+    }
+}
+
+/// Memory budget of a broker, see [`crate::config`] for the defaults
+/// implemented by [`InnerDistributorMutex`] for its const generics, so the [`Distributor`] of a
+/// socket and the tasks serving it only need a single generic parameter
+#[allow(async_fn_in_trait)]
+pub trait BrokerConfig: sealed::Operations + Sized + 'static {
+    /// amount of sockets
+    const CONNECTIONS: usize;
+    const SUBSCRIBER_WORDS: usize;
+    const QUEUE_LEN: usize;
+    const TREE_SIZE: usize;
+    const MAX_MESSAGE_SIZE: usize;
+    const MAX_TOPIC_LENGTH: usize;
+    const MAX_WILL_LENGTH: usize;
+    /// length of the keys of the storage, at least `key_length(MAX_TOPIC_LENGTH)`
+    const KEY_LENGTH: usize;
+
+    /// buffer for a single message, `[u8; MAX_MESSAGE_SIZE]`
+    type Buffer: AsRef<[u8]> + AsMut<[u8]>;
+    /// topic of at most `MAX_TOPIC_LENGTH` bytes, `String<MAX_TOPIC_LENGTH>`
+    type Topic: Clone
+        + Default
+        + PartialEq
+        + fmt::Debug
+        + fmt::Write
+        + Deref<Target = str>
+        + AsRef<str>
+        + for<'a> TryFrom<&'a str>;
+    /// key of the storage, `Key<KEY_LENGTH>`
+    type Key: fmt::Debug + Deref<Target = str>;
+    /// snapshot of the counters, `Stats<CONNECTIONS>`
+    type Stats;
+
+    /// a zeroed message buffer
+    fn buffer() -> Self::Buffer;
+    /// snapshot of the counters of the broker
+    async fn stats(&self) -> Self::Stats;
+}
+
+mod sealed {
+    use super::*;
+
+    /// The operations of the [`Distributor`] of socket `id`, sealed so [`BrokerConfig`] is only
+    /// implemented by [`InnerDistributorMutex`]
+    #[allow(async_fn_in_trait)]
+    pub trait Operations {
+        async fn lock_publishing<T>(&self, id: usize, future: impl Future<Output = T>) -> T;
+        async fn unlock_publishing(&self, id: usize);
+        async fn publish(
+            &self,
+            id: usize,
+            topic: &str,
+            publish: &MPublish<'_>,
+        ) -> Result<(), DistributorError>;
+        async fn forward(
+            &self,
+            id: usize,
+            topic: &str,
+            publish: &MPublish<'_>,
+        ) -> Result<(), DistributorError>;
+        async fn publish_chunk(
+            &self,
+            id: usize,
+            start: Option<StreamStart<'_>>,
+            data: &[u8],
+            last: bool,
+        ) -> Result<(), DistributorError>;
+        async fn enable_streaming(&self, id: usize);
+        async fn subscribe(
+            &self,
+            id: usize,
+            subscription: &str,
+            no_local: bool,
+        ) -> Result<(), DistributorError>;
+        async fn unsubscribe(&self, id: usize, subscription: &str);
+        fn session_key(
+            mount_point: Option<&str>,
+            client_id: &str,
+        ) -> Option<<Self as BrokerConfig>::Key>
+        where
+            Self: BrokerConfig;
+        async fn restore_session(&self, id: usize, key: &str) -> bool;
+        async fn save_session(&self, id: usize, key: &str);
+        async fn remove_session(&self, key: &str);
+        async fn cleanup(&self, id: usize);
+        fn traffic(&self, id: usize) -> &TrafficCounters;
+        async fn accept_client(&self, id: usize, client_id: &str);
+        async fn reject_client(&self);
+        async fn allow_control(&self, id: usize);
+        async fn set_remote(&self, id: usize, remote: IpEndpoint);
+        async fn disconnect_reason(&self, id: usize) -> DisconnectReasonCode;
+        async fn set_peer(&self, id: usize);
+        async fn broker_id(&self) -> Option<&'static str>;
+        async fn is_duplicate(&self, origin: &str, message_id: u32) -> bool;
+        async fn dropped(&self) -> u32;
+        async fn interest_version(&self) -> u32;
+        async fn local_interest<const L: usize>(
+            &self,
+            interest: &mut Vec<<Self as BrokerConfig>::Topic, L>,
+        ) where
+            Self: BrokerConfig;
+        async fn fulfill_will(&self, id: usize);
+        async fn set_will(&self, id: usize, will: MPublish<'_>) -> Result<(), DistributorError>;
+        async fn unset_will(&self, id: usize);
+        async fn next(&'static self, id: usize) -> Result<Message<'static>, DistributorError>;
+    }
+}
+
+use sealed::Operations;
+
+impl<
+        M: RawMutex + 'static,
+        const N: usize,
+        const SUBSCRIBER_WORDS: usize,
+        const QUEUE_LEN: usize,
+        const TREE_SIZE: usize,
+        const MAX_MESSAGE_SIZE: usize,
+        const MAX_TOPIC_LENGTH: usize,
+        const MAX_WILL_LENGTH: usize,
+        const KEY_LENGTH: usize,
+    > BrokerConfig
+    for InnerDistributorMutex<
+        M,
+        N,
+        SUBSCRIBER_WORDS,
+        QUEUE_LEN,
+        TREE_SIZE,
+        MAX_MESSAGE_SIZE,
+        MAX_TOPIC_LENGTH,
+        MAX_WILL_LENGTH,
+        KEY_LENGTH,
+    >
+{
+    const CONNECTIONS: usize = N;
+    const SUBSCRIBER_WORDS: usize = SUBSCRIBER_WORDS;
+    const QUEUE_LEN: usize = QUEUE_LEN;
+    const TREE_SIZE: usize = TREE_SIZE;
+    const MAX_MESSAGE_SIZE: usize = MAX_MESSAGE_SIZE;
+    const MAX_TOPIC_LENGTH: usize = MAX_TOPIC_LENGTH;
+    const MAX_WILL_LENGTH: usize = MAX_WILL_LENGTH;
+    const KEY_LENGTH: usize = KEY_LENGTH;
+
+    type Buffer = [u8; MAX_MESSAGE_SIZE];
+    type Topic = String<MAX_TOPIC_LENGTH>;
+    type Key = Key<KEY_LENGTH>;
+    type Stats = Stats<N>;
+
+    fn buffer() -> Self::Buffer {
+        [0; MAX_MESSAGE_SIZE]
+    }
+
+    async fn stats(&self) -> Stats<N> {
+        InnerDistributorMutex::stats(self).await
+    }
+}
+
+impl<
+        M: RawMutex + 'static,
+        const N: usize,
+        const SUBSCRIBER_WORDS: usize,
+        const QUEUE_LEN: usize,
+        const TREE_SIZE: usize,
+        const MAX_MESSAGE_SIZE: usize,
+        const MAX_TOPIC_LENGTH: usize,
+        const MAX_WILL_LENGTH: usize,
+        const KEY_LENGTH: usize,
+    > Operations
+    for InnerDistributorMutex<
+        M,
+        N,
+        SUBSCRIBER_WORDS,
+        QUEUE_LEN,
+        TREE_SIZE,
+        MAX_MESSAGE_SIZE,
+        MAX_TOPIC_LENGTH,
+        MAX_WILL_LENGTH,
+        KEY_LENGTH,
+    >
+{
+    async fn lock_publishing<T>(&self, id: usize, future: impl Future<Output = T>) -> T {
+        let res = future.await;
+
+        // delay till there is enough space
+        self.wait_for(|inner, waker| {
+            if inner.has_space_for(self.pool.free_slots(), id) {
+                inner.lock_wakers[id] = None;
+                inner.lock_for_publishing(id).unwrap();
+                Some(())
+            } else {
+                inner.lock_wakers[id] = Some(waker.clone());
+                // slots are freed by sockets that are done sending a message
+                self.pool.register(waker);
+                None
+            }
+        })
+        .await;
+
+        res
+    }
+
+    async fn unlock_publishing(&self, id: usize) {
+        self.lock().await.unlock_for_publishing(id);
+    }
+
+    async fn publish(
+        &self,
+        id: usize,
+        topic: &str,
+        publish: &MPublish<'_>,
+    ) -> Result<(), DistributorError> {
+        let mut inner = self.lock().await;
+        if let Some(action) = control::action(topic) {
+            inner.control(&self.pool, &self.traffic, id, action, publish);
+            return Ok(());
+        }
+        // the publisher only gets the message back if one of its subscriptions allows it
+        let skip = inner.tree.no_local(topic, id).then_some(id);
+        inner.publish(&self.pool, topic, publish, skip)
+    }
+
+    async fn forward(
+        &self,
+        id: usize,
+        topic: &str,
+        publish: &MPublish<'_>,
+    ) -> Result<(), DistributorError> {
+        self.lock()
+            .await
+            .publish(&self.pool, topic, publish, Some(id))
+    }
+
+    async fn publish_chunk(
+        &self,
+        id: usize,
+        start: Option<StreamStart<'_>>,
+        data: &[u8],
+        last: bool,
+    ) -> Result<(), DistributorError> {
+        self.lock()
+            .await
+            .publish_chunk(&self.pool, id, start, data, last)
+    }
+
+    async fn enable_streaming(&self, id: usize) {
+        self.lock().await.streaming.set(id);
+    }
+
+    async fn subscribe(
+        &self,
+        id: usize,
+        subscription: &str,
+        no_local: bool,
+    ) -> Result<(), DistributorError> {
+        let mut inner = self.lock().await;
+        inner.subscribe(subscription, id)?;
+        if no_local {
+            inner.tree.set_no_local(subscription, id);
+        }
+        inner.send_retained(&self.pool, subscription, id);
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, id: usize, subscription: &str) {
+        self.lock().await.unsubscribe(subscription, id);
+    }
+
+    fn session_key(mount_point: Option<&str>, client_id: &str) -> Option<Key<KEY_LENGTH>> {
+        session_key(mount_point, client_id)
+    }
+
+    async fn restore_session(&self, id: usize, key: &str) -> bool {
+        self.lock().await.restore_session(key, id)
+    }
+
+    async fn save_session(&self, id: usize, key: &str) {
+        self.lock().await.save_session(key, id)
+    }
+
+    async fn remove_session(&self, key: &str) {
+        self.lock().await.remove_session(key)
+    }
+
+    async fn cleanup(&self, id: usize) {
+        let mut inner = self.lock().await;
+        inner.leave_streams(&self.pool, id);
+        inner.streaming.unset(id);
+        inner.park_session(&self.pool, id);
+        inner.connection_closed(id, &self.traffic[id]);
+        inner.unsubscribe_all_topics(&self.pool, id);
+        inner.unlock_for_publishing(id);
+        inner.peers.unset(id);
+        inner.overflowed.unset(id);
+        inner.control_allowed.unset(id);
+    }
+
+    fn traffic(&self, id: usize) -> &TrafficCounters {
+        &self.traffic[id]
+    }
+
+    async fn accept_client(&self, id: usize, client_id: &str) {
+        self.lock().await.connection_accepted(id, client_id);
+    }
+
+    async fn reject_client(&self) {
+        self.lock().await.connection_rejected();
+    }
+
+    async fn allow_control(&self, id: usize) {
+        self.lock().await.control_allowed.set(id);
+    }
+
+    async fn set_remote(&self, id: usize, remote: IpEndpoint) {
+        self.lock().await.remotes[id] = Some(remote);
+    }
+
+    async fn disconnect_reason(&self, id: usize) -> DisconnectReasonCode {
+        self.lock().await.disconnects[id]
+            .take()
+            .unwrap_or(DisconnectReasonCode::AdministrativeAction)
+    }
+
+    async fn set_peer(&self, id: usize) {
+        self.lock().await.peers.set(id);
+    }
+
+    async fn broker_id(&self) -> Option<&'static str> {
+        self.lock().await.broker_id
+    }
+
+    async fn is_duplicate(&self, origin: &str, message_id: u32) -> bool {
+        self.lock().await.duplicates.check(origin, message_id)
+    }
+
+    async fn dropped(&self) -> u32 {
+        self.lock().await.dropped
+    }
+
+    async fn interest_version(&self) -> u32 {
+        self.lock().await.interest_version
+    }
+
+    async fn local_interest<const L: usize>(
+        &self,
+        interest: &mut Vec<<Self as BrokerConfig>::Topic, L>,
+    ) where
+        Self: BrokerConfig,
+    {
+        self.lock().await.local_interest(interest)
+    }
+
+    async fn fulfill_will(&self, id: usize) {
+        let will = self.lock().await.wills[id].take();
+        if let Some(will) = will {
+            let packet = MqttPacket::parse_complete(will.get_written_data()).unwrap();
+            let packet = match packet {
+                MqttPacket::Publish(ref publish) => publish,
+                _ => unreachable!(),
+            };
+            // wait till there is time to publish message
+            self.lock_publishing(id, async {}).await;
+            let _ = Operations::publish(self, id, packet.topic_name, packet).await;
+            self.unlock_publishing(id).await;
+        }
+    }
+
+    async fn set_will(&self, id: usize, will: MPublish<'_>) -> Result<(), DistributorError> {
+        let mut writer = PacketWriter::default();
+        MqttPacket::Publish(will)
+            .write(&mut writer)
+            .map_err(|_| DistributorError::MessageTooLong)?;
+        self.lock().await.wills[id] = Some(writer);
+        Ok(())
+    }
+
+    async fn unset_will(&self, id: usize) {
+        self.lock().await.wills[id] = None;
+    }
+
+    async fn next(&'static self, id: usize) -> Result<Message<'static>, DistributorError> {
+        let pool = &self.pool;
+        self.wait_for(|inner, waker| {
+            if inner.disconnects[id].is_some() {
+                inner.wakers[id] = None;
+                return Some(Err(DistributorError::Disconnected));
+            }
+            if inner.overflowed.get(id) {
+                // the socket receives messages again once it has been told about the overflow
+                inner.overflowed.unset(id);
+                inner.wakers[id] = None;
+                return Some(Err(DistributorError::QueueFull));
+            }
+            // messages queued while the session was offline are older than the ones in the queue,
+            // but they do not interrupt a streamed publish
+            if inner.queued.get(id) && inner.receiving[id].is_none() {
+                match inner.next_queued(pool, id) {
+                    Ok(Some(message)) => {
+                        inner.wakers[id] = None;
+                        return Some(Ok(message));
+                    }
+                    Ok(None) => {}
+                    Err(()) => {
+                        inner.wakers[id] = Some(waker.clone());
+                        pool.register(waker);
+                        return None;
+                    }
+                }
+            }
+            match inner.next_message(pool, id) {
+                Some(message) => {
+                    inner.wakers[id] = None;
+                    Some(Ok(message))
+                }
+                // all messages ment for this subscriber have been read
+                None => {
+                    inner.wakers[id] = Some(waker.clone());
+                    None
+                }
+            }
+        })
+        .await
+    }
+}
+
+/// The distributor as seen by a single socket
+pub struct Distributor<B: BrokerConfig> {
+    id: usize,
+    inner: &'static B,
+}
+
+impl<B: BrokerConfig> Distributor<B> {
+    pub fn new(inner: &'static B, id: usize) -> Self {
+        Self { id, inner }
+    }
+    /// gets the socket id
+    pub fn get_id(&self) -> usize {
+        self.id
+    }
+
+    /// use this function so the server only processes n MQTT messages at a time
+    /// were: n = QUEUE_LEN
+    /// it should be used as follows
+    /// ```no_run
+    /// loop {
+    ///     distributor.unlock().await;
+    ///     let msg = match select(distributor.next(), distributor.lock(parser.next())).await {
+    ///         ...
+    ///     }
+    /// }
+    /// ```
+    pub async fn lock<T>(&self, feature: impl Future<Output = T>) -> T {
+        self.inner.lock_publishing(self.id, feature).await
+    }
+    /// unlocks previously locked with `lock` function
+    pub async fn unlock(&self) {
+        self.inner.unlock_publishing(self.id).await
+    }
+
+    /// Publishes a message to all subscribers of a topic
+    /// `$CONTROL` topics are handled by the broker instead, see [`crate::control`]
+    pub async fn publish(
+        &self,
+        topic: &str,
+        publish: &MPublish<'_>,
+    ) -> Result<(), DistributorError> {
+        self.inner.publish(self.id, topic, publish).await
+    }
+
+    /// Publishes a message to all subscribers of a topic except this socket
+    /// used by bridges so forwarded messages are not sent back to where they came from
+    pub async fn forward(
+        &self,
+        topic: &str,
+        publish: &MPublish<'_>,
+    ) -> Result<(), DistributorError> {
+        self.inner.forward(self.id, topic, publish).await
+    }
+
+    /// Publishes a chunk of a message that is too large for the queue to all subscribers of its
+    /// topic that enabled streaming, the first chunk comes with the `start` of the message
+    /// the chunks have to be published one after another, a new start aborts an unfinished
+    /// message
+    pub async fn publish_chunk(
+        &self,
+        start: Option<StreamStart<'_>>,
+        data: &[u8],
+        last: bool,
+    ) -> Result<(), DistributorError> {
+        self.inner.publish_chunk(self.id, start, data, last).await
+    }
+
+    /// Makes this socket receive streamed messages, it has to write their chunks one after
+    /// another, see [`Message::chunk`]
+    pub async fn enable_streaming(&self) {
+        self.inner.enable_streaming(self.id).await
+    }
+
+    /// Subscribes to a topic, retained messages of matching topics are queued for this socket
+    pub async fn subscribe(&self, subscription: &str) -> Result<(), DistributorError> {
+        self.inner.subscribe(self.id, subscription, false).await
+    }
+
+    /// Subscribes to a topic like [`Self::subscribe`], but the messages published by this socket
+    /// itself are not sent back to it, like the No Local option of MQTT
+    /// the option is not part of persistent sessions, restored subscriptions get every message
+    pub async fn subscribe_no_local(&self, subscription: &str) -> Result<(), DistributorError> {
+        self.inner.subscribe(self.id, subscription, true).await
+    }
+
+    /// key of the persistent session of `client_id`, see [`crate::storage`]
+    /// `None` if the mount point and the client id are too long for the keys of the broker
+    pub fn session_key(&self, mount_point: Option<&str>, client_id: &str) -> Option<B::Key> {
+        B::session_key(mount_point, client_id)
+    }
+
+    /// subscribes to the topics of the persistent session `key`, see [`crate::storage`]
+    /// returns false if there is no such session
+    pub async fn restore_session(&self, key: &str) -> bool {
+        self.inner.restore_session(self.id, key).await
+    }
+
+    /// saves the subscriptions of this socket as persistent session `key`
+    pub async fn save_session(&self, key: &str) {
+        self.inner.save_session(self.id, key).await
+    }
+
+    /// removes the persistent session `key` and the messages queued for it
+    pub async fn remove_session(&self, key: &str) {
+        self.inner.remove_session(key).await
+    }
+
+    /// should always be called when socket connection is closed.
+    /// cleans up all previous subscriptions and unlocks distributor for new messages to be received
+    pub async fn cleanup(&self) {
+        self.inner.cleanup(self.id).await
+    }
+
+    /// counters the codecs of this socket update
+    pub(crate) fn traffic(&self) -> &'static TrafficCounters {
+        self.inner.traffic(self.id)
+    }
+
+    /// registers the client that completed the handshake on this socket
+    pub async fn accept_client(&self, client_id: &str) {
+        self.inner.accept_client(self.id, client_id).await
+    }
+
+    /// counts a client of this socket that has been refused
+    pub async fn reject_client(&self) {
+        self.inner.reject_client().await
+    }
+
+    /// allows the client of this socket to use the `$CONTROL` topics
+    pub async fn allow_control(&self) {
+        self.inner.allow_control(self.id).await
+    }
+
+    /// address of the peer connected to this socket, listed with its client
+    pub async fn set_remote(&self, remote: IpEndpoint) {
+        self.inner.set_remote(self.id, remote).await
+    }
+
+    /// reason to send after `next` failed with `Disconnected`
+    pub async fn disconnect_reason(&self) -> DisconnectReasonCode {
+        self.inner.disconnect_reason(self.id).await
+    }
+
+    /// marks this socket as connection of a federated broker
+    pub async fn set_peer(&self) {
+        self.inner.set_peer(self.id).await
+    }
+
+    /// id of this broker if it is part of a federation
+    pub async fn broker_id(&self) -> Option<&'static str> {
+        self.inner.broker_id().await
+    }
+
+    /// true if the message `message_id` of broker `origin` has been imported over any link
+    /// before, otherwise it is remembered
+    pub async fn is_duplicate(&self, origin: &str, message_id: u32) -> bool {
+        self.inner.is_duplicate(origin, message_id).await
+    }
+
+    /// amount of messages that could not be delivered to all of their subscribers
+    pub async fn dropped(&self) -> u32 {
+        self.inner.dropped().await
+    }
+
+    /// changes whenever any socket subscribes or unsubscribes
+    pub async fn interest_version(&self) -> u32 {
+        self.inner.interest_version().await
+    }
+
+    /// collects the topic filters of all sockets that are not federated brokers
+    pub async fn local_interest<const L: usize>(&self, interest: &mut Vec<B::Topic, L>) {
+        self.inner.local_interest(interest).await
+    }
+
+    /// fulfill will and publish will message to defined topic
+    pub async fn fulfill_will(&self) {
+        self.inner.fulfill_will(self.id).await
+    }
+
+    pub async fn set_will(&self, will: MPublish<'_>) -> Result<(), DistributorError> {
+        self.inner.set_will(self.id, will).await
+    }
+
+    pub async fn unset_will(&self) {
+        self.inner.unset_will(self.id).await
+    }
+
+    pub async fn unsubscribe(&self, subscription: &str) {
+        self.inner.unsubscribe(self.id, subscription).await
+    }
+
+    /// waits for the next message for this socket
+    /// fails with `QueueFull` if the socket has been too slow and missed messages, see
+    /// `OverflowPolicy::Disconnect`, and with `Disconnected` if the client should be
+    /// disconnected, see `InnerDistributor::disconnect`
+    pub async fn next(&self) -> Result<Message<'static>, DistributorError> {
+        self.inner.next(self.id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::publish_start;
+    use crate::config::subscriber_words;
+    use crate::storage::RamStorage;
+    use core::num::NonZeroU16;
+    use embassy_futures::join::join;
+    use embassy_futures::{block_on, yield_now};
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use mqtt_format::v5::packets::publish::PublishProperties;
+    use mqtt_format::v5::qos::QualityOfService;
+    use mqtt_format::v5::variable_header::PacketIdentifier;
+    use static_cell::make_static;
+
+    /// publish on `/a`, with a packet identifier unless it is QoS 0
+    fn publish(payload: &[u8], qos: QualityOfService) -> MPublish<'_> {
+        let packet_identifier = match qos {
+            QualityOfService::AtMostOnce => None,
+            _ => Some(PacketIdentifier(NonZeroU16::new(1).unwrap())),
+        };
+        MPublish {
+            duplicate: false,
+            quality_of_service: qos,
+            retain: false,
+            topic_name: "/a",
+            packet_identifier,
+            properties: PublishProperties::new(),
+            payload,
+        }
+    }
+
+    #[test]
+    fn test_cleanup() {
+        let inner = &*make_static!(InnerDistributorMutex::<NoopRawMutex, 10>::new(
+            InnerDistributor::default()
+        ));
+        let dist0 = Distributor::new(inner, 0);
+        let dist1 = Distributor::new(inner, 1);
+        block_on(dist0.subscribe("/a/b/c")).unwrap();
+        block_on(dist0.subscribe("/a/b/d")).unwrap();
+        block_on(dist0.subscribe("/a/b/e")).unwrap();
+        block_on(dist0.subscribe("/a/b/f")).unwrap();
+        block_on(dist1.subscribe("/a")).unwrap();
+        let publish = publish(&[], QualityOfService::AtMostOnce);
+        block_on(dist0.publish("/a", &publish)).unwrap();
+        assert_eq!(dist0.inner.try_lock().unwrap().queue.len(), 1);
+        block_on(dist0.cleanup());
+        assert_eq!(dist0.inner.try_lock().unwrap().queue.len(), 1);
+        block_on(dist1.cleanup());
+        assert_eq!(dist0.inner.try_lock().unwrap().queue.len(), 0);
+    }
+
+    #[test]
+    fn test_many_connections() {
+        const N: usize = 100;
+        let inner = &*make_static!(InnerDistributorMutex::<
+            NoopRawMutex,
+            N,
+            { subscriber_words(N) },
+        >::new(InnerDistributor::default()));
+        let dist0 = Distributor::new(inner, 0);
+        let dist99 = Distributor::new(inner, 99);
+        block_on(dist99.subscribe("/a")).unwrap();
+        let publish = publish(&[], QualityOfService::AtMostOnce);
+        block_on(dist0.publish("/a", &publish)).unwrap();
+        let inner = dist0.inner.try_lock().unwrap();
+        let subscribers = &inner.queue.back().unwrap().subscribers;
+        assert_eq!(
+            subscribers.iter_ones().collect::<Vec<_, 4>>().as_slice(),
+            [99]
+        );
+        assert_eq!(subscribers.count_ones(), 1);
+    }
+
+    #[test]
+    fn test_contended_lock() {
+        let inner = &*make_static!(InnerDistributorMutex::<NoopRawMutex, 2>::new(
+            InnerDistributor::default()
+        ));
+        let dist0 = Distributor::new(inner, 0);
+        let holder = async {
+            let _inner = inner.lock().await;
+            yield_now().await;
+        };
+        // waits for the holder instead of panicking
+        let (_, subscribed) = block_on(join(holder, dist0.subscribe("/a")));
+        subscribed.unwrap();
+        assert_eq!(
+            dist0
+                .inner
+                .try_lock()
+                .unwrap()
+                .tree
+                .get_subscribed("/a")
+                .count_ones(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_slow_subscriber() {
+        let pool = SlotPool::<NoopRawMutex, 3, 4, DEFAULT_MAX_MESSAGE_SIZE>::new();
+        let mut inner = InnerDistributor::<3, 1, 4>::default();
+        inner.subscribe("/a", 1).unwrap();
+        inner.subscribe("/a", 2).unwrap();
+        let publish = publish(&[], QualityOfService::AtMostOnce);
+        for _ in 0..3 {
+            inner.publish(&pool, "/a", &publish, None).unwrap();
+        }
+        // the fast subscriber reads all messages while the slow one has not read any
+        let ids = core::iter::from_fn(|| inner.next_message(&pool, 1).map(|m| m.id()));
+        assert_eq!(ids.collect::<Vec<_, 4>>().as_slice(), [1, 2, 3]);
+        assert_eq!(inner.queue.len(), 3);
+        assert_eq!(inner.next_message(&pool, 2).unwrap().id(), 1);
+        assert_eq!(inner.queue.len(), 2);
+        inner.publish(&pool, "/a", &publish, None).unwrap();
+        assert_eq!(inner.next_message(&pool, 1).unwrap().id(), 4);
+        assert!(inner.next_message(&pool, 1).is_none());
+    }
+
+    #[test]
+    fn test_overflow_policy() {
+        let publish = publish(&[], QualityOfService::AtMostOnce);
+        type Pool = SlotPool<NoopRawMutex, 3, 2, DEFAULT_MAX_MESSAGE_SIZE>;
+        let overflow = |pool: &Pool, policy| {
+            let mut inner = InnerDistributor::<3, 1, 2>::default().with_overflow_policy(policy);
+            inner.subscribe("/a", 1).unwrap();
+            inner.subscribe("/a", 2).unwrap();
+            for _ in 0..2 {
+                inner.publish(pool, "/a", &publish, None).unwrap();
+            }
+            // socket 1 is fast, socket 2 has not read anything
+            inner.next_message(pool, 1).unwrap();
+            inner.next_message(pool, 1).unwrap();
+            inner.publish(pool, "/a", &publish, None).unwrap();
+            inner
+        };
+        let ids = |pool: &Pool, inner: &mut InnerDistributor<3, 1, 2>, id| {
+            core::iter::from_fn(|| inner.next_message(pool, id).map(|m| m.id()))
+                .collect::<Vec<_, 4>>()
+        };
+
+        let pool = Pool::new();
+        let mut inner = overflow(&pool, OverflowPolicy::DropNewest);
+        assert_eq!(inner.dropped, 1);
+        assert_eq!(inner.queue.len(), 2);
+        assert!(ids(&pool, &mut inner, 1).is_empty());
+        assert_eq!(ids(&pool, &mut inner, 2).as_slice(), [1, 2]);
+        assert_eq!(pool.free_slots(), 2);
+
+        let pool = Pool::new();
+        let mut inner = overflow(&pool, OverflowPolicy::DropOldest);
+        assert_eq!(inner.dropped, 1);
+        assert_eq!(inner.queue.len(), 2);
+        assert_eq!(ids(&pool, &mut inner, 1).as_slice(), [3]);
+        assert_eq!(ids(&pool, &mut inner, 2).as_slice(), [2, 3]);
+
+        // the slot of a message that is still being sent is not freed by dropping it
+        let pool = Pool::new();
+        let mut inner =
+            InnerDistributor::<3, 1, 2>::default().with_overflow_policy(OverflowPolicy::DropOldest);
+        inner.subscribe("/a", 1).unwrap();
+        inner.subscribe("/a", 2).unwrap();
+        for _ in 0..2 {
+            inner.publish(&pool, "/a", &publish, None).unwrap();
+        }
+        let sending = inner.next_message(&pool, 1).unwrap();
+        inner.publish(&pool, "/a", &publish, None).unwrap();
+        // the oldest and the new message are dropped, the second one is kept
+        assert_eq!(inner.dropped, 2);
+        assert_eq!(inner.queue.len(), 1);
+        assert_eq!(ids(&pool, &mut inner, 2).as_slice(), [2]);
+        drop(sending);
+        assert_eq!(ids(&pool, &mut inner, 1).as_slice(), [2]);
+        assert_eq!(pool.free_slots(), 2);
+
+        let pool = Pool::new();
+        let mut inner = overflow(&pool, OverflowPolicy::Disconnect);
+        assert_eq!(inner.dropped, 1);
+        assert_eq!(inner.queue.len(), 1);
+        assert!(inner.overflowed.get(2));
+        assert_eq!(ids(&pool, &mut inner, 1).as_slice(), [3]);
+        assert!(ids(&pool, &mut inner, 2).is_empty());
+
+        // a fast subscriber that is still sending the oldest message is not disconnected
+        let pool = Pool::new();
+        let mut inner =
+            InnerDistributor::<3, 1, 2>::default().with_overflow_policy(OverflowPolicy::Disconnect);
+        inner.subscribe("/a", 1).unwrap();
+        inner.subscribe("/a", 2).unwrap();
+        for _ in 0..2 {
+            inner.publish(&pool, "/a", &publish, None).unwrap();
+        }
+        let sending = inner.next_message(&pool, 1).unwrap();
+        inner.publish(&pool, "/a", &publish, None).unwrap();
+        // the slow subscriber misses the oldest message and the new one is dropped
+        assert_eq!(inner.dropped, 2);
+        assert!(inner.overflowed.get(2));
+        assert!(!inner.overflowed.get(1));
+        drop(sending);
+        assert_eq!(ids(&pool, &mut inner, 1).as_slice(), [2]);
+        assert_eq!(pool.free_slots(), 2);
+    }
+
+    #[test]
+    fn test_shared_slot() {
+        let pool = SlotPool::<NoopRawMutex, 3, 1, DEFAULT_MAX_MESSAGE_SIZE>::new();
+        let mut inner = InnerDistributor::<3, 1, 1>::default();
+        inner.subscribe("/a", 1).unwrap();
+        inner.subscribe("/a", 2).unwrap();
+        let publish = publish(b"payload", QualityOfService::AtLeastOnce);
+        inner.publish(&pool, "/a", &publish, None).unwrap();
+        let first = inner.next_message(&pool, 1).unwrap();
+        let second = inner.next_message(&pool, 2).unwrap();
+        // both subscribers read the same buffer, which is sent as QoS 0
+        assert_eq!(first.message().as_ptr(), second.message().as_ptr());
+        match MqttPacket::parse_complete(first.message()) {
+            Ok(MqttPacket::Publish(publish)) => {
+                assert!(matches!(
+                    publish.quality_of_service,
+                    QualityOfService::AtMostOnce
+                ));
+                assert!(publish.packet_identifier.is_none());
+                assert_eq!(publish.payload, b"payload");
+            }
+            _ => panic!("expected publish"),
+        }
+        assert!(inner.queue.is_empty());
+        // the slot is freed once the last subscriber is done sending
+        drop(first);
+        assert_eq!(pool.free_slots(), 0);
+        drop(second);
+        assert_eq!(pool.free_slots(), 1);
+    }
+
+    #[test]
+    fn test_streams() {
+        let pool = SlotPool::<NoopRawMutex, 3, 2, DEFAULT_MAX_MESSAGE_SIZE>::new();
+        let mut inner = InnerDistributor::<3, 1, 2>::default();
+        inner.subscribe("/a", 1).unwrap();
+        inner.subscribe("/a", 2).unwrap();
+        inner.streaming.set(1);
+        let start = StreamStart {
+            topic: "/a",
+            retain: false,
+            len: 6,
+        };
+        inner
+            .publish_chunk(&pool, 0, Some(start), &[0, 1, 2], false)
+            .unwrap();
+        // socket 2 can not send chunks and misses the publish
+        assert_eq!(inner.dropped, 1);
+        let message = inner.next_message(&pool, 1).unwrap();
+        let chunk = message.chunk().unwrap();
+        assert!(chunk.first && !chunk.last);
+        let header = publish_start(message.message()).unwrap();
+        assert_eq!(header.topic, "/a");
+        assert_eq!(header.len, 6);
+        assert_eq!(header.rest, [0, 1, 2]);
+        drop(message);
+
+        // the slot of the next chunk is reserved for the publisher
+        let publish = publish(&[], QualityOfService::AtMostOnce);
+        assert!(inner.has_space_for(pool.free_slots(), 2));
+        inner.publish(&pool, "/a", &publish, None).unwrap();
+        assert!(!inner.has_space_for(pool.free_slots(), 2));
+        assert!(inner.has_space_for(pool.free_slots(), 0));
+
+        // the message published in between is read after the last chunk
+        inner
+            .publish_chunk(&pool, 0, None, &[3, 4, 5], true)
+            .unwrap();
+        let message = inner.next_message(&pool, 1).unwrap();
+        assert_eq!(message.chunk().map(|chunk| chunk.last), Some(true));
+        assert_eq!(message.message(), [3, 4, 5]);
+        drop(message);
+        assert!(inner.next_message(&pool, 1).unwrap().chunk().is_none());
+        assert!(inner.streams[0].is_none());
+
+        // a publisher that disconnects in the middle of a publish disconnects its subscribers
+        inner
+            .publish_chunk(&pool, 0, Some(start), &[0, 1, 2], false)
+            .unwrap();
+        drop(inner.next_message(&pool, 1).unwrap());
+        inner.publish_chunk(&pool, 0, None, &[3], false).unwrap();
+        inner.leave_streams(&pool, 0);
+        assert!(inner.overflowed.get(1));
+        assert!(inner.queue.iter().all(|msg| msg.chunk.is_none()));
+        assert_eq!(pool.free_slots(), 1);
+    }
+
+    #[test]
+    fn test_retained() {
+        let storage = &*make_static!(RamStorage::<NoopRawMutex, 4>::new());
+        let pool = SlotPool::<NoopRawMutex, 2, 4, DEFAULT_MAX_MESSAGE_SIZE>::new();
+        let mut inner = InnerDistributor::<2, 1, 4>::default().with_storage(storage);
+        let mut publish = MPublish {
+            retain: true,
+            topic_name: "/a/b",
+            ..publish(b"retained", QualityOfService::AtMostOnce)
+        };
+        inner.publish(&pool, "/a/b", &publish, None).unwrap();
+        assert!(inner.queue.is_empty());
+
+        inner.subscribe("/a/+", 1).unwrap();
+        inner.send_retained(&pool, "/a/+", 1);
+        let message = inner.next_message(&pool, 1).unwrap();
+        match MqttPacket::parse_complete(message.message()) {
+            Ok(MqttPacket::Publish(publish)) => {
+                assert!(publish.retain);
+                assert_eq!(publish.topic_name, "/a/b");
+                assert_eq!(publish.payload, b"retained");
+            }
+            _ => panic!("expected publish"),
+        }
+        drop(message);
+
+        // sessions keep the subscriptions of a socket
+        inner.save_session("sclient", 1);
+        inner.unsubscribe_all_topics(&pool, 1);
+        assert!(inner.restore_session("sclient", 0));
+        assert!(inner.tree.get_subscribed("/a/c").get(0));
+        assert!(!inner.restore_session("sother", 0));
+
+        // an empty payload removes the retained message
+        publish.payload = &[];
+        inner.publish(&pool, "/a/b", &publish, None).unwrap();
+        assert!(!storage.get("r/a/b", &mut |_| {}));
+    }
+
+    #[test]
+    fn test_clear_retained() {
+        let storage = &*make_static!(RamStorage::<NoopRawMutex, 4>::new());
+        let pool = SlotPool::<NoopRawMutex, 2, 4, DEFAULT_MAX_MESSAGE_SIZE>::new();
+        let mut inner = InnerDistributor::<2, 1, 4>::default().with_storage(storage);
+        for topic in ["/a/b", "/a/c", "/b"] {
+            let publish = MPublish {
+                retain: true,
+                topic_name: topic,
+                ..publish(b"retained", QualityOfService::AtMostOnce)
+            };
+            inner.publish(&pool, topic, &publish, None).unwrap();
+        }
+        assert_eq!(inner.clear_retained("/a/+"), 2);
+        assert!(!storage.get("r/a/b", &mut |_| {}));
+        assert!(storage.get("r/b", &mut |_| {}));
+        assert_eq!(inner.clear_retained("#"), 1);
+        assert_eq!(inner.clear_retained("#"), 0);
+    }
+
+    #[test]
+    fn test_long_topics() {
+        const MAX_TOPIC_LENGTH: usize = 128;
+        const KEY_LENGTH: usize = key_length(MAX_TOPIC_LENGTH);
+        let storage = &*make_static!(RamStorage::<NoopRawMutex, 4, 256, KEY_LENGTH>::new());
+        let pool = SlotPool::<NoopRawMutex, 2, 4, 256>::new();
+        let mut inner =
+            InnerDistributor::<2, 1, 4, 16, 256, MAX_TOPIC_LENGTH, 128, KEY_LENGTH>::default()
+                .with_storage(storage);
+        let mut topic = String::<MAX_TOPIC_LENGTH>::new();
+        while topic.push('a').is_ok() {}
+        let publish = MPublish {
+            retain: true,
+            topic_name: &topic,
+            ..publish(b"retained", QualityOfService::AtMostOnce)
+        };
+        inner.publish(&pool, &topic, &publish, None).unwrap();
+        assert_eq!(storage.len(), 1);
+
+        // the session of the longest mount point fits as well
+        inner.subscribe(&topic, 1).unwrap();
+        let key = session_key::<KEY_LENGTH>(Some(&topic), "client").unwrap();
+        inner.save_session(&key, 1);
+        assert_eq!(inner.sessions[1].as_deref(), Some(&*key));
+        assert_eq!(storage.len(), 2);
+    }
+
+    #[test]
+    fn test_offline_queue() {
+        let storage = &*make_static!(RamStorage::<NoopRawMutex, 8>::new());
+        let pool = SlotPool::<NoopRawMutex, 2, 4, DEFAULT_MAX_MESSAGE_SIZE>::new();
+        let mut inner = InnerDistributor::<2, 1, 4>::default()
+            .with_storage(storage)
+            .with_offline_queue_len(2);
+        // a session that does not exist is not taken over by the socket
+        assert!(!inner.restore_session("sclient", 1));
+        assert!(inner.sessions[1].is_none());
+        assert!(!inner.queued.get(1));
+        inner.subscribe("/a", 1).unwrap();
+        inner.save_session("sclient", 1);
+        let qos1 = QualityOfService::AtLeastOnce;
+        // the unread message is kept when the client disconnects
+        inner
+            .publish(&pool, "/a", &publish(b"1", qos1), None)
+            .unwrap();
+        inner.park_session(&pool, 1);
+        inner.unsubscribe_all_topics(&pool, 1);
+        assert_eq!(pool.free_slots(), 4);
+
+        inner
+            .publish(&pool, "/a", &publish(b"2", qos1), None)
+            .unwrap();
+        // QoS 0 messages are not queued and the queue of the session is full
+        let qos0 = QualityOfService::AtMostOnce;
+        inner
+            .publish(&pool, "/a", &publish(b"3", qos0), None)
+            .unwrap();
+        inner
+            .publish(&pool, "/a", &publish(b"4", qos1), None)
+            .unwrap();
+        assert_eq!(inner.dropped, 1);
+
+        let expect = |message: Message<'_>, payload: &[u8]| {
+            let packet = MqttPacket::parse_complete(message.message());
+            assert!(matches!(packet, Ok(MqttPacket::Publish(p)) if p.payload == payload));
+        };
+        // the client reconnects on another socket, the message stays stored until it is written
+        assert!(inner.restore_session("sclient", 0));
+        expect(inner.next_queued(&pool, 0).unwrap().unwrap(), b"1");
+        assert_eq!(storage.len(), 3);
+        inner.park_session(&pool, 0);
+
+        // it is sent again after the connection got lost, then the others in order
+        assert!(inner.restore_session("sclient", 1));
+        for payload in [b"1", b"2"] {
+            expect(inner.next_queued(&pool, 1).unwrap().unwrap(), payload);
+        }
+        assert!(inner.next_queued(&pool, 1).unwrap().is_none());
+        assert!(!inner.queued.get(1));
+        assert!(inner.offline_queues.is_empty());
+        assert_eq!(storage.len(), 1);
     }
 }
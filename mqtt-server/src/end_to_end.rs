@@ -0,0 +1,737 @@
+//! End-to-end tests of the connection handler
+//!
+//! Every client is connected over an in-memory pipe to its own [`serve_connection`] task, all
+//! tasks run on a single threaded tokio executor. Run with `cargo test --features std` from the
+//! `mqtt-server` directory.
+//! The bridge connects to a second broker over a TCP socket on localhost.
+use core::future::Future;
+use std::rc::Rc;
+
+use embassy_net::{IpAddress, IpEndpoint};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_io_adapters::tokio_1::FromTokio;
+use mqtt_format::v5::packets::connack::ConnackReasonCode;
+use mqtt_format::v5::packets::disconnect::DisconnectReasonCode;
+use mqtt_format::v5::packets::publish::{MPublish, PublishProperties};
+use mqtt_format::v5::packets::MqttPacket;
+use mqtt_format::v5::qos::QualityOfService;
+use mqtt_format::v5::write::{WResult, WriteMqttPacket};
+use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+use tokio::net::TcpListener;
+use tokio::task::{JoinHandle, LocalSet};
+
+use crate::bridge::{BridgeConfig, BridgeDirection, BridgeTopic};
+use crate::codec::{
+    write_string, write_subscriptions, write_variable_u32, Frame, MqttCodecDecoder,
+    MqttCodecEncoder, PacketWriter,
+};
+use crate::config::InnerDistributorMutex;
+use crate::distributor::{Distributor, InnerDistributor};
+use crate::host;
+use crate::socket::{serve_connection, ConnectionConfig};
+
+const CONNECTIONS: usize = 4;
+const QUEUE_LEN: usize = 2;
+const PACKET_SIZE: usize = 256;
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+type Broker = InnerDistributorMutex<NoopRawMutex, CONNECTIONS, 1, QUEUE_LEN>;
+
+fn broker() -> &'static Broker {
+    Box::leak(Box::new(InnerDistributorMutex::new(
+        InnerDistributor::default(),
+    )))
+}
+
+/// Runs a test on a single threaded executor so connection handlers can be spawned locally
+fn run<F: Future<Output = ()>>(test: impl FnOnce(&'static Broker) -> F) {
+    let broker = broker();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .unwrap();
+    LocalSet::new().block_on(&runtime, test(broker));
+}
+
+/// Writes a CONNECT packet, mqtt-format has no way to construct the will by hand
+fn write_connect<const N: usize>(
+    writer: &mut PacketWriter<N>,
+    client_id: &str,
+    will: Option<(&str, &[u8])>,
+) -> WResult<PacketWriter<N>> {
+    // protocol name, version, flags, keep alive, empty properties and client id
+    let mut remaining_length = 6 + 1 + 1 + 2 + 1 + 2 + client_id.len() as u32;
+    if let Some((topic, payload)) = will {
+        remaining_length += 1 + 2 + topic.len() as u32 + 2 + payload.len() as u32;
+    }
+    writer.write_byte(0x10)?;
+    write_variable_u32(writer, remaining_length)?;
+    write_string(writer, "MQTT")?;
+    writer.write_byte(5)?;
+    // clean start and will flag
+    writer.write_byte(if will.is_some() { 0x06 } else { 0x02 })?;
+    writer.write_slice(&0u16.to_be_bytes())?;
+    writer.write_byte(0)?;
+    write_string(writer, client_id)?;
+    if let Some((topic, payload)) = will {
+        writer.write_byte(0)?;
+        write_string(writer, topic)?;
+        writer.write_slice(&(payload.len() as u16).to_be_bytes())?;
+        writer.write_slice(payload)?;
+    }
+    Ok(())
+}
+
+/// Writes a PUBLISH with response topic and correlation data, mqtt-format can not encode them
+fn write_request<const N: usize>(
+    writer: &mut PacketWriter<N>,
+    topic: &str,
+    response_topic: &str,
+    correlation_data: &[u8],
+    payload: &[u8],
+) -> WResult<PacketWriter<N>> {
+    let properties_length =
+        1 + 2 + response_topic.len() as u32 + 1 + 2 + correlation_data.len() as u32;
+    writer.write_byte(0x30)?;
+    write_variable_u32(
+        writer,
+        2 + topic.len() as u32 + 1 + properties_length + payload.len() as u32,
+    )?;
+    write_string(writer, topic)?;
+    write_variable_u32(writer, properties_length)?;
+    writer.write_byte(0x08)?;
+    write_string(writer, response_topic)?;
+    writer.write_byte(0x09)?;
+    writer.write_slice(&(correlation_data.len() as u16).to_be_bytes())?;
+    writer.write_slice(correlation_data)?;
+    writer.write_slice(payload)
+}
+
+/// Scripted MQTT client connected to a connection handler of the broker
+struct Client {
+    parser: MqttCodecDecoder<FromTokio<ReadHalf<DuplexStream>>, [u8; PACKET_SIZE]>,
+    encoder: MqttCodecEncoder<FromTokio<WriteHalf<DuplexStream>>, [u8; PACKET_SIZE]>,
+    handler: JoinHandle<()>,
+}
+
+impl Client {
+    /// Opens a pipe to a new connection handler using slot `id` of the broker
+    fn new(broker: &'static Broker, id: usize) -> Self {
+        Self::with_config(broker, id, ConnectionConfig::default())
+    }
+
+    fn with_config(broker: &'static Broker, id: usize, config: ConnectionConfig) -> Self {
+        let (client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server);
+        let handler = tokio::task::spawn_local(async move {
+            let distributor = Distributor::new(broker, id);
+            serve_connection(
+                FromTokio::new(reader),
+                FromTokio::new(writer),
+                &distributor,
+                &config,
+            )
+            .await;
+        });
+        let (reader, writer) = tokio::io::split(client);
+        Client {
+            parser: MqttCodecDecoder::new(FromTokio::new(reader), [0; PACKET_SIZE])
+                .with_streaming(0),
+            encoder: MqttCodecEncoder::new(FromTokio::new(writer), [0; PACKET_SIZE]),
+            handler,
+        }
+    }
+
+    async fn send(&mut self, data: &[u8]) {
+        let packet = MqttPacket::parse_complete(data).unwrap();
+        self.encoder.write(packet).await.unwrap();
+    }
+
+    async fn receive(&mut self) -> MqttPacket<'_> {
+        with_timeout(TIMEOUT, self.parser.next())
+            .await
+            .expect("no packet received")
+            .unwrap()
+            .expect("connection closed")
+    }
+
+    /// Receives a packet that does not fit into the buffer of the client
+    async fn receive_large(&mut self) -> Vec<u8> {
+        let mut packet = Vec::new();
+        loop {
+            let frame = with_timeout(TIMEOUT, self.parser.next_frame())
+                .await
+                .expect("no packet received")
+                .unwrap()
+                .expect("connection closed");
+            match frame {
+                Frame::Chunk {
+                    data, remaining, ..
+                } => {
+                    packet.extend_from_slice(data);
+                    if remaining == 0 {
+                        return packet;
+                    }
+                }
+                Frame::Packet(packet) => panic!("expected a large packet, got {:?}", packet),
+            }
+        }
+    }
+
+    /// Asserts that nothing is received for a while
+    async fn expect_silence(&mut self) {
+        let received = with_timeout(Duration::from_millis(100), self.parser.next()).await;
+        assert!(received.is_err(), "unexpected packet {:?}", received);
+    }
+
+    async fn connect(&mut self, client_id: &str, will: Option<(&str, &[u8])>) {
+        let mut writer = PacketWriter::<PACKET_SIZE>::default();
+        write_connect(&mut writer, client_id, will).unwrap();
+        self.send(writer.get_written_data()).await;
+        match self.receive().await {
+            MqttPacket::Connack(connack) => {
+                assert!(matches!(connack.reason_code, ConnackReasonCode::Success))
+            }
+            packet => panic!("expected CONNACK, got {:?}", packet),
+        }
+    }
+
+    async fn subscribe(&mut self, filter: &str) {
+        let mut writer = PacketWriter::<PACKET_SIZE>::default();
+        write_subscriptions(&mut writer, true, 1, [filter].iter(), 0).unwrap();
+        self.send(writer.get_written_data()).await;
+        match self.receive().await {
+            MqttPacket::Suback(_) => {}
+            packet => panic!("expected SUBACK, got {:?}", packet),
+        }
+    }
+
+    async fn publish(&mut self, topic: &str, payload: &[u8]) {
+        let publish = MqttPacket::Publish(MPublish {
+            duplicate: false,
+            quality_of_service: QualityOfService::AtMostOnce,
+            retain: false,
+            topic_name: topic,
+            packet_identifier: None,
+            properties: PublishProperties::new(),
+            payload,
+        });
+        self.encoder.write(publish).await.unwrap();
+        match self.receive().await {
+            MqttPacket::Puback(_) => {}
+            packet => panic!("expected PUBACK, got {:?}", packet),
+        }
+    }
+
+    /// Publishes a packet that is larger than the buffers of the client and the broker
+    async fn publish_large(&mut self, topic: &str, payload: &[u8]) {
+        let mut writer = PacketWriter::<4096>::default();
+        MqttPacket::Publish(MPublish {
+            duplicate: false,
+            quality_of_service: QualityOfService::AtMostOnce,
+            retain: false,
+            topic_name: topic,
+            packet_identifier: None,
+            properties: PublishProperties::new(),
+            payload,
+        })
+        .write(&mut writer)
+        .unwrap();
+        self.encoder
+            .write_chunk(writer.get_written_data())
+            .await
+            .unwrap();
+        match self.receive().await {
+            MqttPacket::Puback(_) => {}
+            packet => panic!("expected PUBACK, got {:?}", packet),
+        }
+    }
+
+    async fn expect_publish(&mut self, topic: &str, payload: &[u8]) {
+        match self.receive().await {
+            MqttPacket::Publish(publish) => {
+                assert_eq!(publish.topic_name, topic);
+                assert_eq!(publish.payload, payload);
+            }
+            packet => panic!("expected PUBLISH, got {:?}", packet),
+        }
+    }
+
+    /// Publishes a request and returns the payload of the response published to
+    /// `response_topic`, the client has to be subscribed to it
+    async fn request(&mut self, topic: &str, payload: &[u8], response_topic: &str) -> String {
+        let mut writer = PacketWriter::<PACKET_SIZE>::default();
+        write_request(&mut writer, topic, response_topic, b"42", payload).unwrap();
+        self.send(writer.get_written_data()).await;
+        let mut response = None;
+        let mut acknowledged = false;
+        while response.is_none() || !acknowledged {
+            match self.receive().await {
+                MqttPacket::Puback(_) => acknowledged = true,
+                MqttPacket::Publish(publish) => {
+                    assert_eq!(publish.topic_name, response_topic);
+                    let correlation_data = publish.properties.correlation_data().map(|c| c.0);
+                    assert_eq!(correlation_data, Some(&b"42"[..]));
+                    response = Some(String::from_utf8(publish.payload.to_vec()).unwrap());
+                }
+                packet => panic!("expected PUBACK or PUBLISH, got {:?}", packet),
+            }
+        }
+        response.unwrap()
+    }
+
+    /// Sends DISCONNECT and waits until the handler is done
+    async fn disconnect(mut self) {
+        self.send(&[0xE0, 0x00]).await;
+        self.handler.await.unwrap();
+    }
+
+    /// Drops the connection without DISCONNECT and waits until the handler is done
+    async fn close(self) {
+        let Client {
+            parser,
+            encoder,
+            handler,
+        } = self;
+        drop(parser);
+        drop(encoder);
+        handler.await.unwrap();
+    }
+}
+
+#[test]
+fn test_connect() {
+    run(|broker| async move {
+        let mut client = Client::new(broker, 0);
+        client.connect("client", None).await;
+        // PINGREQ
+        client.send(&[0xC0, 0x00]).await;
+        match client.receive().await {
+            MqttPacket::Pingresp(_) => {}
+            packet => panic!("expected PINGRESP, got {:?}", packet),
+        }
+        client.disconnect().await;
+    });
+}
+
+#[test]
+fn test_fan_out() {
+    run(|broker| async move {
+        let mut publisher = Client::new(broker, 0);
+        let mut first = Client::new(broker, 1);
+        let mut second = Client::new(broker, 2);
+        publisher.connect("publisher", None).await;
+        first.connect("first", None).await;
+        second.connect("second", None).await;
+        first.subscribe("sensors/+").await;
+        second.subscribe("sensors/temp").await;
+
+        publisher.publish("sensors/temp", b"21").await;
+        first.expect_publish("sensors/temp", b"21").await;
+        second.expect_publish("sensors/temp", b"21").await;
+
+        publisher.publish("sensors/humidity", b"40").await;
+        first.expect_publish("sensors/humidity", b"40").await;
+        second.expect_silence().await;
+        publisher.expect_silence().await;
+    });
+}
+
+#[test]
+fn test_will_on_abrupt_close() {
+    run(|broker| async move {
+        let mut watcher = Client::new(broker, 0);
+        let mut device = Client::new(broker, 1);
+        watcher.connect("watcher", None).await;
+        watcher.subscribe("status/#").await;
+        device
+            .connect("device", Some(("status/device", b"offline")))
+            .await;
+
+        device.close().await;
+        watcher.expect_publish("status/device", b"offline").await;
+    });
+}
+
+#[test]
+fn test_cleanup_on_disconnect() {
+    run(|broker| async move {
+        let mut publisher = Client::new(broker, 0);
+        let mut subscriber = Client::new(broker, 1);
+        publisher.connect("publisher", None).await;
+        subscriber.connect("subscriber", None).await;
+        subscriber.subscribe("a").await;
+        subscriber.disconnect().await;
+
+        // the slot is reused by a client without subscriptions
+        let mut subscriber = Client::new(broker, 1);
+        subscriber.connect("subscriber", None).await;
+        // more messages than fit into the queue, blocks if the old subscription is still queued
+        for _ in 0..QUEUE_LEN * 2 {
+            publisher.publish("a", b"message").await;
+        }
+        subscriber.expect_silence().await;
+    });
+}
+
+fn no_intruder(client_id: &str, _username: Option<&str>, _password: Option<&[u8]>) -> bool {
+    client_id != "intruder"
+}
+
+#[test]
+fn test_authentication() {
+    run(|broker| async move {
+        let config = ConnectionConfig {
+            authenticate: Some(no_intruder),
+            ..Default::default()
+        };
+        let mut client = Client::with_config(broker, 0, config.clone());
+        client.connect("device", None).await;
+        client.disconnect().await;
+
+        let mut client = Client::with_config(broker, 0, config);
+        let mut writer = PacketWriter::<PACKET_SIZE>::default();
+        write_connect(&mut writer, "intruder", None).unwrap();
+        client.send(writer.get_written_data()).await;
+        match client.receive().await {
+            MqttPacket::Connack(connack) => {
+                assert!(matches!(
+                    connack.reason_code,
+                    ConnackReasonCode::NotAuthorized
+                ))
+            }
+            packet => panic!("expected CONNACK, got {:?}", packet),
+        }
+        client.handler.await.unwrap();
+    });
+}
+
+#[test]
+fn test_mount_points() {
+    run(|broker| async move {
+        let group = |mount_point: &'static str| ConnectionConfig {
+            mount_point: Some(mount_point),
+            ..Default::default()
+        };
+        let mut first = Client::with_config(broker, 0, group("first/"));
+        let mut second = Client::with_config(broker, 1, group("second/"));
+        let mut observer = Client::new(broker, 2);
+        first.connect("first", None).await;
+        second.connect("second", None).await;
+        observer.connect("observer", None).await;
+        first.subscribe("#").await;
+        second.subscribe("#").await;
+        observer.subscribe("+/sensors/temp").await;
+
+        first.publish("sensors/temp", b"21").await;
+        // the mount point is stripped again for clients of the group
+        first.expect_publish("sensors/temp", b"21").await;
+        observer.expect_publish("first/sensors/temp", b"21").await;
+        second.expect_silence().await;
+    });
+}
+
+#[test]
+fn test_large_publish() {
+    run(|broker| async move {
+        let mut publisher = Client::new(broker, 0);
+        let config = ConnectionConfig {
+            mount_point: Some("group/"),
+            ..Default::default()
+        };
+        let mut subscriber = Client::with_config(broker, 1, config);
+        let mut observer = Client::new(broker, 2);
+        publisher.connect("publisher", None).await;
+        subscriber.connect("subscriber", None).await;
+        observer.connect("observer", None).await;
+        subscriber.subscribe("a").await;
+        observer.subscribe("group/a").await;
+
+        // the publish is forwarded chunk by chunk, it does not fit into the queue
+        let payload = [7; 1500];
+        publisher.publish_large("group/a", &payload).await;
+        for (client, topic) in [(&mut subscriber, "a"), (&mut observer, "group/a")] {
+            let packet = client.receive_large().await;
+            match MqttPacket::parse_complete(&packet) {
+                Ok(MqttPacket::Publish(publish)) => {
+                    assert_eq!(publish.topic_name, topic);
+                    assert_eq!(publish.payload, payload);
+                }
+                packet => panic!("expected PUBLISH, got {:?}", packet),
+            }
+        }
+
+        // the queue can be used as usual afterwards
+        publisher.publish("group/a", b"small").await;
+        subscriber.expect_publish("a", b"small").await;
+        observer.expect_publish("group/a", b"small").await;
+    });
+}
+
+#[test]
+fn test_stats() {
+    run(|broker| async move {
+        let mut publisher = Client::new(broker, 0);
+        let mut subscriber = Client::new(broker, 1);
+        publisher.connect("publisher", None).await;
+        subscriber.connect("subscriber", None).await;
+        subscriber.subscribe("a").await;
+        publisher.publish("a", b"message").await;
+        subscriber.expect_publish("a", b"message").await;
+
+        let stats = broker.stats().await;
+        assert_eq!(stats.connections_accepted, 2);
+        assert_eq!(stats.connected(), 2);
+        // CONNACK, SUBSCRIBE, SUBACK, PUBLISH and PUBACK by packet type
+        assert_eq!(stats.traffic.packets_out[2], 2);
+        assert_eq!(stats.traffic.packets_in[8], 1);
+        assert_eq!(stats.traffic.packets_out[9], 1);
+        assert_eq!(stats.traffic.packets_in[3], 1);
+        assert_eq!(stats.traffic.packets_out[3], 1);
+        assert_eq!(stats.traffic.packets_out[4], 1);
+        assert_eq!(stats.tree_used, 2);
+        assert_eq!(stats.clients[1].subscriptions, 1);
+        assert_eq!(stats.clients[1].packets_out, 3);
+        assert!(stats.clients[1].bytes_in > 0);
+
+        // the traffic of closed connections is kept
+        subscriber.disconnect().await;
+        let closed = broker.stats().await;
+        assert!(!closed.clients[1].connected);
+        assert_eq!(closed.clients[1].packets_in, 0);
+        assert_eq!(closed.traffic.packets_in[14], 1);
+        assert_eq!(closed.traffic.bytes_out, stats.traffic.bytes_out);
+    });
+}
+
+#[test]
+fn test_admin() {
+    run(|broker| async move {
+        let mut sensor = Client::new(broker, 0);
+        let mut display = Client::new(broker, 2);
+        sensor.connect("sensor", None).await;
+        display.connect("display", None).await;
+        display.subscribe("sensors/+").await;
+
+        {
+            let inner = broker.lock().await;
+            let clients: Vec<_> = inner
+                .clients()
+                .map(|(id, client)| (id, client.client_id.as_str()))
+                .collect();
+            assert_eq!(clients, [(0, "sensor"), (2, "display")]);
+            // connections over pipes have no remote address
+            assert!(inner.clients().all(|(_, client)| client.remote.is_none()));
+            let mut filters = Vec::new();
+            inner.subscriptions(2, |filter| filters.push(filter.to_owned()));
+            assert_eq!(filters, ["sensors/+"]);
+        }
+
+        let reason = DisconnectReasonCode::AdministrativeAction;
+        assert!(!broker.lock().await.disconnect(1, reason));
+        assert!(broker.lock().await.disconnect(2, reason));
+        match display.receive().await {
+            MqttPacket::Disconnect(disconnect) => assert!(matches!(
+                disconnect.reason_code,
+                DisconnectReasonCode::AdministrativeAction
+            )),
+            packet => panic!("expected DISCONNECT, got {:?}", packet),
+        }
+        display.handler.await.unwrap();
+        let inner = broker.lock().await;
+        assert_eq!(inner.clients().count(), 1);
+        let mut filters = 0;
+        inner.subscriptions(2, |_| filters += 1);
+        assert_eq!(filters, 0);
+    });
+}
+
+fn admin_only(client_id: &str, _username: Option<&str>, _password: Option<&[u8]>) -> bool {
+    client_id == "admin"
+}
+
+#[test]
+fn test_control() {
+    run(|broker| async move {
+        let config = ConnectionConfig {
+            authorize_control: Some(admin_only),
+            ..Default::default()
+        };
+        let mut admin = Client::with_config(broker, 0, config.clone());
+        let mut device = Client::with_config(broker, 1, config);
+        let mut observer = Client::new(broker, 2);
+        admin.connect("admin", None).await;
+        device.connect("device", None).await;
+        observer.connect("observer", None).await;
+        admin.subscribe("admin/responses").await;
+        device.subscribe("device/responses").await;
+        observer.subscribe("$CONTROL/#").await;
+
+        let stats = admin
+            .request("$CONTROL/stats", b"", "admin/responses")
+            .await;
+        assert!(
+            stats.starts_with("{\"ok\":true,\"connected\":3,"),
+            "{}",
+            stats
+        );
+        // other clients get no response, they can not publish in the name of the broker
+        let mut writer = PacketWriter::<PACKET_SIZE>::default();
+        write_request(
+            &mut writer,
+            "$CONTROL/kick",
+            "admin/responses",
+            b"42",
+            b"admin",
+        )
+        .unwrap();
+        device.send(writer.get_written_data()).await;
+        match device.receive().await {
+            MqttPacket::Puback(_) => {}
+            packet => panic!("expected PUBACK, got {:?}", packet),
+        }
+        admin.expect_silence().await;
+        let response = admin
+            .request("$CONTROL/reboot", b"", "admin/responses")
+            .await;
+        assert_eq!(response, "{\"ok\":false,\"error\":\"unknown action\"}");
+
+        let response = admin
+            .request("$CONTROL/kick", b"device", "admin/responses")
+            .await;
+        assert_eq!(response, "{\"ok\":true,\"kicked\":1}");
+        match device.receive().await {
+            MqttPacket::Disconnect(disconnect) => assert!(matches!(
+                disconnect.reason_code,
+                DisconnectReasonCode::AdministrativeAction
+            )),
+            packet => panic!("expected DISCONNECT, got {:?}", packet),
+        }
+        device.handler.await.unwrap();
+        // requests are not sent to subscribers
+        observer.expect_silence().await;
+    });
+}
+
+/// Serves slot `id` of `broker` on a TCP socket on localhost, returns its address
+async fn listen_on_localhost(broker: &'static Broker, id: usize) -> IpEndpoint {
+    let listener = Rc::new(TcpListener::bind("127.0.0.1:0").await.unwrap());
+    let port = listener.local_addr().unwrap().port();
+    tokio::task::spawn_local(async move { host::listen(&listener, id, broker).await });
+    IpEndpoint::new(IpAddress::v4(127, 0, 0, 1), port)
+}
+
+fn bridge_config(remote: IpEndpoint, topics: &'static [BridgeTopic]) -> BridgeConfig {
+    BridgeConfig {
+        remote,
+        client_id: "edge1",
+        username: None,
+        password: None,
+        keep_alive: 0,
+        topics,
+        min_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(100),
+    }
+}
+
+/// Waits until slot `id` of `broker` has subscribed to something
+async fn wait_for_subscription(broker: &'static Broker, id: usize) {
+    let subscribed = async {
+        loop {
+            let mut filters = 0;
+            broker.lock().await.subscriptions(id, |_| filters += 1);
+            if filters > 0 {
+                return;
+            }
+            Timer::after(Duration::from_millis(10)).await;
+        }
+    };
+    with_timeout(TIMEOUT, subscribed)
+        .await
+        .expect("not subscribed");
+}
+
+#[test]
+fn test_bridge() {
+    run(|broker| async move {
+        static TOPICS: [BridgeTopic; 3] = [
+            BridgeTopic {
+                pattern: "sensors/#",
+                direction: BridgeDirection::Out,
+                local_prefix: "home/",
+                remote_prefix: "edge1/",
+            },
+            BridgeTopic {
+                pattern: "commands/#",
+                direction: BridgeDirection::In,
+                local_prefix: "home/",
+                remote_prefix: "edge1/",
+            },
+            BridgeTopic {
+                pattern: "status/#",
+                direction: BridgeDirection::Both,
+                local_prefix: "home/",
+                remote_prefix: "edge1/",
+            },
+        ];
+        let upstream = self::broker();
+        let remote = listen_on_localhost(upstream, 1).await;
+        let mut local = Client::new(broker, 0);
+        let mut central = Client::new(upstream, 0);
+        local.connect("local", None).await;
+        central.connect("central", None).await;
+        local.subscribe("home/#").await;
+        central.subscribe("edge1/#").await;
+
+        let bridge =
+            tokio::task::spawn_local(host::bridge(1, bridge_config(remote, &TOPICS), broker));
+        wait_for_subscription(broker, 1).await;
+        wait_for_subscription(upstream, 1).await;
+
+        local.publish("home/sensors/temp", b"21").await;
+        local.expect_publish("home/sensors/temp", b"21").await;
+        central.expect_publish("edge1/sensors/temp", b"21").await;
+
+        central.publish("edge1/commands/light", b"on").await;
+        central.expect_publish("edge1/commands/light", b"on").await;
+        local.expect_publish("home/commands/light", b"on").await;
+        // the exports of a `Both` topic are not echoed thanks to No Local
+        local.publish("home/status/door", b"open").await;
+        local.expect_publish("home/status/door", b"open").await;
+        central.expect_publish("edge1/status/door", b"open").await;
+        central.publish("edge1/status/window", b"closed").await;
+        central
+            .expect_publish("edge1/status/window", b"closed")
+            .await;
+        local.expect_publish("home/status/window", b"closed").await;
+        // topics outside of the patterns are not forwarded
+        local.publish("home/lights", b"on").await;
+        local.expect_publish("home/lights", b"on").await;
+        central.publish("edge1/sensors/humidity", b"40").await;
+        central
+            .expect_publish("edge1/sensors/humidity", b"40")
+            .await;
+        central.expect_silence().await;
+        local.expect_silence().await;
+        bridge.abort();
+    });
+}
+
+#[test]
+fn test_bridge_invalid_topic() {
+    run(|broker| async move {
+        static TOPICS: [BridgeTopic; 1] = [BridgeTopic {
+            pattern: "sensors/#",
+            direction: BridgeDirection::In,
+            local_prefix: "home/",
+            remote_prefix: "edge1/#/",
+        }];
+        let remote = listen_on_localhost(self::broker(), 0).await;
+        // the bridge stops instead of reconnecting forever
+        let bridge = host::bridge(0, bridge_config(remote, &TOPICS), broker);
+        with_timeout(TIMEOUT, bridge)
+            .await
+            .expect("bridge still running");
+    });
+}
@@ -8,12 +8,18 @@ use mqtt_format::v5::packets::suback::SubackReasonCode;
 pub enum DistributorError {
     #[error("Topic too long")]
     TopicTooLong,
+    #[error("Invalid topic filter")]
+    InvalidTopicFilter,
     #[error("Message too long")]
     MessageTooLong,
     #[error("Queue full")]
     QueueFull,
     #[error("Unexpected packet")]
     UnexpectedPacket,
+    #[error("Keep alive timeout")]
+    KeepAliveTimeout,
+    #[error("Disconnected by the server")]
+    Disconnected,
     #[error("Unknown error")]
     Unknown,
 }
@@ -22,6 +28,7 @@ impl From<TopicsError> for DistributorError {
     fn from(e: TopicsError) -> Self {
         match e {
             TopicsError::TopicTooLong => DistributorError::TopicTooLong,
+            TopicsError::InvalidFilter => DistributorError::InvalidTopicFilter,
             TopicsError::Full => DistributorError::QueueFull,
         }
     }
@@ -31,9 +38,12 @@ impl From<DistributorError> for DisconnectReasonCode {
     fn from(e: DistributorError) -> Self {
         match e {
             DistributorError::TopicTooLong => DisconnectReasonCode::TopicNameInvalid,
+            DistributorError::InvalidTopicFilter => DisconnectReasonCode::TopicFilterInvalid,
             DistributorError::MessageTooLong => DisconnectReasonCode::PacketTooLarge,
             DistributorError::QueueFull => DisconnectReasonCode::ReceiveMaximumExceeded,
             DistributorError::UnexpectedPacket => DisconnectReasonCode::ProtocolError,
+            DistributorError::KeepAliveTimeout => DisconnectReasonCode::KeepAliveTimeout,
+            DistributorError::Disconnected => DisconnectReasonCode::AdministrativeAction,
             DistributorError::Unknown => DisconnectReasonCode::UnspecifiedError,
         }
     }
@@ -43,9 +53,12 @@ impl From<DistributorError> for SubackReasonCode {
     fn from(e: DistributorError) -> Self {
         match e {
             DistributorError::TopicTooLong => SubackReasonCode::TopicFilterInvalid,
+            DistributorError::InvalidTopicFilter => SubackReasonCode::TopicFilterInvalid,
             DistributorError::MessageTooLong => SubackReasonCode::UnspecifiedError,
             DistributorError::QueueFull => SubackReasonCode::QuotaExceeded,
             DistributorError::UnexpectedPacket => SubackReasonCode::ImplementationSpecificError,
+            DistributorError::KeepAliveTimeout => SubackReasonCode::UnspecifiedError,
+            DistributorError::Disconnected => SubackReasonCode::UnspecifiedError,
             DistributorError::Unknown => SubackReasonCode::UnspecifiedError,
         }
     }
@@ -67,5 +80,87 @@ pub enum MqttCodecError {
 #[repr(u8)]
 pub enum TopicsError {
     TopicTooLong,
+    /// wildcards that do not fill a whole level or a `#` that is not the last level
+    InvalidFilter,
     Full,
 }
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, ErrorCategory)]
+#[repr(u8)]
+pub enum MqttSnError {
+    Incomplete,
+    Invalid,
+    InvalidLength,
+    UnknownPacket,
+    BufferTooSmall,
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, ErrorCategory)]
+#[repr(u8)]
+pub enum BridgeError {
+    #[error("Could not connect to remote broker")]
+    ConnectionFailed,
+    #[error("Remote broker refused connection")]
+    ConnectionRefused,
+    #[error("Connection closed")]
+    ConnectionClosed,
+    #[error("Unexpected packet")]
+    ProtocolError,
+    #[error("Topic too long")]
+    TopicTooLong,
+    #[error("Invalid topic filter")]
+    InvalidTopicFilter,
+    #[error("Could not keep up with local messages")]
+    Overflow,
+}
+
+impl BridgeError {
+    /// errors in the `BridgeConfig` that reconnecting does not fix
+    pub(crate) fn is_permanent(self) -> bool {
+        matches!(
+            self,
+            BridgeError::TopicTooLong | BridgeError::InvalidTopicFilter
+        )
+    }
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, ErrorCategory)]
+#[repr(u8)]
+pub enum PersistenceError {
+    #[error("Flash operation failed")]
+    Flash,
+    #[error("Flash range too small for the stored records")]
+    Full,
+    #[error("Record too large")]
+    TooLarge,
+}
+
+impl From<DistributorError> for BridgeError {
+    fn from(e: DistributorError) -> Self {
+        match e {
+            DistributorError::TopicTooLong => BridgeError::TopicTooLong,
+            DistributorError::InvalidTopicFilter => BridgeError::InvalidTopicFilter,
+            _ => BridgeError::Overflow,
+        }
+    }
+}
+
+impl From<MqttCodecError> for BridgeError {
+    fn from(e: MqttCodecError) -> Self {
+        match e {
+            MqttCodecError::ConnectionReset => BridgeError::ConnectionClosed,
+            _ => BridgeError::ProtocolError,
+        }
+    }
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, ErrorCategory)]
+#[repr(u8)]
+pub enum ListenerError {
+    #[error("Transport not supported by the acceptor")]
+    UnsupportedTransport,
+}
@@ -0,0 +1,500 @@
+//! Federation of several brokers into a mesh
+//!
+//! Every broker runs a link to each of its peers. A link connects to the peer as a client with
+//! the client id `$fed/<broker id>` and subscribes to everything the local clients are
+//! subscribed to. The peer recognizes the link by its client id and tags every publish it sends
+//! over the link with the broker the message originated from and a message id. Links drop
+//! messages that originated from their own broker (loops) or that were already received over
+//! another link (duplicates).
+//!
+//! Subscription interest is only propagated one hop, so every broker needs a link to every
+//! other broker.
+use core::fmt::Write as _;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{IpEndpoint, Stack};
+use embassy_net_driver::Driver;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_io_async::Write;
+use heapless::{Deque, String, Vec};
+use mqtt_format::v5::integers::variable_u32_binary_size;
+use mqtt_format::v5::packets::pingreq::MPingreq;
+use mqtt_format::v5::packets::publish::MPublish;
+use mqtt_format::v5::packets::MqttPacket;
+use mqtt_format::v5::qos::QualityOfService;
+use mqtt_format::v5::write::{MqttWriteError, WriteMqttPacket};
+
+use crate::bridge::handshake;
+use crate::codec::{
+    write_string, write_subscriptions, write_variable_u32, MqttCodecDecoder, MqttCodecEncoder,
+    PacketWriter, SliceWriter,
+};
+use crate::config::BrokerConfig;
+use crate::distributor::Distributor;
+use crate::errors::BridgeError;
+use crate::log::{info, warn};
+
+/// Client id prefix used by links, followed by the broker id
+pub const CLIENT_ID_PREFIX: &str = "$fed/";
+/// User property holding the id of the broker a message was first published on
+const ORIGIN_PROPERTY: &str = "fed-origin";
+/// User property holding the message id assigned by the origin broker
+const MESSAGE_ID_PROPERTY: &str = "fed-id";
+/// Maximum length of a broker id
+pub const MAX_BROKER_ID_LENGTH: usize = 32;
+/// How many different topic filters are propagated to a peer
+const MAX_INTEREST: usize = 16;
+/// How many message ids are remembered to detect duplicates, shared by all links
+pub(crate) const DUPLICATE_CACHE_SIZE: usize = 32;
+/// How often a link checks whether the local subscriptions changed
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+const KEEP_ALIVE: u16 = 60;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Buffer for the header of a tagged publish, messages with a longer header are not tagged
+pub(crate) const TAGGED_HEADER_SIZE: usize = 256;
+
+pub type BrokerId = String<MAX_BROKER_ID_LENGTH>;
+
+/// Another broker of the federation
+pub struct FederationPeer {
+    /// broker id the peer was created with, see [`InnerDistributor::with_broker_id`](crate::distributor::InnerDistributor::with_broker_id)
+    pub id: &'static str,
+    pub remote: IpEndpoint,
+}
+
+/// Remembers the latest message ids together with their origin
+#[derive(Default)]
+pub(crate) struct DuplicateCache<const N: usize> {
+    entries: Deque<(u32, u32), N>,
+}
+
+impl<const N: usize> DuplicateCache<N> {
+    /// returns true if the message was seen before, otherwise it is remembered
+    pub(crate) fn check(&mut self, origin: &str, id: u32) -> bool {
+        let entry = (hash(origin), id);
+        if self.entries.iter().any(|e| *e == entry) {
+            return true;
+        }
+        if self.entries.is_full() {
+            self.entries.pop_front();
+        }
+        let _ = self.entries.push_back(entry);
+        false
+    }
+}
+
+/// FNV-1a, good enough to tell a handful of broker ids apart
+fn hash(value: &str) -> u32 {
+    value
+        .bytes()
+        .fold(0x811c_9dc5, |h, b| (h ^ b as u32).wrapping_mul(0x0100_0193))
+}
+
+/// Returns origin broker and message id of a publish tagged by a peer
+pub(crate) fn origin<'a>(publish: &'a MPublish) -> Option<(&'a str, Option<u32>)> {
+    let mut origin = None;
+    let mut id = None;
+    for property in publish.properties.user_properties()?.iter() {
+        match property.key {
+            ORIGIN_PROPERTY => origin = Some(property.value),
+            MESSAGE_ID_PROPERTY => id = property.value.parse().ok(),
+            _ => {}
+        }
+    }
+    Some((origin?, id))
+}
+
+/// Encodes the header of `publish` with origin and message id user properties, the payload has
+/// to be written right after it
+/// topic alias and subscription identifier only apply to the connection the message was received
+/// on, all other properties of the original message are kept
+pub(crate) fn tag<const S: usize>(
+    writer: &mut PacketWriter<S>,
+    publish: &MPublish,
+    origin: &str,
+    id: u32,
+) -> Result<(), MqttWriteError> {
+    let mut id_string = String::<10>::new();
+    let _ = write!(id_string, "{}", id);
+    let user_properties = || {
+        publish
+            .properties
+            .user_properties()
+            .into_iter()
+            .flat_map(|p| p.iter())
+            .filter(|p| p.key != ORIGIN_PROPERTY && p.key != MESSAGE_ID_PROPERTY)
+            .map(|p| (p.key, p.value))
+            .chain([
+                (ORIGIN_PROPERTY, origin),
+                (MESSAGE_ID_PROPERTY, id_string.as_str()),
+            ])
+    };
+    let properties = &publish.properties;
+    let payload_format = properties.payload_format_indicator().map(|p| p.0);
+    let expiry = properties.message_expiry_interval().map(|e| e.0);
+    let content_type = properties.content_type().map(|c| c.0);
+    let response_topic = properties.response_topic().map(|t| t.0);
+    let correlation_data = properties.correlation_data().map(|c| c.0);
+    let properties_length = payload_format.map_or(0, |_| 1 + 1)
+        + expiry.map_or(0, |_| 1 + 4)
+        + content_type.map_or(0, |c| 1 + 2 + c.len() as u32)
+        + response_topic.map_or(0, |t| 1 + 2 + t.len() as u32)
+        + correlation_data.map_or(0, |d| 1 + 2 + d.len() as u32)
+        + user_properties()
+            .map(|(key, value)| 1 + 2 + key.len() as u32 + 2 + value.len() as u32)
+            .sum::<u32>();
+    let remaining_length = 2
+        + publish.topic_name.len() as u32
+        + variable_u32_binary_size(properties_length)
+        + properties_length
+        + publish.payload.len() as u32;
+
+    // forwarded with QoS 0, like every other message the broker sends
+    writer.write_byte(0x30 | publish.retain as u8)?;
+    write_variable_u32(writer, remaining_length)?;
+    write_string(writer, publish.topic_name)?;
+    write_variable_u32(writer, properties_length)?;
+    if let Some(format) = payload_format {
+        writer.write_byte(0x01)?;
+        writer.write_byte(format)?;
+    }
+    if let Some(expiry) = expiry {
+        writer.write_byte(0x02)?;
+        writer.write_slice(&expiry.to_be_bytes())?;
+    }
+    if let Some(content_type) = content_type {
+        writer.write_byte(0x03)?;
+        write_string(writer, content_type)?;
+    }
+    if let Some(response_topic) = response_topic {
+        writer.write_byte(0x08)?;
+        write_string(writer, response_topic)?;
+    }
+    if let Some(data) = correlation_data {
+        writer.write_byte(0x09)?;
+        writer.write_slice(&(data.len() as u16).to_be_bytes())?;
+        writer.write_slice(data)?;
+    }
+    for (key, value) in user_properties() {
+        // user property identifier
+        writer.write_byte(0x26)?;
+        write_string(writer, key)?;
+        write_string(writer, value)?;
+    }
+    Ok(())
+}
+
+/// What a socket connected to a peer should do with a message from the distributor
+pub(crate) enum Outgoing {
+    /// the message came from the peer itself or can not be tagged
+    Drop,
+    /// the message already carries an origin
+    Forward,
+    /// the message was published on this broker and its tagged header has been written, it has
+    /// to be sent before the payload
+    Tagged,
+}
+
+/// Prepares a message for a socket connected to peer `peer`
+pub(crate) fn outgoing<const S: usize>(
+    writer: &mut PacketWriter<S>,
+    publish: &MPublish,
+    peer: &str,
+    broker_id: &str,
+    message_id: u32,
+) -> Outgoing {
+    match origin(publish) {
+        Some((origin, _)) if origin == peer => Outgoing::Drop,
+        Some(_) => Outgoing::Forward,
+        None => match tag(writer, publish, broker_id, message_id) {
+            Ok(()) => Outgoing::Tagged,
+            Err(_) => {
+                // an untagged message would come back from the peer as a new one
+                warn!("FEDERATION: message too long to tag, not sent to {}", peer);
+                Outgoing::Drop
+            }
+        },
+    }
+}
+
+/// Maintains the link to `peer` forever
+/// the distributor has to be created with [`InnerDistributor::with_broker_id`](crate::distributor::InnerDistributor::with_broker_id)
+pub async fn federation_link<T, B: BrokerConfig>(
+    stack: &'static Stack<T>,
+    id: usize,
+    peer: &'static FederationPeer,
+    distributor: &'static B,
+) where
+    T: Driver,
+{
+    let mut rx_buffer = [0; 1600];
+    let mut tx_buffer = [0; 1600];
+    let distributor = Distributor::new(distributor, id);
+    let Some(broker_id) = distributor.broker_id().await else {
+        warn!("FEDERATION: distributor has no broker id");
+        return;
+    };
+    let mut client_id = String::<{ CLIENT_ID_PREFIX.len() + MAX_BROKER_ID_LENGTH }>::new();
+    if client_id.push_str(CLIENT_ID_PREFIX).is_err() || client_id.push_str(broker_id).is_err() {
+        warn!("FEDERATION: broker id {} too long", broker_id);
+        return;
+    }
+    loop {
+        distributor.cleanup().await;
+
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(KEEP_ALIVE as u64 * 2)));
+        info!(
+            "FEDERATION: connecting to {} at {}...",
+            peer.id, peer.remote
+        );
+        if let Err(e) = link(&mut socket, &client_id, peer, &distributor).await {
+            warn!("FEDERATION {}: {:?}", peer.id, e);
+        }
+        socket.abort();
+        Timer::after(RECONNECT_DELAY).await;
+    }
+}
+
+async fn link<B: BrokerConfig>(
+    socket: &mut TcpSocket<'_>,
+    client_id: &str,
+    peer: &FederationPeer,
+    distributor: &Distributor<B>,
+) -> Result<(), BridgeError> {
+    socket
+        .connect(peer.remote)
+        .await
+        .map_err(|_| BridgeError::ConnectionFailed)?;
+    let (reader, writer) = socket.split();
+    let mut parser = MqttCodecDecoder::new(reader, B::buffer());
+    let mut encoder = MqttCodecEncoder::new(writer, B::buffer());
+    handshake(&mut parser, &mut encoder, client_id, None, None, KEEP_ALIVE).await?;
+    info!("FEDERATION: connected to {}", peer.id);
+
+    let mut interest = Vec::<B::Topic, MAX_INTEREST>::new();
+    let mut interest_version = None;
+    let mut packet_identifier = 0;
+    let ping_interval = Duration::from_secs(KEEP_ALIVE as u64 / 2);
+    let mut next_ping = Instant::now() + ping_interval;
+    loop {
+        // unlock after processing packet
+        distributor.unlock().await;
+        let version = distributor.interest_version().await;
+        if interest_version != Some(version) {
+            interest_version = Some(version);
+            sync_interest(
+                &mut encoder,
+                &mut interest,
+                &mut packet_identifier,
+                distributor,
+            )
+            .await?;
+        }
+        if Instant::now() >= next_ping {
+            encoder.write(MqttPacket::Pingreq(MPingreq {})).await?;
+            next_ping = Instant::now() + ping_interval;
+        }
+
+        // the decoder keeps its progress when the timeout cancels it
+        let received = distributor
+            .lock(with_timeout(SYNC_INTERVAL, parser.next()))
+            .await;
+        match received {
+            Ok(Ok(Some(MqttPacket::Publish(publish)))) => {
+                import(&publish, distributor).await;
+            }
+            Ok(Ok(Some(
+                MqttPacket::Suback(_) | MqttPacket::Unsuback(_) | MqttPacket::Pingresp(_),
+            ))) => {}
+            Ok(Ok(Some(MqttPacket::Disconnect(_)))) | Ok(Ok(None)) => {
+                return Err(BridgeError::ConnectionClosed)
+            }
+            Ok(Ok(Some(_))) => return Err(BridgeError::ProtocolError),
+            Ok(Err(e)) => return Err(e.into()),
+            // nothing received
+            Err(_) => {}
+        }
+    }
+}
+
+/// Publishes a message received from a peer locally, unless it is a loop or a duplicate
+async fn import<B: BrokerConfig>(publish: &MPublish<'_>, distributor: &Distributor<B>) {
+    let broker_id = distributor.broker_id().await;
+    match origin(publish) {
+        Some((origin, _)) if Some(origin) == broker_id => return,
+        Some((origin, Some(id))) if distributor.is_duplicate(origin, id).await => return,
+        _ => {}
+    }
+    let publish = MPublish {
+        duplicate: false,
+        quality_of_service: QualityOfService::AtMostOnce,
+        packet_identifier: None,
+        ..publish.clone()
+    };
+    if let Err(e) = distributor.forward(publish.topic_name, &publish).await {
+        warn!(
+            "FEDERATION: could not import {}: {:?}",
+            publish.topic_name, e
+        );
+    }
+}
+
+/// Subscribes the peer to new local topic filters and unsubscribes it from removed ones
+async fn sync_interest<U, B: BrokerConfig>(
+    encoder: &mut MqttCodecEncoder<U, B::Buffer>,
+    interest: &mut Vec<B::Topic, MAX_INTEREST>,
+    packet_identifier: &mut u16,
+    distributor: &Distributor<B>,
+) -> Result<(), BridgeError>
+where
+    U: Write,
+{
+    let mut current = Vec::<B::Topic, MAX_INTEREST>::new();
+    distributor.local_interest(&mut current).await;
+    let added = current.iter().filter(|t| !interest.contains(t));
+    send_subscriptions::<U, B>(encoder, true, packet_identifier, added).await?;
+    let removed = interest.iter().filter(|t| !current.contains(t));
+    send_subscriptions::<U, B>(encoder, false, packet_identifier, removed).await?;
+    *interest = current;
+    Ok(())
+}
+
+async fn send_subscriptions<'a, U, B: BrokerConfig>(
+    encoder: &mut MqttCodecEncoder<U, B::Buffer>,
+    subscribe: bool,
+    packet_identifier: &mut u16,
+    filters: impl Iterator<Item = &'a B::Topic> + Clone,
+) -> Result<(), BridgeError>
+where
+    U: Write,
+{
+    if filters.clone().next().is_none() {
+        return Ok(());
+    }
+    *packet_identifier = packet_identifier.wrapping_add(1).max(1);
+    let mut buffer = B::buffer();
+    let mut writer = SliceWriter::new(buffer.as_mut());
+    write_subscriptions(&mut writer, subscribe, *packet_identifier, filters, 0)
+        .map_err(|_| BridgeError::TopicTooLong)?;
+    let packet = MqttPacket::parse_complete(writer.get_written_data())
+        .map_err(|_| BridgeError::ProtocolError)?;
+    encoder.write(packet).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_cache() {
+        let mut cache = DuplicateCache::<2>::default();
+        assert!(!cache.check("a", 1));
+        assert!(cache.check("a", 1));
+        assert!(!cache.check("b", 1));
+        assert!(!cache.check("a", 2));
+        // oldest entry has been evicted
+        assert!(!cache.check("a", 1));
+    }
+
+    #[test]
+    fn test_tag() {
+        let publish = MPublish {
+            duplicate: false,
+            quality_of_service: QualityOfService::AtMostOnce,
+            retain: false,
+            topic_name: "/a/b",
+            packet_identifier: None,
+            properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+            payload: b"hello",
+        };
+        let mut writer = PacketWriter::<128>::default();
+        tag(&mut writer, &publish, "broker-a", 42).unwrap();
+        writer.write_slice(publish.payload).unwrap();
+        let packet = MqttPacket::parse_complete(writer.get_written_data()).unwrap();
+        let MqttPacket::Publish(tagged) = packet else {
+            panic!("expected publish");
+        };
+        assert_eq!(tagged.topic_name, "/a/b");
+        assert_eq!(tagged.payload, b"hello");
+        assert_eq!(origin(&tagged), Some(("broker-a", Some(42))));
+        assert_eq!(origin(&publish), None);
+    }
+
+    #[test]
+    fn test_outgoing() {
+        let publish = MPublish {
+            duplicate: false,
+            quality_of_service: QualityOfService::AtMostOnce,
+            retain: false,
+            topic_name: "/a/b",
+            packet_identifier: None,
+            properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+            payload: b"hello",
+        };
+        let mut writer = PacketWriter::<128>::default();
+        let tagged = outgoing(&mut writer, &publish, "broker-b", "broker-a", 42);
+        assert!(matches!(tagged, Outgoing::Tagged));
+
+        // a message that can not be tagged is not sent untagged
+        let mut writer = PacketWriter::<8>::default();
+        let untagged = outgoing(&mut writer, &publish, "broker-b", "broker-a", 42);
+        assert!(matches!(untagged, Outgoing::Drop));
+    }
+
+    #[test]
+    fn test_tag_properties() {
+        // content type, expiry, response topic, correlation data, topic alias and a user property
+        let mut properties = PacketWriter::<64>::default();
+        properties.write_byte(0x03).unwrap();
+        write_string(&mut properties, "text/plain").unwrap();
+        properties.write_byte(0x02).unwrap();
+        properties.write_slice(&60u32.to_be_bytes()).unwrap();
+        properties.write_byte(0x08).unwrap();
+        write_string(&mut properties, "/reply").unwrap();
+        properties.write_byte(0x09).unwrap();
+        properties.write_slice(&[0, 2, 4, 2]).unwrap();
+        properties.write_byte(0x23).unwrap();
+        properties.write_slice(&[0, 1]).unwrap();
+        properties.write_byte(0x26).unwrap();
+        write_string(&mut properties, "key").unwrap();
+        write_string(&mut properties, "value").unwrap();
+        let properties = properties.get_written_data();
+
+        let mut writer = PacketWriter::<128>::default();
+        writer.write_byte(0x30).unwrap();
+        let remaining_length = 2 + 4 + 1 + properties.len() as u32 + 5;
+        write_variable_u32(&mut writer, remaining_length).unwrap();
+        write_string(&mut writer, "/a/b").unwrap();
+        write_variable_u32(&mut writer, properties.len() as u32).unwrap();
+        writer.write_slice(properties).unwrap();
+        writer.write_slice(b"hello").unwrap();
+        let Ok(MqttPacket::Publish(publish)) =
+            MqttPacket::parse_complete(writer.get_written_data())
+        else {
+            panic!("expected publish");
+        };
+
+        let mut writer = PacketWriter::<128>::default();
+        tag(&mut writer, &publish, "broker-a", 42).unwrap();
+        writer.write_slice(publish.payload).unwrap();
+        let Ok(MqttPacket::Publish(tagged)) = MqttPacket::parse_complete(writer.get_written_data())
+        else {
+            panic!("expected publish");
+        };
+        let properties = &tagged.properties;
+        assert_eq!(properties.content_type().map(|c| c.0), Some("text/plain"));
+        assert_eq!(properties.message_expiry_interval().map(|e| e.0), Some(60));
+        assert_eq!(properties.response_topic().map(|t| t.0), Some("/reply"));
+        assert_eq!(
+            properties.correlation_data().map(|c| c.0),
+            Some(&[4, 2][..])
+        );
+        assert!(properties.topic_alias().is_none());
+        let user_properties = properties.user_properties().unwrap();
+        let mut user_properties = user_properties.iter().map(|p| (p.key, p.value));
+        assert_eq!(user_properties.next(), Some(("key", "value")));
+        assert_eq!(origin(&tagged), Some(("broker-a", Some(42))));
+        assert_eq!(tagged.payload, b"hello");
+    }
+}
@@ -0,0 +1,132 @@
+//! Entry points for the cargo-fuzz targets in `fuzz/`
+//! they are not part of the API and only available with the `fuzzing` feature
+use core::convert::Infallible;
+
+use embassy_futures::block_on;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embedded_io_async::{ErrorType, Read, Write};
+use heapless::Vec;
+use mqtt_format::v5::packets::MqttPacket;
+
+use crate::codec::{Frame, MqttCodecDecoder, PacketWriter};
+use crate::config::{BrokerConfig, InnerDistributorMutex};
+use crate::distributor::{Distributor, InnerDistributor};
+use crate::socket::{serve_connection, ConnectionConfig};
+use crate::topics::{matches, Tree};
+
+type Broker = InnerDistributorMutex<NoopRawMutex, 2, 1, 4, 16, 256>;
+
+/// Returns the input in chunks, like a socket that receives packets in pieces
+struct ChunkedReader<'a> {
+    data: &'a [u8],
+    chunk: usize,
+}
+
+impl ErrorType for ChunkedReader<'_> {
+    type Error = Infallible;
+}
+
+impl Read for ChunkedReader<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let len = buf.len().min(self.chunk).min(self.data.len());
+        buf[..len].copy_from_slice(&self.data[..len]);
+        self.data = &self.data[len..];
+        Ok(len)
+    }
+}
+
+struct Sink;
+
+impl ErrorType for Sink {
+    type Error = Infallible;
+}
+
+impl Write for Sink {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        Ok(buf.len())
+    }
+}
+
+/// The first byte selects the chunk size and the headroom of streaming, if it is enabled
+fn reader(data: &[u8]) -> Option<(ChunkedReader<'_>, Option<usize>)> {
+    let (&config, data) = data.split_first()?;
+    let chunk = (config & 0x3F) as usize + 1;
+    let headroom = if config & 0x40 != 0 { 8 } else { 0 };
+    Some((
+        ChunkedReader { data, chunk },
+        (config & 0x80 != 0).then_some(headroom),
+    ))
+}
+
+/// Decodes a byte stream and checks that every packet encodes to the same bytes again
+pub fn decode(data: &[u8]) {
+    let Some((reader, streaming)) = reader(data) else {
+        return;
+    };
+    let mut decoder = MqttCodecDecoder::new(reader, [0; 64]);
+    if let Some(headroom) = streaming {
+        decoder = decoder.with_streaming(headroom);
+    }
+    block_on(async {
+        loop {
+            match decoder.next_frame().await {
+                Ok(Some(Frame::Packet(packet))) => round_trip(&packet),
+                Ok(Some(Frame::Chunk { data, .. })) => assert!(!data.is_empty()),
+                Ok(None) | Err(_) => return,
+            }
+        }
+    });
+}
+
+fn round_trip(packet: &MqttPacket) {
+    let mut first = PacketWriter::<256>::default();
+    packet
+        .write(&mut first)
+        .expect("decoded packet can not be encoded");
+    assert_eq!(packet.binary_size() as usize, first.write_index);
+
+    let parsed =
+        MqttPacket::parse_complete(first.get_written_data()).expect("encoded packet is invalid");
+    let mut second = PacketWriter::<256>::default();
+    parsed.write(&mut second).unwrap();
+    assert_eq!(first.get_written_data(), second.get_written_data());
+}
+
+/// Runs a whole connection, from the handshake to the cleanup, on a byte stream
+pub fn handle(data: &[u8]) {
+    let Some((reader, _)) = reader(data) else {
+        return;
+    };
+    // every input gets a fresh broker, so a crash does not depend on earlier inputs
+    let broker: *mut Broker = Box::into_raw(Box::new(InnerDistributorMutex::new(
+        InnerDistributor::default(),
+    )));
+    {
+        // SAFETY: the broker is freed below, after the last use of its distributor
+        let distributor = Distributor::new(unsafe { &*broker }, 0);
+        block_on(serve_connection(
+            reader,
+            Sink,
+            &distributor,
+            &ConnectionConfig::default(),
+        ));
+        // no subscriptions must be left behind
+        let mut interest = Vec::<<Broker as BrokerConfig>::Topic, 1>::new();
+        block_on(distributor.local_interest(&mut interest));
+        assert!(interest.is_empty(), "subscriptions left: {:?}", interest);
+    }
+    // SAFETY: the connection is closed and its distributor was the only user of the broker
+    drop(unsafe { Box::from_raw(broker) });
+}
+
+/// Compares the subscription tree with the reference matcher
+pub fn topic(subscription: &str, topic: &str) {
+    let expected = matches(subscription, topic);
+    let mut tree = Tree::<16>::default();
+    if tree.insert(subscription, 0).is_ok() {
+        assert_eq!(tree.get_subscribed(topic).get(0), expected);
+    }
+    if !subscription.contains(['+', '#']) {
+        assert!(matches(subscription, subscription));
+    }
+}
@@ -0,0 +1,65 @@
+use embedded_io_adapters::tokio_1::FromTokio;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::bridge::{retry, session, BridgeConfig};
+use crate::config::BrokerConfig;
+use crate::distributor::Distributor;
+use crate::errors::BridgeError;
+use crate::log::{info, warn};
+use crate::socket::{serve_connection, ConnectionConfig};
+
+/// Same as [`crate::socket::listen`] but serves clients on a TCP socket of the host
+/// every slot of the distributor runs one of these tasks on a shared listener
+pub async fn listen<B: BrokerConfig>(listener: &TcpListener, id: usize, distributor: &'static B) {
+    let distributor = Distributor::new(distributor, id);
+    let config = ConnectionConfig::default();
+    distributor.cleanup().await;
+
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("accept error: {:?}", e);
+                continue;
+            }
+        };
+        if let Err(e) = stream.set_nodelay(true) {
+            warn!("SOCKET {}: could not set nodelay {:?}", id, e);
+        }
+        info!("SOCKET {}: Received connection from {}", id, addr);
+        let (reader, writer) = stream.into_split();
+        serve_connection(
+            FromTokio::new(reader),
+            FromTokio::new(writer),
+            &distributor,
+            &config,
+        )
+        .await;
+    }
+}
+
+/// Same as [`crate::bridge::bridge`] but connects to the remote broker with a TCP socket of the
+/// host
+pub async fn bridge<B: BrokerConfig>(id: usize, config: BridgeConfig, distributor: &'static B) {
+    let distributor = Distributor::new(distributor, id);
+    let mut backoff = config.min_backoff;
+
+    loop {
+        distributor.cleanup().await;
+
+        info!("BRIDGE: connecting to {}...", config.remote);
+        let mut connected = false;
+        let result = match TcpStream::connect(config.remote.to_string()).await {
+            Ok(stream) => {
+                let (reader, writer) = stream.into_split();
+                let (reader, writer) = (FromTokio::new(reader), FromTokio::new(writer));
+                session(reader, writer, &config, &distributor, &mut connected).await
+            }
+            Err(_) => Err(BridgeError::ConnectionFailed),
+        };
+        if !retry(result, connected, &mut backoff, &config).await {
+            distributor.cleanup().await;
+            return;
+        }
+    }
+}
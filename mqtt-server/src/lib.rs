@@ -1,14 +1,32 @@
-//#![cfg_attr(not(feature = "std"), no_std)]
-#![no_std]
-//#[cfg(feature = "std")]
-//extern crate core;
+#![cfg_attr(not(feature = "std"), no_std)]
 
+pub mod acceptor;
+#[cfg(feature = "benchmarks")]
+pub mod bench;
+pub mod bridge;
 pub mod codec;
+pub mod control;
 pub mod distributor;
+pub mod federation;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+#[cfg(feature = "std")]
+pub mod host;
+#[cfg(feature = "mqtt-sn")]
+pub mod mqttsn;
+#[cfg(feature = "persistence")]
+pub mod persistence;
 pub mod socket;
-//mod topics;
+pub mod stats;
+pub mod storage;
+mod topics;
 mod bitset;
 pub mod config;
 mod errors;
+#[cfg(any(test, feature = "benchmarks"))]
 mod topics_list;
 mod log;
+#[cfg(all(test, feature = "std"))]
+mod end_to_end;
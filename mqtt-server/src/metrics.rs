@@ -0,0 +1,330 @@
+//! Prometheus endpoint
+//!
+//! [`metrics_server`] is a tiny HTTP/1.1 server that answers `GET /metrics` with the statistics
+//! of the distributor in the OpenMetrics text format, see [`crate::stats`]. It serves one request
+//! per connection and closes it afterwards. Enable the `metrics` feature and spawn it next to the
+//! connection workers:
+//!
+//! ```no_run
+//! #[embassy_executor::task]
+//! async fn metrics_task(stack: &'static Stack<Device>, distributor: &'static Distributor) -> ! {
+//!     metrics_server(stack, distributor, &MetricsConfig::default()).await
+//! }
+//! ```
+use core::fmt::{self, Write as _};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::Stack;
+use embassy_net_driver::Driver;
+use embassy_time::Duration;
+use embedded_io_async::Write;
+use heapless::String;
+
+use crate::config::BrokerConfig;
+use crate::log::{info, warn};
+use crate::stats::{ClientStats, Stats, PACKET_TYPES};
+
+/// Maximum size of the rendered metrics, metrics that do not fit are left out
+pub const BODY_SIZE: usize = 4096;
+const EOF: &str = "# EOF\n";
+/// Requests with longer headers are answered without reading the rest
+const REQUEST_SIZE: usize = 512;
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Name, type and help text of a metric with one value per connected client
+type ClientMetric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&ClientStats) -> usize,
+);
+
+/// Names of the MQTT control packet types, used as label of the packet counters
+const PACKET_NAMES: [&str; PACKET_TYPES] = [
+    "reserved",
+    "connect",
+    "connack",
+    "publish",
+    "puback",
+    "pubrec",
+    "pubrel",
+    "pubcomp",
+    "subscribe",
+    "suback",
+    "unsubscribe",
+    "unsuback",
+    "pingreq",
+    "pingresp",
+    "disconnect",
+    "auth",
+];
+
+pub struct MetricsConfig {
+    pub port: u16,
+    /// connections that do not send a request in time are closed
+    pub timeout: Duration,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            port: 9883,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Serves the metrics of `distributor` forever
+/// N is the amount of sockets of the distributor
+pub async fn metrics_server<T, B: BrokerConfig<Stats = Stats<N>>, const N: usize>(
+    stack: &'static Stack<T>,
+    distributor: &'static B,
+    config: &MetricsConfig,
+) -> !
+where
+    T: Driver,
+{
+    let mut rx_buffer = [0; REQUEST_SIZE];
+    let mut tx_buffer = [0; 1024];
+    let mut body = String::<BODY_SIZE>::new();
+    info!("METRICS: serving on TCP:{}", config.port);
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(config.timeout));
+        if let Err(e) = socket.accept(config.port).await {
+            warn!("METRICS: accept error {:?}", e);
+            continue;
+        }
+        let served = async {
+            match read_request(&mut socket).await? {
+                Request::Metrics => {
+                    body.clear();
+                    if render(&distributor.stats().await, &mut body).is_err() {
+                        warn!("METRICS: not all metrics fit into {} bytes", BODY_SIZE);
+                    }
+                    finish(&mut body);
+                    respond(&mut socket, "200 OK", CONTENT_TYPE, &body).await
+                }
+                Request::NotFound => respond(&mut socket, "404 Not Found", "text/plain", "").await,
+                Request::NotAllowed => {
+                    respond(&mut socket, "405 Method Not Allowed", "text/plain", "").await
+                }
+                Request::Closed => Ok(()),
+            }
+        };
+        if let Err(e) = served.await {
+            warn!("METRICS: {:?}", e);
+        }
+        socket.close();
+        let _ = socket.flush().await;
+    }
+}
+
+enum Request {
+    Metrics,
+    NotFound,
+    NotAllowed,
+    /// the connection was closed before a request was received
+    Closed,
+}
+
+/// reads the request line and the headers, a body is ignored
+async fn read_request(socket: &mut TcpSocket<'_>) -> Result<Request, embassy_net::tcp::Error> {
+    let mut request = [0; REQUEST_SIZE];
+    let mut len = 0;
+    while !request[..len].windows(4).any(|w| w == b"\r\n\r\n") && len < request.len() {
+        let n = socket.read(&mut request[len..]).await?;
+        if n == 0 {
+            return Ok(Request::Closed);
+        }
+        len += n;
+    }
+    let line = request[..len]
+        .split(|b| *b == b'\r')
+        .next()
+        .unwrap_or_default();
+    let mut parts = line.split(|b| *b == b' ');
+    Ok(match (parts.next(), parts.next()) {
+        (Some(b"GET"), Some(path)) => match path.split(|b| *b == b'?').next() {
+            Some(b"/metrics") => Request::Metrics,
+            _ => Request::NotFound,
+        },
+        _ => Request::NotAllowed,
+    })
+}
+
+async fn respond(
+    socket: &mut TcpSocket<'_>,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<(), embassy_net::tcp::Error> {
+    let mut header = String::<160>::new();
+    // the header fits, the status and content types are constant
+    let _ = write!(
+        header,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    socket.write_all(header.as_bytes()).await?;
+    socket.write_all(body.as_bytes()).await
+}
+
+/// cuts `body` after its last complete line that leaves space for the final `# EOF`
+fn finish<const SIZE: usize>(body: &mut String<SIZE>) {
+    let mut end = body.len().min(SIZE - EOF.len());
+    if !body[..end].ends_with('\n') {
+        end = body[..end].rfind('\n').map_or(0, |i| i + 1);
+    }
+    body.truncate(end);
+    // fits, space has been left for it
+    let _ = body.push_str(EOF);
+}
+
+/// writes the metric family header
+fn family(out: &mut impl fmt::Write, name: &str, kind: &str, help: &str) -> fmt::Result {
+    writeln!(out, "# TYPE {} {}", name, kind)?;
+    writeln!(out, "# HELP {} {}", name, help)
+}
+
+/// writes a family with a single sample, counters get the `_total` suffix
+fn metric(
+    out: &mut impl fmt::Write,
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: usize,
+) -> fmt::Result {
+    family(out, name, kind, help)?;
+    let suffix = if kind == "counter" { "_total" } else { "" };
+    writeln!(out, "{}{} {}", name, suffix, value)
+}
+
+/// renders `stats` in the OpenMetrics text format without the final `# EOF`
+/// stops at the first metric that does not fit into `out`
+fn render<const N: usize>(stats: &Stats<N>, out: &mut impl fmt::Write) -> fmt::Result {
+    let traffic = &stats.traffic;
+    #[rustfmt::skip]
+    let single = [
+        ("mqtt_connections_accepted", "counter", "Clients that completed the handshake", stats.connections_accepted as usize),
+        ("mqtt_connections_rejected", "counter", "Clients that have been refused", stats.connections_rejected as usize),
+        ("mqtt_clients_connected", "gauge", "Clients connected right now", stats.connected()),
+        ("mqtt_bytes_received", "counter", "Bytes received from all clients", traffic.bytes_in as usize),
+        ("mqtt_bytes_sent", "counter", "Bytes sent to all clients", traffic.bytes_out as usize),
+        ("mqtt_messages_dropped", "counter", "Messages not delivered to all subscribers", stats.dropped as usize),
+        ("mqtt_queue_used", "gauge", "Messages queued right now", stats.queue_used),
+        ("mqtt_queue_high_water", "gauge", "Most messages queued at the same time", stats.queue_high_water),
+        ("mqtt_queue_capacity", "gauge", "Messages that can be queued", stats.queue_capacity),
+        ("mqtt_subscription_nodes_used", "gauge", "Nodes of the subscription tree in use", stats.tree_used),
+        ("mqtt_subscription_nodes_capacity", "gauge", "Nodes of the subscription tree", stats.tree_capacity),
+    ];
+    for (name, kind, help, value) in single {
+        metric(out, name, kind, help, value)?;
+    }
+
+    for (name, help, packets) in [
+        (
+            "mqtt_packets_received",
+            "Packets received by type",
+            &traffic.packets_in,
+        ),
+        (
+            "mqtt_packets_sent",
+            "Packets sent by type",
+            &traffic.packets_out,
+        ),
+    ] {
+        family(out, name, "counter", help)?;
+        // the reserved type 0 is never sent
+        for (kind, count) in PACKET_NAMES.iter().zip(packets.iter()).skip(1) {
+            writeln!(out, "{}_total{{type=\"{}\"}} {}", name, kind, count)?;
+        }
+    }
+
+    let connected = || {
+        stats
+            .clients
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.connected)
+    };
+    #[rustfmt::skip]
+    let clients: [ClientMetric; 6] = [
+        ("mqtt_client_packets_received", "counter", "Packets received from the client of a socket", |c| c.packets_in as usize),
+        ("mqtt_client_packets_sent", "counter", "Packets sent to the client of a socket", |c| c.packets_out as usize),
+        ("mqtt_client_bytes_received", "counter", "Bytes received from the client of a socket", |c| c.bytes_in as usize),
+        ("mqtt_client_bytes_sent", "counter", "Bytes sent to the client of a socket", |c| c.bytes_out as usize),
+        ("mqtt_client_messages_dropped", "counter", "Messages the client of a socket missed", |c| c.dropped as usize),
+        ("mqtt_client_subscriptions", "gauge", "Topic filters of the client of a socket", |c| c.subscriptions),
+    ];
+    for (name, kind, help, value) in clients {
+        family(out, name, kind, help)?;
+        let suffix = if kind == "counter" { "_total" } else { "" };
+        for (slot, client) in connected() {
+            writeln!(
+                out,
+                "{}{}{{slot=\"{}\"}} {}",
+                name,
+                suffix,
+                slot,
+                value(client)
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::{ClientStats, Traffic};
+
+    #[test]
+    fn test_render() {
+        let mut traffic = Traffic::default();
+        traffic.packets_in[3] = 5;
+        traffic.bytes_out = 120;
+        let mut clients = [ClientStats::default(); 2];
+        clients[1] = ClientStats {
+            connected: true,
+            packets_in: 5,
+            subscriptions: 2,
+            ..Default::default()
+        };
+        let stats = Stats {
+            connections_accepted: 3,
+            connections_rejected: 1,
+            traffic,
+            dropped: 0,
+            queue_used: 1,
+            queue_high_water: 2,
+            queue_capacity: 4,
+            tree_used: 3,
+            tree_capacity: 64,
+            clients,
+        };
+        let mut out = String::<BODY_SIZE>::new();
+        render(&stats, &mut out).unwrap();
+        for line in [
+            "# TYPE mqtt_connections_accepted counter\n",
+            "mqtt_connections_accepted_total 3\n",
+            "mqtt_clients_connected 1\n",
+            "mqtt_bytes_sent_total 120\n",
+            "mqtt_queue_used 1\n",
+            "mqtt_packets_received_total{type=\"publish\"} 5\n",
+            "mqtt_client_subscriptions{slot=\"1\"} 2\n",
+        ] {
+            assert!(out.contains(line), "missing {:?}", line);
+        }
+        // only connected clients are listed
+        assert!(!out.contains("slot=\"0\""));
+
+        // metrics that do not fit are left out
+        let mut small = String::<256>::new();
+        assert!(render(&stats, &mut small).is_err());
+        finish(&mut small);
+        assert!(small.starts_with("# TYPE mqtt_connections_accepted counter\n"));
+        assert!(small.ends_with("\n# EOF\n"));
+    }
+}
@@ -0,0 +1,1473 @@
+//! MQTT-SN v1.2 gateway over UDP
+//!
+//! All MQTT-SN clients share a single distributor slot. The gateway keeps track of which client
+//! subscribed to which topic and translates REGISTER/PUBLISH/SUBSCRIBE into calls on the
+//! [`Distributor`], so MQTT-SN nodes and TCP clients see the same topics.
+use core::num::NonZeroU16;
+use embassy_futures::select::select;
+use embassy_futures::select::Either::{First, Second};
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpEndpoint, Stack};
+use embassy_net_driver::Driver;
+use embassy_time::{with_timeout, Duration, Instant};
+use heapless::{String, Vec};
+use mqtt_format::v5::packets::publish::{MPublish, PublishProperties};
+use mqtt_format::v5::packets::MqttPacket;
+use mqtt_format::v5::qos::QualityOfService;
+use mqtt_format::v5::variable_header::PacketIdentifier;
+
+use crate::config::BrokerConfig;
+use crate::distributor::Distributor;
+use crate::errors::{DistributorError, MqttSnError};
+use crate::log::{info, warn};
+use crate::topics::matches;
+
+/// Maximum size of a single MQTT-SN datagram
+const MAX_PACKET_SIZE: usize = 512;
+/// Maximum length of a client id, as defined by the spec
+const MAX_CLIENT_ID_LENGTH: usize = 23;
+/// How many topic filters a single MQTT-SN client can subscribe to
+const MAX_CLIENT_SUBSCRIPTIONS: usize = 8;
+/// How many normal topic ids the gateway can hand out
+const MAX_REGISTERED_TOPICS: usize = 32;
+/// How many QoS 2 publishes of a single client can wait for their PUBREL
+const MAX_PENDING_RELEASES: usize = 4;
+/// How often the gateway checks for clients that exceeded their keep alive
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+const CONNECT: u8 = 0x04;
+const CONNACK: u8 = 0x05;
+const REGISTER: u8 = 0x0A;
+const REGACK: u8 = 0x0B;
+const PUBLISH: u8 = 0x0C;
+const PUBACK: u8 = 0x0D;
+const PUBCOMP: u8 = 0x0E;
+const PUBREC: u8 = 0x0F;
+const PUBREL: u8 = 0x10;
+const SUBSCRIBE: u8 = 0x12;
+const SUBACK: u8 = 0x13;
+const UNSUBSCRIBE: u8 = 0x14;
+const UNSUBACK: u8 = 0x15;
+const PINGREQ: u8 = 0x16;
+const PINGRESP: u8 = 0x17;
+const DISCONNECT: u8 = 0x18;
+
+const TOPIC_ID_NORMAL: u8 = 0b00;
+const TOPIC_ID_PREDEFINED: u8 = 0b01;
+const TOPIC_ID_SHORT: u8 = 0b10;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SnQos {
+    #[default]
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+    /// QoS -1, publish without being connected
+    NoSession,
+}
+
+impl SnQos {
+    fn quality_of_service(self) -> QualityOfService {
+        match self {
+            SnQos::AtMostOnce | SnQos::NoSession => QualityOfService::AtMostOnce,
+            SnQos::AtLeastOnce => QualityOfService::AtLeastOnce,
+            SnQos::ExactlyOnce => QualityOfService::ExactlyOnce,
+        }
+    }
+}
+
+/// Flags field of CONNECT, PUBLISH, SUBSCRIBE, SUBACK and UNSUBSCRIBE
+/// the topic id type bits are decoded into [`SnTopic`]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Flags {
+    pub dup: bool,
+    pub qos: SnQos,
+    pub retain: bool,
+    pub will: bool,
+    pub clean_session: bool,
+}
+
+impl Flags {
+    fn parse(byte: u8) -> (Self, u8) {
+        let qos = match (byte >> 5) & 0b11 {
+            0 => SnQos::AtMostOnce,
+            1 => SnQos::AtLeastOnce,
+            2 => SnQos::ExactlyOnce,
+            _ => SnQos::NoSession,
+        };
+        let flags = Flags {
+            dup: byte & 0x80 != 0,
+            qos,
+            retain: byte & 0x10 != 0,
+            will: byte & 0x08 != 0,
+            clean_session: byte & 0x04 != 0,
+        };
+        (flags, byte & 0b11)
+    }
+    fn to_byte(self, topic_id_type: u8) -> u8 {
+        let qos = match self.qos {
+            SnQos::AtMostOnce => 0,
+            SnQos::AtLeastOnce => 1,
+            SnQos::ExactlyOnce => 2,
+            SnQos::NoSession => 3,
+        };
+        (self.dup as u8) << 7
+            | qos << 5
+            | (self.retain as u8) << 4
+            | (self.will as u8) << 3
+            | (self.clean_session as u8) << 2
+            | topic_id_type
+    }
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnTopic<'a> {
+    /// topic id handed out with REGISTER/REGACK
+    Normal(u16),
+    /// topic id both the client and the gateway know beforehand
+    Predefined(u16),
+    /// two character topic name
+    Short([u8; 2]),
+    /// full topic name, only valid in SUBSCRIBE and UNSUBSCRIBE
+    Name(&'a str),
+}
+
+impl SnTopic<'_> {
+    fn id_type(&self) -> u8 {
+        match self {
+            SnTopic::Normal(_) | SnTopic::Name(_) => TOPIC_ID_NORMAL,
+            SnTopic::Predefined(_) => TOPIC_ID_PREDEFINED,
+            SnTopic::Short(_) => TOPIC_ID_SHORT,
+        }
+    }
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ReturnCode {
+    Accepted = 0x00,
+    Congestion = 0x01,
+    InvalidTopicId = 0x02,
+    NotSupported = 0x03,
+}
+
+impl ReturnCode {
+    fn parse(byte: u8) -> Result<Self, MqttSnError> {
+        match byte {
+            0x00 => Ok(ReturnCode::Accepted),
+            0x01 => Ok(ReturnCode::Congestion),
+            0x02 => Ok(ReturnCode::InvalidTopicId),
+            0x03 => Ok(ReturnCode::NotSupported),
+            _ => Err(MqttSnError::Invalid),
+        }
+    }
+}
+
+impl From<DistributorError> for ReturnCode {
+    fn from(e: DistributorError) -> Self {
+        match e {
+            DistributorError::TopicTooLong | DistributorError::InvalidTopicFilter => {
+                ReturnCode::InvalidTopicId
+            }
+            DistributorError::QueueFull => ReturnCode::Congestion,
+            _ => ReturnCode::NotSupported,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SnPacket<'a> {
+    Connect {
+        flags: Flags,
+        duration: u16,
+        client_id: &'a str,
+    },
+    Connack {
+        return_code: ReturnCode,
+    },
+    Register {
+        topic_id: u16,
+        msg_id: u16,
+        topic_name: &'a str,
+    },
+    Regack {
+        topic_id: u16,
+        msg_id: u16,
+        return_code: ReturnCode,
+    },
+    Publish {
+        flags: Flags,
+        topic: SnTopic<'a>,
+        msg_id: u16,
+        data: &'a [u8],
+    },
+    Puback {
+        topic_id: u16,
+        msg_id: u16,
+        return_code: ReturnCode,
+    },
+    Pubrec {
+        msg_id: u16,
+    },
+    Pubrel {
+        msg_id: u16,
+    },
+    Pubcomp {
+        msg_id: u16,
+    },
+    Subscribe {
+        flags: Flags,
+        msg_id: u16,
+        topic: SnTopic<'a>,
+    },
+    Suback {
+        flags: Flags,
+        topic_id: u16,
+        msg_id: u16,
+        return_code: ReturnCode,
+    },
+    Unsubscribe {
+        flags: Flags,
+        msg_id: u16,
+        topic: SnTopic<'a>,
+    },
+    Unsuback {
+        msg_id: u16,
+    },
+    Pingreq {
+        client_id: Option<&'a str>,
+    },
+    Pingresp,
+    Disconnect {
+        duration: Option<u16>,
+    },
+}
+
+/// Reads the fields of a packet body front to back
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Result<u8, MqttSnError> {
+        let (&byte, rest) = self.buf.split_first().ok_or(MqttSnError::Incomplete)?;
+        self.buf = rest;
+        Ok(byte)
+    }
+    fn u16(&mut self) -> Result<u16, MqttSnError> {
+        Ok(u16::from_be_bytes([self.u8()?, self.u8()?]))
+    }
+    fn rest(&mut self) -> &'a [u8] {
+        core::mem::take(&mut self.buf)
+    }
+    fn str(&mut self) -> Result<&'a str, MqttSnError> {
+        core::str::from_utf8(self.rest()).map_err(|_| MqttSnError::Invalid)
+    }
+    fn topic(&mut self, id_type: u8, allow_name: bool) -> Result<SnTopic<'a>, MqttSnError> {
+        match id_type {
+            TOPIC_ID_NORMAL if allow_name => Ok(SnTopic::Name(self.str()?)),
+            TOPIC_ID_NORMAL => Ok(SnTopic::Normal(self.u16()?)),
+            TOPIC_ID_PREDEFINED => Ok(SnTopic::Predefined(self.u16()?)),
+            TOPIC_ID_SHORT => Ok(SnTopic::Short([self.u8()?, self.u8()?])),
+            _ => Err(MqttSnError::Invalid),
+        }
+    }
+}
+
+/// Writes the fields of a packet body into a buffer
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn u8(&mut self, byte: u8) -> Result<(), MqttSnError> {
+        self.slice(&[byte])
+    }
+    fn u16(&mut self, value: u16) -> Result<(), MqttSnError> {
+        self.slice(&value.to_be_bytes())
+    }
+    fn slice(&mut self, data: &[u8]) -> Result<(), MqttSnError> {
+        let end = self.pos + data.len();
+        if end > self.buf.len() {
+            return Err(MqttSnError::BufferTooSmall);
+        }
+        self.buf[self.pos..end].copy_from_slice(data);
+        self.pos = end;
+        Ok(())
+    }
+    fn topic(&mut self, topic: &SnTopic) -> Result<(), MqttSnError> {
+        match topic {
+            SnTopic::Normal(id) | SnTopic::Predefined(id) => self.u16(*id),
+            SnTopic::Short(name) => self.slice(name),
+            SnTopic::Name(name) => self.slice(name.as_bytes()),
+        }
+    }
+}
+
+impl<'a> SnPacket<'a> {
+    pub fn parse(buf: &'a [u8]) -> Result<Self, MqttSnError> {
+        let (len, header_len) = match buf.first() {
+            None => return Err(MqttSnError::Incomplete),
+            // three byte length field
+            Some(0x01) => {
+                if buf.len() < 3 {
+                    return Err(MqttSnError::Incomplete);
+                }
+                (u16::from_be_bytes([buf[1], buf[2]]) as usize, 3)
+            }
+            Some(&len) => (len as usize, 1),
+        };
+        if len <= header_len {
+            return Err(MqttSnError::InvalidLength);
+        }
+        if len > buf.len() {
+            return Err(MqttSnError::Incomplete);
+        }
+        let msg_type = buf[header_len];
+        let mut r = Reader {
+            buf: &buf[header_len + 1..len],
+        };
+
+        let packet = match msg_type {
+            CONNECT => {
+                let (flags, _) = Flags::parse(r.u8()?);
+                let _protocol_id = r.u8()?;
+                SnPacket::Connect {
+                    flags,
+                    duration: r.u16()?,
+                    client_id: r.str()?,
+                }
+            }
+            CONNACK => SnPacket::Connack {
+                return_code: ReturnCode::parse(r.u8()?)?,
+            },
+            REGISTER => SnPacket::Register {
+                topic_id: r.u16()?,
+                msg_id: r.u16()?,
+                topic_name: r.str()?,
+            },
+            REGACK => SnPacket::Regack {
+                topic_id: r.u16()?,
+                msg_id: r.u16()?,
+                return_code: ReturnCode::parse(r.u8()?)?,
+            },
+            PUBLISH => {
+                let (flags, id_type) = Flags::parse(r.u8()?);
+                SnPacket::Publish {
+                    flags,
+                    topic: r.topic(id_type, false)?,
+                    msg_id: r.u16()?,
+                    data: r.rest(),
+                }
+            }
+            PUBACK => SnPacket::Puback {
+                topic_id: r.u16()?,
+                msg_id: r.u16()?,
+                return_code: ReturnCode::parse(r.u8()?)?,
+            },
+            PUBREC => SnPacket::Pubrec { msg_id: r.u16()? },
+            PUBREL => SnPacket::Pubrel { msg_id: r.u16()? },
+            PUBCOMP => SnPacket::Pubcomp { msg_id: r.u16()? },
+            SUBSCRIBE => {
+                let (flags, id_type) = Flags::parse(r.u8()?);
+                SnPacket::Subscribe {
+                    flags,
+                    msg_id: r.u16()?,
+                    topic: r.topic(id_type, true)?,
+                }
+            }
+            SUBACK => {
+                let (flags, _) = Flags::parse(r.u8()?);
+                SnPacket::Suback {
+                    flags,
+                    topic_id: r.u16()?,
+                    msg_id: r.u16()?,
+                    return_code: ReturnCode::parse(r.u8()?)?,
+                }
+            }
+            UNSUBSCRIBE => {
+                let (flags, id_type) = Flags::parse(r.u8()?);
+                SnPacket::Unsubscribe {
+                    flags,
+                    msg_id: r.u16()?,
+                    topic: r.topic(id_type, true)?,
+                }
+            }
+            UNSUBACK => SnPacket::Unsuback { msg_id: r.u16()? },
+            PINGREQ => {
+                let client_id = r.str()?;
+                SnPacket::Pingreq {
+                    client_id: (!client_id.is_empty()).then_some(client_id),
+                }
+            }
+            PINGRESP => SnPacket::Pingresp,
+            DISCONNECT => SnPacket::Disconnect {
+                duration: if r.buf.is_empty() {
+                    None
+                } else {
+                    Some(r.u16()?)
+                },
+            },
+            _ => return Err(MqttSnError::UnknownPacket),
+        };
+        Ok(packet)
+    }
+
+    /// encodes the packet into `buf` and returns the amount of bytes written
+    pub fn write(&self, buf: &mut [u8]) -> Result<usize, MqttSnError> {
+        if buf.len() < 2 {
+            return Err(MqttSnError::BufferTooSmall);
+        }
+        // leave room for the short length field and the message type, the body is moved if the
+        // packet needs the three byte length field
+        let mut w = Writer { buf, pos: 2 };
+        let msg_type = match *self {
+            SnPacket::Connect {
+                flags,
+                duration,
+                client_id,
+            } => {
+                w.u8(flags.to_byte(0))?;
+                w.u8(0x01)?;
+                w.u16(duration)?;
+                w.slice(client_id.as_bytes())?;
+                CONNECT
+            }
+            SnPacket::Connack { return_code } => {
+                w.u8(return_code as u8)?;
+                CONNACK
+            }
+            SnPacket::Register {
+                topic_id,
+                msg_id,
+                topic_name,
+            } => {
+                w.u16(topic_id)?;
+                w.u16(msg_id)?;
+                w.slice(topic_name.as_bytes())?;
+                REGISTER
+            }
+            SnPacket::Regack {
+                topic_id,
+                msg_id,
+                return_code,
+            } => {
+                w.u16(topic_id)?;
+                w.u16(msg_id)?;
+                w.u8(return_code as u8)?;
+                REGACK
+            }
+            SnPacket::Publish {
+                flags,
+                ref topic,
+                msg_id,
+                data,
+            } => {
+                if let SnTopic::Name(_) = topic {
+                    return Err(MqttSnError::Invalid);
+                }
+                w.u8(flags.to_byte(topic.id_type()))?;
+                w.topic(topic)?;
+                w.u16(msg_id)?;
+                w.slice(data)?;
+                PUBLISH
+            }
+            SnPacket::Puback {
+                topic_id,
+                msg_id,
+                return_code,
+            } => {
+                w.u16(topic_id)?;
+                w.u16(msg_id)?;
+                w.u8(return_code as u8)?;
+                PUBACK
+            }
+            SnPacket::Pubrec { msg_id } => {
+                w.u16(msg_id)?;
+                PUBREC
+            }
+            SnPacket::Pubrel { msg_id } => {
+                w.u16(msg_id)?;
+                PUBREL
+            }
+            SnPacket::Pubcomp { msg_id } => {
+                w.u16(msg_id)?;
+                PUBCOMP
+            }
+            SnPacket::Subscribe {
+                flags,
+                msg_id,
+                ref topic,
+            } => {
+                w.u8(flags.to_byte(topic.id_type()))?;
+                w.u16(msg_id)?;
+                w.topic(topic)?;
+                SUBSCRIBE
+            }
+            SnPacket::Suback {
+                flags,
+                topic_id,
+                msg_id,
+                return_code,
+            } => {
+                w.u8(flags.to_byte(0))?;
+                w.u16(topic_id)?;
+                w.u16(msg_id)?;
+                w.u8(return_code as u8)?;
+                SUBACK
+            }
+            SnPacket::Unsubscribe {
+                flags,
+                msg_id,
+                ref topic,
+            } => {
+                w.u8(flags.to_byte(topic.id_type()))?;
+                w.u16(msg_id)?;
+                w.topic(topic)?;
+                UNSUBSCRIBE
+            }
+            SnPacket::Unsuback { msg_id } => {
+                w.u16(msg_id)?;
+                UNSUBACK
+            }
+            SnPacket::Pingreq { client_id } => {
+                if let Some(client_id) = client_id {
+                    w.slice(client_id.as_bytes())?;
+                }
+                PINGREQ
+            }
+            SnPacket::Pingresp => PINGRESP,
+            SnPacket::Disconnect { duration } => {
+                if let Some(duration) = duration {
+                    w.u16(duration)?;
+                }
+                DISCONNECT
+            }
+        };
+        let body_len = w.pos - 2;
+        let buf = w.buf;
+        if body_len + 2 <= 0xFF {
+            buf[0] = (body_len + 2) as u8;
+            buf[1] = msg_type;
+            return Ok(body_len + 2);
+        }
+        let len = body_len + 4;
+        if len > buf.len() || len > u16::MAX as usize {
+            return Err(MqttSnError::BufferTooSmall);
+        }
+        buf.copy_within(2..2 + body_len, 4);
+        buf[0] = 0x01;
+        buf[1..3].copy_from_slice(&(len as u16).to_be_bytes());
+        buf[3] = msg_type;
+        Ok(len)
+    }
+}
+
+/// Settings of the MQTT-SN gateway
+pub struct GatewayConfig {
+    /// UDP port to listen on, MQTT-SN commonly uses 1884
+    pub port: u16,
+    /// topic ids known to the clients without registering them, e.g. `&[(1, "sensors/temp")]`
+    pub predefined_topics: &'static [(u16, &'static str)],
+}
+
+struct SnClient<B: BrokerConfig> {
+    endpoint: IpEndpoint,
+    client_id: String<MAX_CLIENT_ID_LENGTH>,
+    keep_alive: Duration,
+    last_seen: Instant,
+    subscriptions: Vec<B::Topic, MAX_CLIENT_SUBSCRIPTIONS>,
+    /// normal topic ids this client already knows
+    registered: Vec<u16, MAX_REGISTERED_TOPICS>,
+    /// msg ids of QoS 2 publishes that have been published but not released yet
+    pending_releases: Vec<u16, MAX_PENDING_RELEASES>,
+    next_msg_id: u16,
+}
+
+impl<B: BrokerConfig> SnClient<B> {
+    fn next_msg_id(&mut self) -> u16 {
+        self.next_msg_id = self.next_msg_id.wrapping_add(1).max(1);
+        self.next_msg_id
+    }
+}
+
+struct Gateway<B: BrokerConfig, const CLIENTS: usize> {
+    clients: Vec<SnClient<B>, CLIENTS>,
+    /// topic names registered by the gateway, the topic id is the index + 1
+    topics: Vec<B::Topic, MAX_REGISTERED_TOPICS>,
+    predefined: &'static [(u16, &'static str)],
+}
+
+impl<B: BrokerConfig, const CLIENTS: usize> Gateway<B, CLIENTS> {
+    fn new(predefined: &'static [(u16, &'static str)]) -> Self {
+        Self {
+            clients: Vec::new(),
+            topics: Vec::new(),
+            predefined,
+        }
+    }
+
+    fn client(&self, endpoint: IpEndpoint) -> Option<usize> {
+        self.clients.iter().position(|c| c.endpoint == endpoint)
+    }
+
+    /// looks up or registers a topic name and returns its normal topic id
+    fn register_topic(&mut self, name: &str) -> Option<u16> {
+        if let Some(i) = self.topics.iter().position(|t| &**t == name) {
+            return Some(i as u16 + 1);
+        }
+        let topic = B::Topic::try_from(name).ok()?;
+        self.topics.push(topic).ok()?;
+        Some(self.topics.len() as u16)
+    }
+
+    fn topic_name<'a>(&'a self, topic: &'a SnTopic<'a>) -> Option<&'a str> {
+        match topic {
+            SnTopic::Normal(id) => self
+                .topics
+                .get((*id as usize).checked_sub(1)?)
+                .map(|t| &**t),
+            SnTopic::Predefined(id) => self
+                .predefined
+                .iter()
+                .find(|(i, _)| i == id)
+                .map(|(_, name)| *name),
+            SnTopic::Short(name) => core::str::from_utf8(name).ok(),
+            SnTopic::Name(name) => Some(*name),
+        }
+    }
+
+    async fn remove_client(&mut self, index: usize, distributor: &Distributor<B>) {
+        let client = self.clients.swap_remove(index);
+        info!("MQTT-SN: client {} removed", client.client_id.as_str());
+        for subscription in client.subscriptions.iter() {
+            self.release_subscription(subscription, distributor).await;
+        }
+    }
+
+    /// unsubscribes the gateway slot if no client is interested in the topic anymore
+    async fn release_subscription(&self, topic: &str, distributor: &Distributor<B>) {
+        let still_used = self
+            .clients
+            .iter()
+            .any(|c| c.subscriptions.iter().any(|s| &**s == topic));
+        if !still_used {
+            distributor.unsubscribe(topic).await;
+        }
+    }
+
+    async fn expire(&mut self, now: Instant, distributor: &Distributor<B>) {
+        let mut i = 0;
+        while i < self.clients.len() {
+            let client = &self.clients[i];
+            // clients get 1.5 times their keep alive period before they are dropped
+            let grace = client.keep_alive + client.keep_alive / 2;
+            if client.keep_alive.as_ticks() != 0 && now - client.last_seen > grace {
+                info!(
+                    "MQTT-SN: client {} keep alive expired",
+                    client.client_id.as_str()
+                );
+                self.remove_client(i, distributor).await;
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// processes a packet received from `from` and returns the answer for the client
+    async fn handle(
+        &mut self,
+        from: IpEndpoint,
+        packet: SnPacket<'_>,
+        distributor: &Distributor<B>,
+        now: Instant,
+    ) -> Option<SnPacket<'static>> {
+        let index = self.client(from);
+        if let Some(i) = index {
+            self.clients[i].last_seen = now;
+        }
+
+        match (packet, index) {
+            (
+                SnPacket::Connect {
+                    flags,
+                    duration,
+                    client_id,
+                },
+                _,
+            ) => Some(SnPacket::Connack {
+                return_code: self
+                    .connect(from, flags, duration, client_id, distributor, now)
+                    .await,
+            }),
+            (
+                SnPacket::Register {
+                    msg_id, topic_name, ..
+                },
+                Some(i),
+            ) => {
+                let (topic_id, return_code) = match self.register_topic(topic_name) {
+                    Some(id) => {
+                        // the client may register the same topic twice
+                        let registered = &mut self.clients[i].registered;
+                        if !registered.contains(&id) && registered.push(id).is_err() {
+                            (0, ReturnCode::Congestion)
+                        } else {
+                            (id, ReturnCode::Accepted)
+                        }
+                    }
+                    None => (0, ReturnCode::Congestion),
+                };
+                Some(SnPacket::Regack {
+                    topic_id,
+                    msg_id,
+                    return_code,
+                })
+            }
+            (
+                SnPacket::Publish {
+                    flags,
+                    topic,
+                    msg_id,
+                    data,
+                },
+                index,
+            ) => {
+                // QoS -1 is the only way to publish without connecting
+                if index.is_none() && flags.qos != SnQos::NoSession {
+                    return None;
+                }
+                if index.is_none() && matches!(topic, SnTopic::Normal(_)) {
+                    return None;
+                }
+                let topic_id = match topic {
+                    SnTopic::Normal(id) | SnTopic::Predefined(id) => id,
+                    SnTopic::Short(name) => u16::from_be_bytes(name),
+                    SnTopic::Name(_) => 0,
+                };
+                let exactly_once = flags.qos == SnQos::ExactlyOnce;
+                let pending = index.map(|i| &self.clients[i].pending_releases);
+                let return_code = match self.topic_name(&topic) {
+                    // a retransmitted QoS 2 publish is only published once, till its PUBREL
+                    Some(_) if exactly_once && pending.is_some_and(|p| p.contains(&msg_id)) => {
+                        ReturnCode::Accepted
+                    }
+                    Some(_) if exactly_once && pending.is_some_and(|p| p.is_full()) => {
+                        ReturnCode::Congestion
+                    }
+                    Some(topic_name) => {
+                        let quality_of_service = flags.qos.quality_of_service();
+                        let packet_identifier = match quality_of_service {
+                            QualityOfService::AtMostOnce => None,
+                            _ => NonZeroU16::new(msg_id).map(PacketIdentifier),
+                        };
+                        let publish = MPublish {
+                            duplicate: false,
+                            quality_of_service,
+                            retain: flags.retain,
+                            topic_name,
+                            packet_identifier,
+                            properties: PublishProperties::new(),
+                            payload: data,
+                        };
+                        match distributor.publish(topic_name, &publish).await {
+                            Ok(()) => ReturnCode::Accepted,
+                            Err(e) => e.into(),
+                        }
+                    }
+                    None => ReturnCode::InvalidTopicId,
+                };
+                if exactly_once && return_code == ReturnCode::Accepted {
+                    if let Some(i) = index {
+                        let pending = &mut self.clients[i].pending_releases;
+                        if !pending.contains(&msg_id) {
+                            // there is room, a full list is answered with congestion above
+                            let _ = pending.push(msg_id);
+                        }
+                    }
+                }
+                match flags.qos {
+                    SnQos::AtLeastOnce => Some(SnPacket::Puback {
+                        topic_id,
+                        msg_id,
+                        return_code,
+                    }),
+                    SnQos::ExactlyOnce if return_code == ReturnCode::Accepted => {
+                        Some(SnPacket::Pubrec { msg_id })
+                    }
+                    SnQos::ExactlyOnce => Some(SnPacket::Puback {
+                        topic_id,
+                        msg_id,
+                        return_code,
+                    }),
+                    // the client is only informed about unknown topic ids
+                    SnQos::AtMostOnce if return_code == ReturnCode::InvalidTopicId => {
+                        Some(SnPacket::Puback {
+                            topic_id,
+                            msg_id,
+                            return_code,
+                        })
+                    }
+                    _ => None,
+                }
+            }
+            (SnPacket::Pubrel { msg_id }, Some(i)) => {
+                self.clients[i].pending_releases.retain(|id| *id != msg_id);
+                Some(SnPacket::Pubcomp { msg_id })
+            }
+            (SnPacket::Subscribe { msg_id, topic, .. }, Some(i)) => {
+                let (topic_id, return_code) = self.subscribe(i, &topic, distributor).await;
+                Some(SnPacket::Suback {
+                    flags: Flags::default(),
+                    topic_id,
+                    msg_id,
+                    return_code,
+                })
+            }
+            (SnPacket::Unsubscribe { msg_id, topic, .. }, Some(i)) => {
+                let name = self
+                    .topic_name(&topic)
+                    .and_then(|t| B::Topic::try_from(t).ok());
+                if let Some(name) = name {
+                    self.clients[i].subscriptions.retain(|s| *s != name);
+                    self.release_subscription(&name, distributor).await;
+                }
+                Some(SnPacket::Unsuback { msg_id })
+            }
+            (SnPacket::Pingreq { .. }, Some(_)) => Some(SnPacket::Pingresp),
+            (SnPacket::Disconnect { .. }, Some(i)) => {
+                // sleeping clients are not supported, a DISCONNECT with duration ends the session
+                self.remove_client(i, distributor).await;
+                Some(SnPacket::Disconnect { duration: None })
+            }
+            (SnPacket::Puback { .. } | SnPacket::Regack { .. }, Some(_)) => None,
+            (packet, Some(_)) => {
+                #[cfg(feature = "defmt")]
+                let packet = ();
+                warn!("MQTT-SN: unexpected packet {:?}", packet);
+                None
+            }
+            (_, None) => {
+                warn!("MQTT-SN: packet from unknown client {}", from);
+                None
+            }
+        }
+    }
+
+    async fn connect(
+        &mut self,
+        from: IpEndpoint,
+        flags: Flags,
+        duration: u16,
+        client_id: &str,
+        distributor: &Distributor<B>,
+        now: Instant,
+    ) -> ReturnCode {
+        if flags.will {
+            // the WILLTOPICREQ/WILLMSGREQ exchange is not implemented
+            return ReturnCode::NotSupported;
+        }
+        let Ok(client_id) = String::try_from(client_id) else {
+            return ReturnCode::NotSupported;
+        };
+        // a reconnecting client replaces its previous session
+        if let Some(i) = self
+            .clients
+            .iter()
+            .position(|c| c.endpoint == from || c.client_id == client_id)
+        {
+            if flags.clean_session {
+                self.remove_client(i, distributor).await;
+            } else {
+                let client = &mut self.clients[i];
+                client.endpoint = from;
+                client.keep_alive = Duration::from_secs(duration as u64);
+                client.last_seen = now;
+                // topic ids are not kept over reconnects
+                client.registered.clear();
+                return ReturnCode::Accepted;
+            }
+        }
+        let client = SnClient {
+            endpoint: from,
+            client_id,
+            keep_alive: Duration::from_secs(duration as u64),
+            last_seen: now,
+            subscriptions: Vec::new(),
+            registered: Vec::new(),
+            pending_releases: Vec::new(),
+            next_msg_id: 0,
+        };
+        match self.clients.push(client) {
+            Ok(()) => {
+                info!("MQTT-SN: client connected from {}", from);
+                ReturnCode::Accepted
+            }
+            Err(_) => ReturnCode::Congestion,
+        }
+    }
+
+    /// subscribes client `i` and returns the topic id for the SUBACK
+    async fn subscribe(
+        &mut self,
+        i: usize,
+        topic: &SnTopic<'_>,
+        distributor: &Distributor<B>,
+    ) -> (u16, ReturnCode) {
+        let Some(name) = self
+            .topic_name(topic)
+            .and_then(|t| B::Topic::try_from(t).ok())
+        else {
+            return (0, ReturnCode::InvalidTopicId);
+        };
+        // wildcard subscriptions get their topic ids registered when a publish is delivered
+        let topic_id = match topic {
+            SnTopic::Name(name) if !name.contains(['+', '#']) => match self.register_topic(name) {
+                Some(id) => {
+                    let _ = self.clients[i].registered.push(id);
+                    id
+                }
+                None => return (0, ReturnCode::Congestion),
+            },
+            SnTopic::Predefined(id) => *id,
+            _ => 0,
+        };
+        if let Err(e) = distributor.subscribe(&name).await {
+            return (0, e.into());
+        }
+        let subscriptions = &mut self.clients[i].subscriptions;
+        if !subscriptions.contains(&name) && subscriptions.push(name.clone()).is_err() {
+            self.release_subscription(&name, distributor).await;
+            return (0, ReturnCode::Congestion);
+        }
+        (topic_id, ReturnCode::Accepted)
+    }
+
+    /// sends a publish received from the distributor to all interested MQTT-SN clients
+    async fn deliver(&mut self, socket: &impl Transmit, tx: &mut [u8], message: &[u8]) {
+        let publish = match MqttPacket::parse_complete(message) {
+            Ok(MqttPacket::Publish(publish)) => publish,
+            _ => return,
+        };
+        let topic_name = publish.topic_name;
+        for i in 0..self.clients.len() {
+            let interested = self.clients[i]
+                .subscriptions
+                .iter()
+                .any(|s| matches(s, topic_name));
+            if !interested {
+                continue;
+            }
+            let topic = if topic_name.len() == 2 {
+                let name = topic_name.as_bytes();
+                SnTopic::Short([name[0], name[1]])
+            } else if let Some((id, _)) = self.predefined.iter().find(|(_, t)| *t == topic_name) {
+                SnTopic::Predefined(*id)
+            } else {
+                let Some(topic_id) = self.register_topic(topic_name) else {
+                    warn!("MQTT-SN: no topic id left for {}", topic_name);
+                    continue;
+                };
+                let client = &mut self.clients[i];
+                if !client.registered.contains(&topic_id) {
+                    if client.registered.push(topic_id).is_err() {
+                        warn!("MQTT-SN: client {} knows too many topics", i);
+                        continue;
+                    }
+                    let register = SnPacket::Register {
+                        topic_id,
+                        msg_id: client.next_msg_id(),
+                        topic_name,
+                    };
+                    send(socket, tx, &register, client.endpoint).await;
+                }
+                SnTopic::Normal(topic_id)
+            };
+            let packet = SnPacket::Publish {
+                flags: Flags::default(),
+                topic,
+                msg_id: 0,
+                data: publish.payload,
+            };
+            send(socket, tx, &packet, self.clients[i].endpoint).await;
+        }
+    }
+}
+
+/// Sends the datagrams of the gateway, the tests replace the UDP socket with a fake one
+trait Transmit {
+    async fn send_to(&self, datagram: &[u8], to: IpEndpoint);
+}
+
+impl Transmit for UdpSocket<'_> {
+    async fn send_to(&self, datagram: &[u8], to: IpEndpoint) {
+        if let Err(e) = UdpSocket::send_to(self, datagram, to).await {
+            warn!("MQTT-SN: could not send to {}: {:?}", to, e);
+        }
+    }
+}
+
+async fn send(socket: &impl Transmit, tx: &mut [u8], packet: &SnPacket<'_>, to: IpEndpoint) {
+    let len = match packet.write(tx) {
+        Ok(len) => len,
+        Err(e) => {
+            warn!("MQTT-SN: could not encode packet {:?}", e);
+            return;
+        }
+    };
+    socket.send_to(&tx[..len], to).await;
+}
+
+/// Runs a MQTT-SN gateway on `config.port` using distributor slot `id`
+/// `CLIENTS` sets how many MQTT-SN clients can be connected at the same time
+pub async fn mqttsn_gateway<T, B: BrokerConfig, const CLIENTS: usize>(
+    stack: &'static Stack<T>,
+    id: usize,
+    config: GatewayConfig,
+    distributor: &'static B,
+) where
+    T: Driver,
+{
+    let mut rx_meta = [PacketMetadata::EMPTY; 8];
+    let mut tx_meta = [PacketMetadata::EMPTY; 8];
+    let mut rx_buffer = [0; 1600];
+    let mut tx_buffer = [0; 1600];
+    let mut rx = [0; MAX_PACKET_SIZE];
+    let mut tx = [0; MAX_PACKET_SIZE];
+    let distributor = Distributor::new(distributor, id);
+    distributor.cleanup().await;
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(config.port) {
+        warn!("MQTT-SN: could not bind UDP:{} {:?}", config.port, e);
+        return;
+    }
+    info!("MQTT-SN: Listening on UDP:{}...", config.port);
+
+    let mut gateway = Gateway::<B, CLIENTS>::new(config.predefined_topics);
+    loop {
+        // unlock after processing packet
+        distributor.unlock().await;
+        let received = with_timeout(EXPIRY_CHECK_INTERVAL, socket.recv_from(&mut rx));
+        match select(distributor.next(), distributor.lock(received)).await {
+            First(Ok(msg)) => gateway.deliver(&socket, &mut tx, msg.message()).await,
+            First(Err(e)) => warn!("MQTT-SN: clients missed messages {:?}", e),
+            Second(Ok(Ok((n, from)))) => match SnPacket::parse(&rx[..n]) {
+                Ok(packet) => {
+                    if let Some(answer) = gateway
+                        .handle(from, packet, &distributor, Instant::now())
+                        .await
+                    {
+                        send(&socket, &mut tx, &answer, from).await;
+                    }
+                }
+                Err(e) => warn!("MQTT-SN: invalid packet from {}: {:?}", from, e),
+            },
+            Second(Ok(Err(e))) => warn!("MQTT-SN: receive error {:?}", e),
+            // nothing received, only check keep alive
+            Second(Err(_)) => {}
+        }
+        gateway.expire(Instant::now(), &distributor).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::InnerDistributorMutex;
+    use crate::distributor::InnerDistributor;
+    use core::cell::RefCell;
+    use embassy_futures::block_on;
+    use embassy_net::IpAddress;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use heapless::Deque;
+    use static_cell::make_static;
+
+    type Broker = InnerDistributorMutex<NoopRawMutex, 2>;
+
+    const PREDEFINED: &[(u16, &str)] = &[(5, "alarms/fire")];
+
+    fn sensor() -> IpEndpoint {
+        IpEndpoint::new(IpAddress::v4(192, 168, 0, 2), 50000)
+    }
+
+    /// Keeps the datagrams sent by the gateway
+    #[derive(Default)]
+    struct FakeSocket {
+        sent: RefCell<Deque<(IpEndpoint, Vec<u8, MAX_PACKET_SIZE>), 4>>,
+    }
+
+    impl Transmit for FakeSocket {
+        async fn send_to(&self, datagram: &[u8], to: IpEndpoint) {
+            let datagram = Vec::from_slice(datagram).unwrap();
+            self.sent.borrow_mut().push_back((to, datagram)).unwrap();
+        }
+    }
+
+    /// Gateway on slot 0 of a broker, datagrams pass through the codec like in `mqttsn_gateway`
+    struct Exchange {
+        broker: &'static Broker,
+        gateway: Gateway<Broker, 2>,
+        distributor: Distributor<Broker>,
+        socket: FakeSocket,
+    }
+
+    impl Exchange {
+        fn new(broker: &'static Broker) -> Self {
+            Self {
+                broker,
+                gateway: Gateway::new(PREDEFINED),
+                distributor: Distributor::new(broker, 0),
+                socket: FakeSocket::default(),
+            }
+        }
+
+        /// lets the gateway handle `packet` sent by `from`
+        fn send(&mut self, from: IpEndpoint, packet: SnPacket<'_>) {
+            let mut rx = [0; MAX_PACKET_SIZE];
+            let mut tx = [0; MAX_PACKET_SIZE];
+            let len = packet.write(&mut rx).unwrap();
+            let packet = SnPacket::parse(&rx[..len]).unwrap();
+            let now = Instant::from_secs(0);
+            block_on(async {
+                let answer = self.gateway.handle(from, packet, &self.distributor, now);
+                if let Some(answer) = answer.await {
+                    send(&self.socket, &mut tx, &answer, from).await;
+                }
+            });
+        }
+
+        /// hands the next message of the distributor to the gateway
+        fn deliver(&mut self) {
+            let mut tx = [0; MAX_PACKET_SIZE];
+            block_on(async {
+                let message = self.distributor.next().await.unwrap();
+                let socket = &self.socket;
+                self.gateway
+                    .deliver(socket, &mut tx, message.message())
+                    .await;
+            });
+        }
+
+        /// asserts that the next datagram is `expected` sent to `to`
+        fn expect(&self, to: IpEndpoint, expected: SnPacket<'_>) {
+            let (endpoint, datagram) = self.socket.sent.borrow_mut().pop_front().unwrap();
+            assert_eq!(endpoint, to);
+            assert_eq!(SnPacket::parse(&datagram).unwrap(), expected);
+        }
+
+        fn expect_silence(&self) {
+            assert!(self.socket.sent.borrow().is_empty());
+        }
+
+        fn connect(&mut self, from: IpEndpoint, client_id: &str) {
+            let flags = Flags {
+                clean_session: true,
+                ..Flags::default()
+            };
+            let connect = SnPacket::Connect {
+                flags,
+                duration: 60,
+                client_id,
+            };
+            self.send(from, connect);
+            let return_code = ReturnCode::Accepted;
+            self.expect(from, SnPacket::Connack { return_code });
+        }
+
+        fn publish(&mut self, from: IpEndpoint, qos: SnQos, topic: SnTopic<'_>, data: &[u8]) {
+            let flags = Flags {
+                qos,
+                ..Flags::default()
+            };
+            let msg_id = match qos {
+                SnQos::AtLeastOnce | SnQos::ExactlyOnce => 1,
+                _ => 0,
+            };
+            let publish = SnPacket::Publish {
+                flags,
+                topic,
+                msg_id,
+                data,
+            };
+            self.send(from, publish);
+        }
+
+        fn subscriptions(&self) -> usize {
+            let mut subscriptions = 0;
+            let inner = block_on(self.broker.lock());
+            inner.subscriptions(0, |_| subscriptions += 1);
+            subscriptions
+        }
+    }
+
+    /// asserts that the TCP client on `observer` receives the publish
+    fn expect_publish(observer: &Distributor<Broker>, topic: &str, payload: &[u8]) {
+        let message = block_on(observer.next()).unwrap();
+        match MqttPacket::parse_complete(message.message()) {
+            Ok(MqttPacket::Publish(publish)) => {
+                assert_eq!(publish.topic_name, topic);
+                assert_eq!(publish.payload, payload);
+            }
+            _ => panic!("expected publish"),
+        }
+    }
+
+    fn round_trip(packet: SnPacket) {
+        let mut buf = [0; 512];
+        let len = packet.write(&mut buf).unwrap();
+        assert_eq!(SnPacket::parse(&buf[..len]).unwrap(), packet);
+    }
+
+    #[test]
+    fn test_parse_publish() {
+        // QoS 1, short topic "ab", message id 7, payload "hi"
+        let data = [0x09, PUBLISH, 0x22, b'a', b'b', 0x00, 0x07, b'h', b'i'];
+        let packet = SnPacket::parse(&data).unwrap();
+        assert_eq!(
+            packet,
+            SnPacket::Publish {
+                flags: Flags {
+                    qos: SnQos::AtLeastOnce,
+                    ..Flags::default()
+                },
+                topic: SnTopic::Short(*b"ab"),
+                msg_id: 7,
+                data: b"hi",
+            }
+        );
+        assert!(SnPacket::parse(&data[..5]).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(SnPacket::Connect {
+            flags: Flags {
+                clean_session: true,
+                ..Flags::default()
+            },
+            duration: 60,
+            client_id: "sensor",
+        });
+        round_trip(SnPacket::Register {
+            topic_id: 3,
+            msg_id: 1,
+            topic_name: "a/b/c",
+        });
+        round_trip(SnPacket::Publish {
+            flags: Flags {
+                qos: SnQos::NoSession,
+                ..Flags::default()
+            },
+            topic: SnTopic::Predefined(12),
+            msg_id: 0,
+            data: &[1, 2, 3],
+        });
+        round_trip(SnPacket::Subscribe {
+            flags: Flags::default(),
+            msg_id: 2,
+            topic: SnTopic::Name("a/+/c"),
+        });
+        round_trip(SnPacket::Disconnect { duration: None });
+        round_trip(SnPacket::Pingreq { client_id: None });
+    }
+
+    #[test]
+    fn test_long_packet() {
+        let data = [0x42; 300];
+        round_trip(SnPacket::Publish {
+            flags: Flags::default(),
+            topic: SnTopic::Normal(1),
+            msg_id: 0,
+            data: &data,
+        });
+    }
+
+    #[test]
+    fn test_register() {
+        let broker = &*make_static!(Broker::new(InnerDistributor::default()));
+        let observer = Distributor::new(broker, 1);
+        block_on(observer.subscribe("sensors/temp")).unwrap();
+        let mut exchange = Exchange::new(broker);
+        exchange.connect(sensor(), "sensor");
+
+        let register = SnPacket::Register {
+            topic_id: 0,
+            msg_id: 1,
+            topic_name: "sensors/temp",
+        };
+        let regack = SnPacket::Regack {
+            topic_id: 1,
+            msg_id: 1,
+            return_code: ReturnCode::Accepted,
+        };
+        exchange.send(sensor(), register);
+        exchange.expect(sensor(), regack);
+        // registering again returns the same topic id
+        exchange.send(sensor(), register);
+        exchange.expect(sensor(), regack);
+
+        exchange.publish(sensor(), SnQos::AtLeastOnce, SnTopic::Normal(1), b"21");
+        let return_code = ReturnCode::Accepted;
+        let puback = SnPacket::Puback {
+            topic_id: 1,
+            msg_id: 1,
+            return_code,
+        };
+        exchange.expect(sensor(), puback);
+        expect_publish(&observer, "sensors/temp", b"21");
+
+        // unknown topic ids are reported even with QoS 0
+        exchange.publish(sensor(), SnQos::AtMostOnce, SnTopic::Normal(2), b"22");
+        let return_code = ReturnCode::InvalidTopicId;
+        let puback = SnPacket::Puback {
+            topic_id: 2,
+            msg_id: 0,
+            return_code,
+        };
+        exchange.expect(sensor(), puback);
+        exchange.expect_silence();
+    }
+
+    #[test]
+    fn test_short_and_predefined_topics() {
+        let broker = &*make_static!(Broker::new(InnerDistributor::default()));
+        let observer = Distributor::new(broker, 1);
+        block_on(observer.subscribe("ab")).unwrap();
+        block_on(observer.subscribe("alarms/#")).unwrap();
+        let mut exchange = Exchange::new(broker);
+
+        // QoS -1 publishes without connecting, but only with short or predefined topic ids
+        exchange.publish(sensor(), SnQos::NoSession, SnTopic::Predefined(5), b"1");
+        expect_publish(&observer, "alarms/fire", b"1");
+        exchange.publish(sensor(), SnQos::NoSession, SnTopic::Normal(1), b"2");
+        exchange.expect_silence();
+
+        exchange.connect(sensor(), "sensor");
+        exchange.publish(sensor(), SnQos::AtMostOnce, SnTopic::Short(*b"ab"), b"3");
+        expect_publish(&observer, "ab", b"3");
+        exchange.publish(sensor(), SnQos::AtLeastOnce, SnTopic::Predefined(6), b"4");
+        let return_code = ReturnCode::InvalidTopicId;
+        let puback = SnPacket::Puback {
+            topic_id: 6,
+            msg_id: 1,
+            return_code,
+        };
+        exchange.expect(sensor(), puback);
+        exchange.expect_silence();
+    }
+
+    #[test]
+    fn test_exactly_once() {
+        let broker = &*make_static!(Broker::new(InnerDistributor::default()));
+        let observer = Distributor::new(broker, 1);
+        block_on(observer.subscribe("ab")).unwrap();
+        let mut exchange = Exchange::new(broker);
+        exchange.connect(sensor(), "sensor");
+
+        // a retransmitted QoS 2 publish is acknowledged again, but only published once
+        exchange.publish(sensor(), SnQos::ExactlyOnce, SnTopic::Short(*b"ab"), b"1");
+        exchange.expect(sensor(), SnPacket::Pubrec { msg_id: 1 });
+        exchange.publish(sensor(), SnQos::ExactlyOnce, SnTopic::Short(*b"ab"), b"1");
+        exchange.expect(sensor(), SnPacket::Pubrec { msg_id: 1 });
+        exchange.publish(sensor(), SnQos::AtMostOnce, SnTopic::Short(*b"ab"), b"2");
+        expect_publish(&observer, "ab", b"1");
+        expect_publish(&observer, "ab", b"2");
+
+        // after the PUBREL the msg id can be used again
+        exchange.send(sensor(), SnPacket::Pubrel { msg_id: 1 });
+        exchange.expect(sensor(), SnPacket::Pubcomp { msg_id: 1 });
+        exchange.publish(sensor(), SnQos::ExactlyOnce, SnTopic::Short(*b"ab"), b"3");
+        exchange.expect(sensor(), SnPacket::Pubrec { msg_id: 1 });
+        expect_publish(&observer, "ab", b"3");
+        exchange.expect_silence();
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let broker = &*make_static!(Broker::new(InnerDistributor::default()));
+        let publisher = Distributor::new(broker, 1);
+        let mut exchange = Exchange::new(broker);
+        exchange.connect(sensor(), "sensor");
+
+        // wildcard subscriptions get no topic id, the gateway slot subscribes on the distributor
+        let subscribe = |msg_id, topic| SnPacket::Subscribe {
+            flags: Flags::default(),
+            msg_id,
+            topic,
+        };
+        let suback = |topic_id, msg_id| SnPacket::Suback {
+            flags: Flags::default(),
+            topic_id,
+            msg_id,
+            return_code: ReturnCode::Accepted,
+        };
+        exchange.send(sensor(), subscribe(1, SnTopic::Name("sensors/+")));
+        exchange.expect(sensor(), suback(0, 1));
+        exchange.send(sensor(), subscribe(2, SnTopic::Predefined(5)));
+        exchange.expect(sensor(), suback(5, 2));
+        assert_eq!(exchange.subscriptions(), 2);
+
+        // the topic id of a publish on a wildcard subscription is registered first
+        let publish = MPublish {
+            duplicate: false,
+            quality_of_service: QualityOfService::AtMostOnce,
+            retain: false,
+            topic_name: "sensors/temp",
+            packet_identifier: None,
+            properties: PublishProperties::new(),
+            payload: b"21",
+        };
+        block_on(publisher.publish("sensors/temp", &publish)).unwrap();
+        exchange.deliver();
+        let register = SnPacket::Register {
+            topic_id: 1,
+            msg_id: 1,
+            topic_name: "sensors/temp",
+        };
+        exchange.expect(sensor(), register);
+        let publish_to = |topic| SnPacket::Publish {
+            flags: Flags::default(),
+            topic,
+            msg_id: 0,
+            data: b"21",
+        };
+        exchange.expect(sensor(), publish_to(SnTopic::Normal(1)));
+        // known topic ids are not registered again
+        block_on(publisher.publish("sensors/temp", &publish)).unwrap();
+        exchange.deliver();
+        exchange.expect(sensor(), publish_to(SnTopic::Normal(1)));
+        let alarm = MPublish {
+            topic_name: "alarms/fire",
+            ..publish
+        };
+        block_on(publisher.publish("alarms/fire", &alarm)).unwrap();
+        exchange.deliver();
+        exchange.expect(sensor(), publish_to(SnTopic::Predefined(5)));
+
+        // the gateway slot unsubscribes once no client needs the topic
+        let unsubscribe = SnPacket::Unsubscribe {
+            flags: Flags::default(),
+            msg_id: 3,
+            topic: SnTopic::Name("sensors/+"),
+        };
+        exchange.send(sensor(), unsubscribe);
+        exchange.expect(sensor(), SnPacket::Unsuback { msg_id: 3 });
+        assert_eq!(exchange.subscriptions(), 1);
+        exchange.send(sensor(), SnPacket::Disconnect { duration: None });
+        exchange.expect(sensor(), SnPacket::Disconnect { duration: None });
+        assert_eq!(exchange.subscriptions(), 0);
+        exchange.expect_silence();
+    }
+}
@@ -0,0 +1,668 @@
+//! Saves retained messages and persistent sessions to NOR flash
+//!
+//! Records are appended to a log that spans all pages of the flash range, so repeated updates of
+//! a key are spread over the whole range. A page is only erased once it is the oldest one, after
+//! its records that are still current have been copied to the newest page. The page after the
+//! newest one is always kept erased.
+//!
+//! ```no_run
+//! let storage: &'static RamStorage<NoopRawMutex, 16> = make_static!(RamStorage::new());
+//! let mut flash = FlashStore::<_>::mount(flash, 0x80000..0x90000).await.unwrap();
+//! flash.load(storage).await.unwrap();
+//! let distributor: &'static Distributor = make_static!(InnerDistributorMutex::new(
+//!     InnerDistributor::default().with_storage(storage),
+//! ));
+//! spawner.spawn(persistence_task(flash, storage)).ok();
+//!
+//! #[embassy_executor::task]
+//! async fn persistence_task(
+//!     mut flash: FlashStore<Flash>,
+//!     storage: &'static RamStorage<NoopRawMutex, 16>,
+//! ) -> ! {
+//!     flash.run(storage, Duration::from_secs(5)).await
+//! }
+//! ```
+use core::ops::Range;
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_time::{Duration, Timer};
+use embedded_storage_async::nor_flash::NorFlash;
+use heapless::Vec;
+
+use crate::errors::PersistenceError;
+use crate::log::{info, warn};
+use crate::storage::{Key, RamStorage};
+
+/// Sequence number of the page and its complement
+const PAGE_HEADER_SIZE: usize = 8;
+/// Length of the record, length of the key, a reserved byte and the checksum of the record
+const RECORD_HEADER_SIZE: usize = 8;
+/// Length of a record that has not been written
+const ERASED: u16 = 0xFFFF;
+
+/// Header of a record read from flash, key and value are in the buffer of the store
+#[derive(Clone, Copy)]
+struct Record {
+    /// offset of the record in the flash
+    offset: u32,
+    /// length of header, key and value without padding
+    len: usize,
+    key_len: usize,
+    /// false if the checksum does not match, e.g. because writing was interrupted
+    valid: bool,
+}
+
+impl Record {
+    fn value_len(&self) -> usize {
+        self.len - RECORD_HEADER_SIZE - self.key_len
+    }
+}
+
+/// Position in the log, pages are visited from the oldest to the newest
+#[derive(Clone, Copy)]
+struct Cursor {
+    /// 0 is the page after the newest one, which is erased unless it is being collected
+    page_index: u32,
+    /// offset of the next record, `None` if the page header has not been checked yet
+    offset: Option<u32>,
+}
+
+impl Cursor {
+    const START: Cursor = Cursor {
+        page_index: 0,
+        offset: None,
+    };
+}
+
+/// Wear levelled key-value log on the pages of `range`
+/// RECORD_SIZE limits the size of a record, 8 bytes of header, the key and the value
+pub struct FlashStore<F: NorFlash, const RECORD_SIZE: usize = 1024> {
+    flash: F,
+    start: u32,
+    pages: u32,
+    /// page the records are appended to
+    head_page: u32,
+    /// sequence number of the head page, the oldest page has the lowest one
+    sequence: u32,
+    /// offset of the next record
+    head: u32,
+    buffer: [u8; RECORD_SIZE],
+}
+
+/// FNV-1a of the lengths, the key and the value of a record
+fn checksum(record: &[u8]) -> u32 {
+    record[..4]
+        .iter()
+        .chain(&record[RECORD_HEADER_SIZE..])
+        .fold(0x811c9dc5, |hash, byte| {
+            (hash ^ *byte as u32).wrapping_mul(0x01000193)
+        })
+}
+
+impl<F: NorFlash, const RECORD_SIZE: usize> FlashStore<F, RECORD_SIZE> {
+    /// Opens the log in `range`, which has to span at least two erase pages
+    /// a range without log is formatted
+    pub async fn mount(flash: F, range: Range<u32>) -> Result<Self, PersistenceError> {
+        let page_size = F::ERASE_SIZE as u32;
+        assert!(
+            range.start % page_size == 0 && range.end % page_size == 0,
+            "range has to be aligned to pages"
+        );
+        assert!(
+            range.end - range.start >= 2 * page_size,
+            "at least two pages needed"
+        );
+        assert!(
+            F::WRITE_SIZE % F::READ_SIZE == 0 && PAGE_HEADER_SIZE % F::READ_SIZE == 0,
+            "unsupported read size"
+        );
+        assert!(
+            PAGE_HEADER_SIZE.next_multiple_of(F::WRITE_SIZE) <= RECORD_SIZE,
+            "record size smaller than write size"
+        );
+        let mut store = Self {
+            flash,
+            start: range.start,
+            pages: (range.end - range.start) / page_size,
+            head_page: 0,
+            sequence: 0,
+            head: 0,
+            buffer: [0xFF; RECORD_SIZE],
+        };
+
+        let mut newest = None;
+        for page in 0..store.pages {
+            if let Some(sequence) = store.read_sequence(page).await? {
+                if newest.map_or(true, |(_, newest)| sequence > newest) {
+                    newest = Some((page, sequence));
+                }
+            }
+        }
+        let Some((page, sequence)) = newest else {
+            info!("PERSISTENCE: formatting flash");
+            store.erase(0..store.pages).await?;
+            store.head_page = store.pages - 1;
+            return store.open_page(0, 1).await.map(|()| store);
+        };
+        store.head_page = page;
+        store.sequence = sequence;
+
+        // the end of the log is the first record that has not been written
+        store.head = store.data_start(page);
+        let mut cursor = Cursor {
+            page_index: store.pages - 1,
+            offset: Some(store.head),
+        };
+        while let Some(record) = store.next_record(&mut cursor).await? {
+            store.head = record.offset + store.padded(record.len);
+        }
+        if !store.is_erased(store.head).await? {
+            // writing a header was interrupted, the rest of the page is lost
+            store.head = store.page_start(page) + page_size;
+        }
+
+        // moving to the next page was interrupted before the oldest page was erased
+        let oldest = store.page_after(page);
+        if store.read_sequence(oldest).await?.is_some() {
+            store.collect(oldest).await?;
+        }
+        Ok(store)
+    }
+
+    /// hands back the flash
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    /// Reads the current value of `key`
+    pub async fn get(&mut self, key: &str) -> Result<Option<&[u8]>, PersistenceError> {
+        let mut cursor = Cursor::START;
+        let mut current = None;
+        while let Some(record) = self.next_record(&mut cursor).await? {
+            if record.valid && self.key(&record) == key.as_bytes() {
+                current = Some(record);
+            }
+        }
+        match current {
+            Some(record) if record.value_len() > 0 => {
+                self.read_record(record.offset).await?;
+                let start = RECORD_HEADER_SIZE + record.key_len;
+                Ok(Some(&self.buffer[start..record.len]))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Appends `value` as the current value of `key`, an empty value removes the key
+    pub async fn set(&mut self, key: &str, value: &[u8]) -> Result<(), PersistenceError> {
+        let len = RECORD_HEADER_SIZE + key.len() + value.len();
+        let padded = self.padded(len);
+        if key.len() > u8::MAX as usize
+            || padded as usize > RECORD_SIZE
+            || padded > self.page_space()
+        {
+            return Err(PersistenceError::TooLarge);
+        }
+        self.reserve(len).await?;
+
+        let record = &mut self.buffer[..len];
+        record[..2].copy_from_slice(&(len as u16).to_le_bytes());
+        record[2] = key.len() as u8;
+        record[3] = 0xFF;
+        record[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + key.len()].copy_from_slice(key.as_bytes());
+        record[RECORD_HEADER_SIZE + key.len()..].copy_from_slice(value);
+        let checksum = checksum(record);
+        record[4..8].copy_from_slice(&checksum.to_le_bytes());
+        self.append(len).await
+    }
+
+    pub async fn remove(&mut self, key: &str) -> Result<(), PersistenceError> {
+        self.set(key, &[]).await
+    }
+
+    /// Fills `storage` with all keys of the log and tracks its changes from now on
+    /// returns the amount of loaded keys
+    pub async fn load<
+        M: RawMutex,
+        const COUNT: usize,
+        const SIZE: usize,
+        const KEY_LENGTH: usize,
+    >(
+        &mut self,
+        storage: &RamStorage<M, COUNT, SIZE, KEY_LENGTH>,
+    ) -> Result<usize, PersistenceError> {
+        let mut loaded = 0;
+        let mut cursor = Cursor::START;
+        while let Some(record) = self.next_record(&mut cursor).await? {
+            if !record.valid || record.value_len() == 0 {
+                continue;
+            }
+            let Ok(key) = Vec::<u8, KEY_LENGTH>::from_slice(self.key(&record)) else {
+                continue;
+            };
+            if !self.is_current(&key, cursor).await? {
+                continue;
+            }
+            let Ok(key) = core::str::from_utf8(&key) else {
+                continue;
+            };
+            // the buffer has been used while searching
+            self.read_record(record.offset).await?;
+            let value = &self.buffer[RECORD_HEADER_SIZE + record.key_len..record.len];
+            match storage.insert_saved(key, value) {
+                Ok(()) => loaded += 1,
+                Err(e) => warn!("PERSISTENCE: could not load {}: {:?}", key, e),
+            }
+        }
+        storage.track_changes();
+        Ok(loaded)
+    }
+
+    /// Writes all changes of `storage` to the log
+    pub async fn save<
+        M: RawMutex,
+        const COUNT: usize,
+        const SIZE: usize,
+        const KEY_LENGTH: usize,
+    >(
+        &mut self,
+        storage: &RamStorage<M, COUNT, SIZE, KEY_LENGTH>,
+    ) -> Result<(), PersistenceError> {
+        let mut key = Key::<KEY_LENGTH>::new();
+        let mut value = Vec::<u8, SIZE>::new();
+        while storage.next_change(&mut key, &mut value) {
+            // a change that fails to be written stays changed and is saved with the next batch
+            self.set(&key, &value).await?;
+            storage.saved(&key, &value);
+        }
+        Ok(())
+    }
+
+    /// Saves the changes of `storage` forever, `delay` after the first change of a batch
+    pub async fn run<
+        M: RawMutex,
+        const COUNT: usize,
+        const SIZE: usize,
+        const KEY_LENGTH: usize,
+    >(
+        &mut self,
+        storage: &RamStorage<M, COUNT, SIZE, KEY_LENGTH>,
+        delay: Duration,
+    ) -> ! {
+        loop {
+            storage.changed().await;
+            // collect more changes to spare the flash
+            Timer::after(delay).await;
+            if let Err(e) = self.save(storage).await {
+                warn!("PERSISTENCE: could not save changes {:?}", e);
+            }
+        }
+    }
+
+    fn page_start(&self, page: u32) -> u32 {
+        self.start + page * F::ERASE_SIZE as u32
+    }
+
+    fn data_start(&self, page: u32) -> u32 {
+        self.page_start(page) + self.padded(PAGE_HEADER_SIZE)
+    }
+
+    /// space for records on a page
+    fn page_space(&self) -> u32 {
+        F::ERASE_SIZE as u32 - self.padded(PAGE_HEADER_SIZE)
+    }
+
+    fn page_after(&self, page: u32) -> u32 {
+        (page + 1) % self.pages
+    }
+
+    fn padded(&self, len: usize) -> u32 {
+        len.next_multiple_of(F::WRITE_SIZE) as u32
+    }
+
+    fn key(&self, record: &Record) -> &[u8] {
+        &self.buffer[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + record.key_len]
+    }
+
+    async fn erase(&mut self, pages: Range<u32>) -> Result<(), PersistenceError> {
+        let (from, to) = (self.page_start(pages.start), self.page_start(pages.end));
+        self.flash
+            .erase(from, to)
+            .await
+            .map_err(|_| PersistenceError::Flash)
+    }
+
+    /// sequence number of `page`, `None` if it is erased or its header is invalid
+    async fn read_sequence(&mut self, page: u32) -> Result<Option<u32>, PersistenceError> {
+        let mut header = [0; PAGE_HEADER_SIZE];
+        self.flash
+            .read(self.page_start(page), &mut header)
+            .await
+            .map_err(|_| PersistenceError::Flash)?;
+        let sequence = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let complement = u32::from_le_bytes(header[4..].try_into().unwrap());
+        Ok((sequence == !complement && sequence != u32::MAX).then_some(sequence))
+    }
+
+    /// true if the record header at `offset` has not been written, or the page ends there
+    async fn is_erased(&mut self, offset: u32) -> Result<bool, PersistenceError> {
+        let page_end = offset - (offset - self.start) % F::ERASE_SIZE as u32 + F::ERASE_SIZE as u32;
+        if offset + RECORD_HEADER_SIZE as u32 > page_end {
+            return Ok(true);
+        }
+        let mut header = [0; RECORD_HEADER_SIZE];
+        self.flash
+            .read(offset, &mut header)
+            .await
+            .map_err(|_| PersistenceError::Flash)?;
+        Ok(header.iter().all(|b| *b == 0xFF))
+    }
+
+    /// makes `page` the head of the log, erasing it if a previous attempt was interrupted
+    async fn open_page(&mut self, page: u32, sequence: u32) -> Result<(), PersistenceError> {
+        if !self.is_erased(self.page_start(page)).await? {
+            self.erase(page..page + 1).await?;
+        }
+        let len = self.padded(PAGE_HEADER_SIZE) as usize;
+        let header = &mut self.buffer[..len];
+        header.fill(0xFF);
+        header[..4].copy_from_slice(&sequence.to_le_bytes());
+        header[4..8].copy_from_slice(&(!sequence).to_le_bytes());
+        self.flash
+            .write(self.page_start(page), &self.buffer[..len])
+            .await
+            .map_err(|_| PersistenceError::Flash)?;
+        self.head_page = page;
+        self.sequence = sequence;
+        self.head = self.data_start(page);
+        Ok(())
+    }
+
+    /// reads the record at `offset` into the buffer, `None` at the end of a page
+    async fn read_record(&mut self, offset: u32) -> Result<Option<Record>, PersistenceError> {
+        let page_end = offset - (offset - self.start) % F::ERASE_SIZE as u32 + F::ERASE_SIZE as u32;
+        if offset + RECORD_HEADER_SIZE as u32 > page_end {
+            return Ok(None);
+        }
+        self.flash
+            .read(offset, &mut self.buffer[..RECORD_HEADER_SIZE])
+            .await
+            .map_err(|_| PersistenceError::Flash)?;
+        let len = u16::from_le_bytes([self.buffer[0], self.buffer[1]]);
+        let key_len = self.buffer[2] as usize;
+        if len == ERASED
+            || (len as usize) < RECORD_HEADER_SIZE + key_len
+            || self.padded(len as usize) as usize > RECORD_SIZE
+            || offset + self.padded(len as usize) > page_end
+        {
+            // a header that does not make sense ends the page as well
+            return Ok(None);
+        }
+        let len = len as usize;
+        let padded = self.padded(len) as usize;
+        self.flash
+            .read(
+                offset + RECORD_HEADER_SIZE as u32,
+                &mut self.buffer[RECORD_HEADER_SIZE..padded],
+            )
+            .await
+            .map_err(|_| PersistenceError::Flash)?;
+        let stored = u32::from_le_bytes(self.buffer[4..8].try_into().unwrap());
+        Ok(Some(Record {
+            offset,
+            len,
+            key_len,
+            valid: stored == checksum(&self.buffer[..len]),
+        }))
+    }
+
+    /// reads the next record of the log into the buffer
+    async fn next_record(
+        &mut self,
+        cursor: &mut Cursor,
+    ) -> Result<Option<Record>, PersistenceError> {
+        while cursor.page_index < self.pages {
+            let page = (self.head_page + 1 + cursor.page_index) % self.pages;
+            let offset = match cursor.offset {
+                Some(offset) => offset,
+                None if self.read_sequence(page).await?.is_some() => self.data_start(page),
+                None => {
+                    cursor.page_index += 1;
+                    continue;
+                }
+            };
+            match self.read_record(offset).await? {
+                Some(record) => {
+                    cursor.offset = Some(offset + self.padded(record.len));
+                    return Ok(Some(record));
+                }
+                None => {
+                    cursor.page_index += 1;
+                    cursor.offset = None;
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// true if no record after `cursor` has the same key
+    async fn is_current(
+        &mut self,
+        key: &[u8],
+        mut cursor: Cursor,
+    ) -> Result<bool, PersistenceError> {
+        while let Some(record) = self.next_record(&mut cursor).await? {
+            if record.valid && self.key(&record) == key {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// writes the record in the buffer at the head of the log
+    async fn append(&mut self, len: usize) -> Result<(), PersistenceError> {
+        let padded = self.padded(len) as usize;
+        self.buffer[len..padded].fill(0xFF);
+        self.flash
+            .write(self.head, &self.buffer[..padded])
+            .await
+            .map_err(|_| PersistenceError::Flash)?;
+        self.head += padded as u32;
+        Ok(())
+    }
+
+    /// makes sure a record of `len` bytes fits on the head page
+    async fn reserve(&mut self, len: usize) -> Result<(), PersistenceError> {
+        for _ in 0..self.pages {
+            let page_end = self.page_start(self.head_page) + F::ERASE_SIZE as u32;
+            if self.head + self.padded(len) <= page_end {
+                return Ok(());
+            }
+            // the next page is always erased
+            let next = self.page_after(self.head_page);
+            self.open_page(next, self.sequence + 1).await?;
+            let oldest = self.page_after(next);
+            if oldest != next && self.read_sequence(oldest).await?.is_some() {
+                self.collect(oldest).await?;
+            }
+        }
+        Err(PersistenceError::Full)
+    }
+
+    /// copies the current records of the oldest `page` to the head and erases it
+    async fn collect(&mut self, page: u32) -> Result<(), PersistenceError> {
+        let mut cursor = Cursor::START;
+        while let Some(record) = self.next_record(&mut cursor).await? {
+            if cursor.page_index > 0 {
+                break;
+            }
+            // removed keys do not need to be kept, there are no older records
+            if !record.valid || record.value_len() == 0 {
+                continue;
+            }
+            let Ok(key) = Vec::<u8, { u8::MAX as usize }>::from_slice(self.key(&record)) else {
+                continue;
+            };
+            if !self.is_current(&key, cursor).await? {
+                continue;
+            }
+            if self.head + self.padded(record.len)
+                > self.page_start(self.head_page) + F::ERASE_SIZE as u32
+            {
+                return Err(PersistenceError::Full);
+            }
+            self.read_record(record.offset).await?;
+            self.append(record.len).await?;
+        }
+        self.erase(page..page + 1).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use embassy_futures::block_on;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embedded_storage_async::nor_flash::{ErrorType, NorFlashErrorKind, ReadNorFlash};
+
+    const PAGE: usize = 256;
+    const PAGES: usize = 4;
+
+    /// RAM backed flash that only allows writing erased bytes
+    struct MockFlash {
+        data: [u8; PAGE * PAGES],
+        erases: [u32; PAGES],
+    }
+
+    impl MockFlash {
+        fn new() -> Self {
+            Self {
+                data: [0xFF; PAGE * PAGES],
+                erases: [0; PAGES],
+            }
+        }
+    }
+
+    impl ErrorType for MockFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for MockFlash {
+        const READ_SIZE: usize = 1;
+
+        async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl NorFlash for MockFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = PAGE;
+
+        async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            assert!(from as usize % PAGE == 0 && to as usize % PAGE == 0);
+            self.data[from as usize..to as usize].fill(0xFF);
+            for page in from as usize / PAGE..to as usize / PAGE {
+                self.erases[page] += 1;
+            }
+            Ok(())
+        }
+
+        async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            assert!(offset % 4 == 0 && bytes.len() % 4 == 0, "unaligned write");
+            let target = &mut self.data[offset..offset + bytes.len()];
+            assert!(target.iter().all(|b| *b == 0xFF), "write to written bytes");
+            target.copy_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    type Store = FlashStore<MockFlash, 128>;
+    const RANGE: Range<u32> = 0..(PAGE * PAGES) as u32;
+
+    #[test]
+    fn test_set_and_remount() {
+        block_on(async {
+            let mut store = Store::mount(MockFlash::new(), RANGE).await.unwrap();
+            store.set("ra", b"first").await.unwrap();
+            store.set("rb", b"second").await.unwrap();
+            store.set("ra", b"third").await.unwrap();
+            store.remove("rb").await.unwrap();
+            assert_eq!(store.get("ra").await.unwrap(), Some(&b"third"[..]));
+            assert_eq!(store.get("rb").await.unwrap(), None);
+
+            let mut store = Store::mount(store.release(), RANGE).await.unwrap();
+            assert_eq!(store.get("ra").await.unwrap(), Some(&b"third"[..]));
+            assert_eq!(store.get("rb").await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn test_wear_levelling() {
+        block_on(async {
+            let mut store = Store::mount(MockFlash::new(), RANGE).await.unwrap();
+            store.set("sclient", b"a/b\0").await.unwrap();
+            for i in 0..200u32 {
+                store.set("rcounter", &i.to_le_bytes()).await.unwrap();
+            }
+            // every page has been erased, the key written once survived all of them
+            let flash = store.release();
+            assert!(flash.erases.iter().all(|erases| *erases >= 2));
+            let max = flash.erases.iter().max().unwrap();
+            let min = flash.erases.iter().min().unwrap();
+            assert!(max - min <= 1);
+
+            let mut store = Store::mount(flash, RANGE).await.unwrap();
+            assert_eq!(store.get("sclient").await.unwrap(), Some(&b"a/b\0"[..]));
+            assert_eq!(
+                store.get("rcounter").await.unwrap(),
+                Some(&199u32.to_le_bytes()[..])
+            );
+        });
+    }
+
+    #[test]
+    fn test_interrupted_write() {
+        block_on(async {
+            let mut store = Store::mount(MockFlash::new(), RANGE).await.unwrap();
+            store.set("ra", b"saved").await.unwrap();
+            let head = store.head as usize;
+            store.set("ra", b"broken").await.unwrap();
+            let mut flash = store.release();
+            // corrupt the value of the last record
+            flash.data[head + RECORD_HEADER_SIZE + 2] = 0;
+
+            let mut store = Store::mount(flash, RANGE).await.unwrap();
+            assert_eq!(store.get("ra").await.unwrap(), Some(&b"saved"[..]));
+            store.set("rb", b"next").await.unwrap();
+            assert_eq!(store.get("rb").await.unwrap(), Some(&b"next"[..]));
+        });
+    }
+
+    #[test]
+    fn test_load_and_save() {
+        block_on(async {
+            let mut store = Store::mount(MockFlash::new(), RANGE).await.unwrap();
+            let storage = RamStorage::<NoopRawMutex, 4, 32>::new();
+            assert_eq!(store.load(&storage).await.unwrap(), 0);
+            storage.set("ra", b"retained").unwrap();
+            storage.set("sclient", b"#\0").unwrap();
+            store.save(&storage).await.unwrap();
+            storage.set("ra", &[]).unwrap();
+            store.save(&storage).await.unwrap();
+
+            let mut store = Store::mount(store.release(), RANGE).await.unwrap();
+            let storage = RamStorage::<NoopRawMutex, 4, 32>::new();
+            assert_eq!(store.load(&storage).await.unwrap(), 1);
+            assert!(!storage.get("ra", &mut |_| {}));
+            assert!(storage.get("sclient", &mut |value| assert_eq!(value, b"#\0")));
+        });
+    }
+}
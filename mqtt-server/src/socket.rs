@@ -1,10 +1,13 @@
+use core::fmt;
+use core::future::pending;
 use core::num::NonZeroU16;
-use embassy_futures::select::select;
-use embassy_futures::select::Either::{First, Second};
+use core::ops::Deref;
+use embassy_futures::select::select3;
+use embassy_futures::select::Either3::{First, Second, Third};
 use embassy_net::tcp::TcpSocket;
 use embassy_net::Stack;
 use embassy_net_driver::Driver;
-use embassy_time::{with_timeout, Duration};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
 use embedded_io_async::{Read, Write};
 use heapless::Vec;
 use mqtt_format::v5::packets::connack::{ConnackProperties, ConnackReasonCode, MConnack};
@@ -18,30 +21,31 @@ use mqtt_format::v5::packets::MqttPacket;
 use mqtt_format::v5::qos::QualityOfService;
 use mqtt_format::v5::variable_header::PacketIdentifier;
 
-use crate::codec::{MqttCodecDecoder, MqttCodecEncoder};
-use crate::config::InnerDistributorMutex;
-use crate::distributor::Distributor;
+use crate::codec::{
+    publish_start, write_connack, Frame, MqttCodecDecoder, MqttCodecEncoder, PacketWriter,
+};
+use crate::config::BrokerConfig;
+use crate::distributor::{Distributor, StreamStart};
 use crate::errors::DistributorError;
+use crate::federation::{self, BrokerId, Outgoing, CLIENT_ID_PREFIX, TAGGED_HEADER_SIZE};
 use crate::log::{info, warn};
 
-pub async fn listen<T, const N: usize>(
+pub async fn listen<T, B: BrokerConfig>(
     stack: &'static Stack<T>,
     id: usize,
     port: u16,
-    distributor: &'static InnerDistributorMutex<N>,
+    distributor: &'static B,
 ) where
     T: Driver,
 {
     let mut rx_buffer = [0; 1600];
     let mut tx_buffer = [0; 1600];
-    let mut distributor = Distributor::new(distributor, id);
+    let distributor = Distributor::new(distributor, id);
+    let config = ConnectionConfig::default();
+    // cleanup settings of a previous run
+    distributor.cleanup().await;
 
     loop {
-        // cleanup previous connection settings
-        // unlocks distributor as well
-        distributor.cleanup();
-        distributor.fulfill_will().await;
-
         let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
         socket.set_timeout(Some(Duration::from_secs(60)));
         socket.set_keep_alive(Some(Duration::from_secs(10)));
@@ -59,57 +63,137 @@ pub async fn listen<T, const N: usize>(
             }
         };
         info!("SOCKET {}: Received connection from {}", id, addr);
+        distributor.set_remote(addr).await;
         let (reader, writer) = socket.split();
-        // connection handler
-        let mut parser = MqttCodecDecoder::<_, 1024>::new(reader);
-        let mut encoder = MqttCodecEncoder::<_, 1024>::new(writer);
-
-        info!("SOCKET {}: Handshaking...", id);
-        let timout = Duration::from_secs(10);
-        match with_timeout(timout, parser.next()).await {
-            Ok(Ok(Some(MqttPacket::Connect(connect)))) => {
-                if let Some(conn_will) = connect.will {
-                    let will = MPublish {
-                        duplicate: false,
-                        topic_name: conn_will.topic,
-                        payload: conn_will.payload,
-                        retain: conn_will.will_retain,
-                        properties: PublishProperties::new(),
-                        packet_identifier: None,
-                        quality_of_service: QualityOfService::AtMostOnce,
-                    };
-                    if let Err(e) = distributor.set_will(will) {
-                        warn!("SOCKET {}: error setting will {:?}", id, e);
-                        continue;
-                    }
-                    info!("SOCKET {}: will topic: {}", id, conn_will.topic);
-                }
-                let pkg = MqttPacket::Connack(MConnack {
-                    session_present: false,
-                    reason_code: ConnackReasonCode::Success,
-                    properties: ConnackProperties::new(),
-                });
-                if let Err(e) = encoder.write(pkg).await {
-                    warn!("SOCKET {}: {:?}", id, e);
-                    continue;
-                }
-            }
-            Err(_e) => {
-                warn!("SOCKET {}: connection to first packet timeout...", id);
-                continue;
-            }
-            Ok(e) => {
-                #[cfg(feature = "defmt")]
-                let e = ();
-                warn!("SOCKET {}: error decoding packet {:?}", id, e);
-                continue;
-            }
+        serve_connection(reader, writer, &distributor, &config).await;
+    }
+}
+
+/// Checks the client id, user name and password of a CONNECT packet
+pub type Authenticate =
+    fn(client_id: &str, username: Option<&str>, password: Option<&[u8]>) -> bool;
+
+/// Settings of a single client connection
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+    /// how long to wait for the CONNECT packet
+    pub connect_timeout: Duration,
+    /// clients are refused with `NotAuthorized` if this returns false, `None` accepts everyone
+    pub authenticate: Option<Authenticate>,
+    /// keep alive of the clients in seconds is raised to this, 0 allows to disable it
+    pub min_keep_alive: u16,
+    /// keep alive of the clients in seconds is lowered to this, clients that disable it get this
+    /// one instead, `u16::MAX` sets no maximum
+    pub max_keep_alive: u16,
+    /// topic prefix like `group1/` that is prepended to all topics of the clients and stripped
+    /// from the messages they receive, clients can not see topics outside of it
+    /// this includes the `$CONTROL` topics, so mounted clients can not administer the broker
+    pub mount_point: Option<&'static str>,
+    /// clients this returns true for may administer the broker over the `$CONTROL` topics, see
+    /// [`crate::control`], `None` allows nobody
+    pub authorize_control: Option<Authenticate>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            authenticate: None,
+            min_keep_alive: 0,
+            max_keep_alive: u16::MAX,
+            mount_point: None,
+            authorize_control: None,
         }
+    }
+}
+
+impl ConnectionConfig {
+    /// keep alive the client has to use if it asks for `requested`
+    fn keep_alive(&self, requested: u16) -> u16 {
+        let max = self.max_keep_alive.max(self.min_keep_alive);
+        // a disabled keep alive exceeds any maximum
+        if requested == 0 && max != u16::MAX {
+            return max;
+        }
+        requested.clamp(self.min_keep_alive, max)
+    }
+}
 
-        if let Err(error) = handle_socket(&mut parser, &mut encoder, &distributor).await {
+/// Settings of the session after a successful handshake
+struct Session<K> {
+    /// id of the broker if the client is a federated broker
+    peer: Option<BrokerId>,
+    /// keep alive in seconds, 0 if disabled
+    keep_alive: u16,
+    mount_point: Option<&'static str>,
+    /// key of the persistent session, `None` if the session ends with the connection
+    session_key: Option<K>,
+}
+
+/// Topic of a client as seen by the distributor
+enum Mounted<'a, S> {
+    Unchanged(&'a str),
+    Prefixed(S),
+}
+
+impl<S: Deref<Target = str>> Deref for Mounted<'_, S> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        match self {
+            Mounted::Unchanged(topic) => topic,
+            Mounted::Prefixed(topic) => topic,
+        }
+    }
+}
+
+/// Prepends the mount point of the client to `topic`
+fn mount<'a, S: Default + fmt::Write>(
+    mount_point: Option<&str>,
+    topic: &'a str,
+) -> Result<Mounted<'a, S>, DistributorError> {
+    let Some(mount_point) = mount_point else {
+        return Ok(Mounted::Unchanged(topic));
+    };
+    let mut mounted = S::default();
+    mounted
+        .write_str(mount_point)
+        .and_then(|()| mounted.write_str(topic))
+        .map_err(|_| DistributorError::TopicTooLong)?;
+    Ok(Mounted::Prefixed(mounted))
+}
+
+/// Serves a single MQTT client over any async byte stream
+/// runs the CONNECT handshake and the session, afterwards the subscriptions of the client are
+/// removed and its will is published
+pub async fn serve_connection<R, W, B: BrokerConfig>(
+    reader: R,
+    writer: W,
+    distributor: &Distributor<B>,
+    config: &ConnectionConfig,
+) where
+    R: Read,
+    W: Write,
+{
+    let id = distributor.get_id();
+    // publishes that do not fit into the buffer are forwarded chunk by chunk, the mount point
+    // and a longer length of the packet have to fit into the first chunk
+    let mut parser = MqttCodecDecoder::new(reader, B::buffer())
+        .with_counters(distributor.traffic())
+        .with_streaming(config.mount_point.map_or(0, str::len) + 1);
+    let mut encoder =
+        MqttCodecEncoder::new(writer, B::buffer()).with_counters(distributor.traffic());
+
+    info!("SOCKET {}: Handshaking...", id);
+    if let Some(session) = handshake(&mut parser, &mut encoder, distributor, config).await {
+        if let Err(error) = handle_socket(&mut parser, &mut encoder, distributor, &session).await {
             warn!("SOCKET {}: {:?}", id, error);
+            let reason_code = match error {
+                DistributorError::Disconnected => distributor.disconnect_reason().await,
+                error => error.into(),
+            };
             let error = MqttPacket::Disconnect(MDisconnect {
-                reason_code: error.into(),
+                reason_code,
                 properties: DisconnectProperties::new(),
             });
             if let Err(e) = encoder.write(error).await {
@@ -120,42 +204,321 @@ pub async fn listen<T, const N: usize>(
             }
         }
     }
+
+    // cleanup connection settings, unlocks distributor as well
+    distributor.cleanup().await;
+    distributor.fulfill_will().await;
 }
 
-async fn handle_socket<
-    T,
-    U,
-    const DECODER_SIZE: usize,
-    const ENCODER_SIZE: usize,
-    const CONNECTIONS: usize,
->(
-    parser: &mut MqttCodecDecoder<T, DECODER_SIZE>,
-    encoder: &mut MqttCodecEncoder<U, ENCODER_SIZE>,
-    distributor: &Distributor<CONNECTIONS>,
+/// Waits for CONNECT and answers with CONNACK
+/// returns `None` if the handshake failed or the client was refused
+async fn handshake<T, U, B: BrokerConfig>(
+    parser: &mut MqttCodecDecoder<T, B::Buffer>,
+    encoder: &mut MqttCodecEncoder<U, B::Buffer>,
+    distributor: &Distributor<B>,
+    config: &ConnectionConfig,
+) -> Option<Session<B::Key>>
+where
+    T: Read,
+    U: Write,
+{
+    let id = distributor.get_id();
+    let mut peer = None;
+    match with_timeout(config.connect_timeout, parser.next()).await {
+        Ok(Ok(Some(MqttPacket::Connect(connect)))) => {
+            if let Some(authenticate) = config.authenticate {
+                if !authenticate(
+                    connect.client_identifier,
+                    connect.username,
+                    connect.password,
+                ) {
+                    warn!("SOCKET {}: client not authorized", id);
+                    distributor.reject_client().await;
+                    let pkg = MqttPacket::Connack(MConnack {
+                        session_present: false,
+                        reason_code: ConnackReasonCode::NotAuthorized,
+                        properties: ConnackProperties::new(),
+                    });
+                    let _ = encoder.write(pkg).await;
+                    return None;
+                }
+            }
+            // links of federated brokers identify themselves with their client id
+            if let Some(broker_id) = connect.client_identifier.strip_prefix(CLIENT_ID_PREFIX) {
+                let Ok(broker_id) = BrokerId::try_from(broker_id) else {
+                    warn!("SOCKET {}: invalid broker id {}", id, broker_id);
+                    distributor.reject_client().await;
+                    let pkg = MqttPacket::Connack(MConnack {
+                        session_present: false,
+                        reason_code: ConnackReasonCode::ClientIdentifierNotValid,
+                        properties: ConnackProperties::new(),
+                    });
+                    let _ = encoder.write(pkg).await;
+                    return None;
+                };
+                info!("SOCKET {}: federated broker {}", id, broker_id);
+                peer = Some(broker_id);
+                distributor.set_peer().await;
+            }
+            if let Some(authorize_control) = config.authorize_control {
+                if authorize_control(
+                    connect.client_identifier,
+                    connect.username,
+                    connect.password,
+                ) {
+                    info!("SOCKET {}: client may use control topics", id);
+                    distributor.allow_control().await;
+                }
+            }
+            if let Some(conn_will) = connect.will {
+                let Ok(topic) = mount::<B::Topic>(config.mount_point, conn_will.topic) else {
+                    warn!("SOCKET {}: will topic too long", id);
+                    return None;
+                };
+                let will = MPublish {
+                    duplicate: false,
+                    topic_name: &topic,
+                    payload: conn_will.payload,
+                    retain: conn_will.will_retain,
+                    properties: PublishProperties::new(),
+                    packet_identifier: None,
+                    quality_of_service: QualityOfService::AtMostOnce,
+                };
+                if let Err(e) = distributor.set_will(will).await {
+                    warn!("SOCKET {}: error setting will {:?}", id, e);
+                    return None;
+                }
+                info!("SOCKET {}: will topic: {}", id, conn_will.topic);
+            }
+            // clients that do not start clean resume their subscriptions
+            let key = distributor.session_key(config.mount_point, connect.client_identifier);
+            let (session_key, session_present) = match key {
+                Some(key) if !connect.clean_start => {
+                    let present = distributor.restore_session(&key).await;
+                    (Some(key), present)
+                }
+                Some(key) => {
+                    distributor.remove_session(&key).await;
+                    (None, false)
+                }
+                None => {
+                    if !connect.clean_start {
+                        warn!("SOCKET {}: client id too long for a persistent session", id);
+                    }
+                    (None, false)
+                }
+            };
+            let keep_alive = config.keep_alive(connect.keep_alive);
+            let sent = if keep_alive == connect.keep_alive {
+                let pkg = MqttPacket::Connack(MConnack {
+                    session_present,
+                    reason_code: ConnackReasonCode::Success,
+                    properties: ConnackProperties::new(),
+                });
+                encoder.write(pkg).await
+            } else {
+                // the client has to use the keep alive of the server
+                let mut connack = PacketWriter::<16>::default();
+                let _ = write_connack(&mut connack, session_present, 0, Some(keep_alive), None);
+                encoder.write_chunk(connack.get_written_data()).await
+            };
+            if let Err(e) = sent {
+                warn!("SOCKET {}: {:?}", id, e);
+                return None;
+            }
+            distributor.accept_client(connect.client_identifier).await;
+            Some(Session {
+                peer,
+                keep_alive,
+                mount_point: config.mount_point,
+                session_key,
+            })
+        }
+        Err(_e) => {
+            warn!("SOCKET {}: connection to first packet timeout...", id);
+            None
+        }
+        Ok(e) => {
+            #[cfg(feature = "defmt")]
+            let e = ();
+            warn!("SOCKET {}: error decoding packet {:?}", id, e);
+            None
+        }
+    }
+}
+
+async fn handle_socket<T, U, B: BrokerConfig>(
+    parser: &mut MqttCodecDecoder<T, B::Buffer>,
+    encoder: &mut MqttCodecEncoder<U, B::Buffer>,
+    distributor: &Distributor<B>,
+    session: &Session<B::Key>,
 ) -> Result<(), DistributorError>
 where
     T: Read,
     U: Write,
 {
+    let peer = session.peer.as_deref();
+    let broker_id = distributor.broker_id().await;
+    let mut header = PacketWriter::<TAGGED_HEADER_SIZE>::default();
+    // clients have to send something within one and a half times the keep alive
+    let keep_alive = Duration::from_millis(session.keep_alive as u64 * 1500);
+    let mut deadline = Instant::now() + keep_alive;
+    // federated brokers only get whole publishes, chunks can not be tagged
+    if peer.is_none() {
+        distributor.enable_streaming().await;
+    }
+    // packet identifier of the publish the client is in the middle of, once its first chunk
+    // has been read
+    let mut streamed: Option<Option<u16>> = None;
+    // the rest of a streamed message that is not sent to the client is skipped
+    let mut skipping = false;
     loop {
         // unlock after processing packet
-        distributor.unlock();
-        let selected = select(distributor.next(), distributor.lock(parser.next())).await;
+        distributor.unlock().await;
+        let timeout = async move {
+            if keep_alive.as_ticks() == 0 {
+                pending::<()>().await
+            } else {
+                Timer::at(deadline).await
+            }
+        };
+        let messages = async {
+            // a chunk the parser has read would be lost if a message came first
+            match streamed {
+                Some(_) => pending().await,
+                None => distributor.next().await,
+            }
+        };
+        let selected = select3(messages, distributor.lock(parser.next_frame()), timeout).await;
         let packet = match selected {
             First(msg) => {
-                let mut packet = MqttPacket::parse_complete(msg.message()).unwrap();
-
-                if let MqttPacket::Publish(ref mut publish) = packet {
-                    publish.packet_identifier = None;
+                let msg = msg?;
+                if let Some(chunk) = msg.chunk() {
+                    if peer.is_some() {
+                        continue;
+                    }
+                    if skipping {
+                        skipping = !chunk.last;
+                        continue;
+                    }
+                    let sent = match session.mount_point {
+                        Some(mount_point) if chunk.first => {
+                            let start =
+                                publish_start(msg.message()).ok_or(DistributorError::Unknown)?;
+                            let Some(topic) = start.topic.strip_prefix(mount_point) else {
+                                skipping = !chunk.last;
+                                continue;
+                            };
+                            match encoder
+                                .write_publish_header(start.retain, topic, start.len)
+                                .await
+                            {
+                                Ok(()) => encoder.write_chunk(start.rest).await,
+                                e => e,
+                            }
+                        }
+                        _ => encoder.write_chunk(msg.message()).await,
+                    };
+                    sent.map_err(|_| DistributorError::Unknown)?;
+                    continue;
                 }
-
+                let sent = match (peer, broker_id) {
+                    (Some(peer), Some(broker_id)) => {
+                        let Ok(MqttPacket::Publish(publish)) =
+                            MqttPacket::parse_complete(msg.message())
+                        else {
+                            continue;
+                        };
+                        header.write_index = 0;
+                        match federation::outgoing(&mut header, &publish, peer, broker_id, msg.id())
+                        {
+                            Outgoing::Drop => continue,
+                            Outgoing::Forward => encoder.write_chunk(msg.message()).await,
+                            Outgoing::Tagged => {
+                                match encoder.write_chunk(header.get_written_data()).await {
+                                    Ok(()) => encoder.write_chunk(publish.payload).await,
+                                    e => e,
+                                }
+                            }
+                        }
+                    }
+                    _ => match session.mount_point {
+                        // the message is sent straight from the queue
+                        None => encoder.write_chunk(msg.message()).await,
+                        Some(mount_point) => {
+                            let Ok(MqttPacket::Publish(publish)) =
+                                MqttPacket::parse_complete(msg.message())
+                            else {
+                                continue;
+                            };
+                            let Some(topic_name) = publish.topic_name.strip_prefix(mount_point)
+                            else {
+                                continue;
+                            };
+                            // shorter than the queued message, so it fits into the encoder
+                            let publish = MPublish {
+                                topic_name,
+                                ..publish
+                            };
+                            encoder.write(MqttPacket::Publish(publish)).await
+                        }
+                    },
+                };
+                sent.map_err(|_| DistributorError::Unknown)?;
+                continue;
+            }
+            Second(Ok(Some(Frame::Packet(packet)))) => {
+                deadline = Instant::now() + keep_alive;
+                packet
+            }
+            Second(Ok(Some(Frame::Chunk {
+                data,
+                first,
+                remaining,
+            }))) => {
+                deadline = Instant::now() + keep_alive;
+                let last = remaining == 0;
+                if first {
+                    // the topic has to be part of the first chunk
+                    let start = publish_start(data).ok_or(DistributorError::TopicTooLong)?;
+                    let topic = mount::<B::Topic>(session.mount_point, start.topic)?;
+                    if start.retain {
+                        warn!(
+                            "SOCKET {}: publish too large to be retained",
+                            distributor.get_id()
+                        );
+                    }
+                    let start_of_publish = StreamStart {
+                        topic: &topic,
+                        retain: start.retain,
+                        len: start.len,
+                    };
+                    distributor
+                        .publish_chunk(Some(start_of_publish), start.rest, last)
+                        .await?;
+                    streamed = Some(start.packet_identifier);
+                } else {
+                    distributor.publish_chunk(None, data, last).await?;
+                }
+                if !last {
+                    continue;
+                }
+                let packet_identifier = streamed
+                    .take()
+                    .flatten()
+                    .and_then(NonZeroU16::new)
+                    .unwrap_or(NonZeroU16::new(1).unwrap());
+                let pkg = MqttPacket::Puback(MPuback {
+                    packet_identifier: PacketIdentifier(packet_identifier),
+                    reason: PubackReasonCode::Success,
+                    properties: PubackProperties::new(),
+                });
                 encoder
-                    .write(packet)
+                    .write(pkg)
                     .await
                     .map_err(|_| DistributorError::Unknown)?;
                 continue;
             }
-            Second(Ok(Some(packet))) => packet,
             Second(Ok(None)) => {
                 // socket closed
                 return Ok(());
@@ -165,11 +528,20 @@ where
                 warn!("SOCKET: {:?}", e);
                 return Err(DistributorError::Unknown);
             }
+            Third(()) => {
+                warn!("SOCKET {}: keep alive timeout", distributor.get_id());
+                return Err(DistributorError::KeepAliveTimeout);
+            }
         };
 
         match packet {
             MqttPacket::Publish(publish) => {
-                distributor.publish(publish.topic_name, &publish)?;
+                let topic = mount::<B::Topic>(session.mount_point, publish.topic_name)?;
+                let publish = MPublish {
+                    topic_name: &topic,
+                    ..publish
+                };
+                distributor.publish(&topic, &publish).await?;
                 let packet_identifier = publish
                     .packet_identifier
                     .unwrap_or(PacketIdentifier(NonZeroU16::new(1).unwrap()));
@@ -184,14 +556,24 @@ where
                     .map_err(|_| DistributorError::Unknown)?;
             }
             MqttPacket::Subscribe(subscribe) => {
-                let result = subscribe
-                    .subscriptions
-                    .iter()
-                    .filter_map(|s| distributor.subscribe(s.topic_filter).err())
-                    .map(SubackReasonCode::from)
-                    .take(8)
-                    .collect::<Vec<_, 8>>();
+                let mut result = Vec::<_, 8>::new();
+                for s in subscribe.subscriptions.iter() {
+                    let subscribed = match mount::<B::Topic>(session.mount_point, s.topic_filter) {
+                        Ok(filter) if s.options.no_local => {
+                            distributor.subscribe_no_local(&filter).await
+                        }
+                        Ok(filter) => distributor.subscribe(&filter).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = subscribed {
+                        // only the first 8 errors are reported
+                        let _ = result.push(SubackReasonCode::from(e));
+                    }
+                }
 
+                if let Some(key) = &session.session_key {
+                    distributor.save_session(key).await;
+                }
                 let pkg = MqttPacket::Suback(MSuback {
                     packet_identifier: subscribe.packet_identifier,
                     properties: SubackProperties::new(),
@@ -203,10 +585,14 @@ where
                     .map_err(|_| DistributorError::Unknown)?;
             }
             MqttPacket::Unsubscribe(unsubscribe) => {
-                unsubscribe
-                    .unsubscriptions
-                    .iter()
-                    .for_each(|s| distributor.unsubscribe(s.topic_filter));
+                for s in unsubscribe.unsubscriptions.iter() {
+                    if let Ok(filter) = mount::<B::Topic>(session.mount_point, s.topic_filter) {
+                        distributor.unsubscribe(&filter).await;
+                    }
+                }
+                if let Some(key) = &session.session_key {
+                    distributor.save_session(key).await;
+                }
                 let pkg = MqttPacket::Unsuback(MUnsuback {
                     packet_identifier: unsubscribe.packet_identifier,
                     properties: UnsubackProperties::new(),
@@ -245,3 +631,32 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keep_alive() {
+        let config = ConnectionConfig::default();
+        assert_eq!(config.keep_alive(0), 0);
+        assert_eq!(config.keep_alive(60), 60);
+
+        let config = ConnectionConfig {
+            min_keep_alive: 10,
+            max_keep_alive: 120,
+            ..Default::default()
+        };
+        assert_eq!(config.keep_alive(5), 10);
+        assert_eq!(config.keep_alive(60), 60);
+        assert_eq!(config.keep_alive(600), 120);
+        assert_eq!(config.keep_alive(0), 120);
+
+        // disabling the keep alive is only allowed without a maximum
+        let config = ConnectionConfig {
+            max_keep_alive: 120,
+            ..Default::default()
+        };
+        assert_eq!(config.keep_alive(0), 120);
+    }
+}
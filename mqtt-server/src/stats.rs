@@ -0,0 +1,194 @@
+//! Statistics of the broker
+//!
+//! The counters are kept where the events happen: the codec of every connection counts packets
+//! and bytes, the distributor counts connections, dropped messages and its memory usage.
+//! `InnerDistributorMutex::stats` collects them into a [`Stats`] snapshot, e.g. to show them on a display.
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Amount of MQTT control packet types, packets are counted by their type, e.g. PUBLISH is 3
+pub const PACKET_TYPES: usize = 16;
+
+/// Packets and bytes sent and received
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Traffic {
+    /// received packets by packet type
+    pub packets_in: [u32; PACKET_TYPES],
+    /// sent packets by packet type
+    pub packets_out: [u32; PACKET_TYPES],
+    pub bytes_in: u32,
+    pub bytes_out: u32,
+}
+
+impl Traffic {
+    /// received packets of all types
+    pub fn total_packets_in(&self) -> u32 {
+        self.packets_in
+            .iter()
+            .fold(0, |sum, n| sum.wrapping_add(*n))
+    }
+    /// sent packets of all types
+    pub fn total_packets_out(&self) -> u32 {
+        self.packets_out
+            .iter()
+            .fold(0, |sum, n| sum.wrapping_add(*n))
+    }
+    fn add(&mut self, other: &Traffic) {
+        let counters = self.packets_in.iter_mut().zip(other.packets_in.iter());
+        let counters = counters.chain(self.packets_out.iter_mut().zip(other.packets_out.iter()));
+        for (counter, other) in counters {
+            *counter = counter.wrapping_add(*other);
+        }
+        self.bytes_in = self.bytes_in.wrapping_add(other.bytes_in);
+        self.bytes_out = self.bytes_out.wrapping_add(other.bytes_out);
+    }
+}
+
+/// Traffic of the connection served by a socket
+/// updated by its codec without locking the distributor
+#[derive(Default)]
+pub struct TrafficCounters {
+    packets_in: [AtomicU32; PACKET_TYPES],
+    packets_out: [AtomicU32; PACKET_TYPES],
+    bytes_in: AtomicU32,
+    bytes_out: AtomicU32,
+}
+
+impl TrafficCounters {
+    /// counts a received packet, `header` is its first byte
+    pub(crate) fn received_packet(&self, header: u8) {
+        self.packets_in[header as usize >> 4].fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn received_bytes(&self, len: usize) {
+        self.bytes_in.fetch_add(len as u32, Ordering::Relaxed);
+    }
+    /// counts a sent packet, `header` is its first byte
+    pub(crate) fn sent_packet(&self, header: u8) {
+        self.packets_out[header as usize >> 4].fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn sent_bytes(&self, len: usize) {
+        self.bytes_out.fetch_add(len as u32, Ordering::Relaxed);
+    }
+    pub(crate) fn snapshot(&self) -> Traffic {
+        self.read(|counter| counter.load(Ordering::Relaxed))
+    }
+    /// returns the counters and starts counting from 0 again
+    pub(crate) fn take(&self) -> Traffic {
+        self.read(|counter| counter.swap(0, Ordering::Relaxed))
+    }
+    fn read(&self, f: impl Fn(&AtomicU32) -> u32) -> Traffic {
+        Traffic {
+            packets_in: core::array::from_fn(|i| f(&self.packets_in[i])),
+            packets_out: core::array::from_fn(|i| f(&self.packets_out[i])),
+            bytes_in: f(&self.bytes_in),
+            bytes_out: f(&self.bytes_out),
+        }
+    }
+}
+
+/// Counters of the client connected to a socket, they start at 0 with every connection
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientStats {
+    /// a client has completed the handshake
+    pub connected: bool,
+    pub packets_in: u32,
+    pub packets_out: u32,
+    pub bytes_in: u32,
+    pub bytes_out: u32,
+    /// messages the client missed because it was too slow
+    pub dropped: u32,
+    /// subscribed topic filters
+    pub subscriptions: usize,
+}
+
+impl ClientStats {
+    pub(crate) fn new(
+        connected: bool,
+        traffic: &Traffic,
+        dropped: u32,
+        subscriptions: usize,
+    ) -> Self {
+        Self {
+            connected,
+            packets_in: traffic.total_packets_in(),
+            packets_out: traffic.total_packets_out(),
+            bytes_in: traffic.bytes_in,
+            bytes_out: traffic.bytes_out,
+            dropped,
+            subscriptions,
+        }
+    }
+}
+
+/// Snapshot of the counters of the broker, see `InnerDistributorMutex::stats`
+/// N is the amount of sockets of the distributor
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy)]
+pub struct Stats<const N: usize> {
+    /// clients that completed the handshake
+    pub connections_accepted: u32,
+    /// clients that have been refused, e.g. because they were not authorized or the broker
+    /// was full
+    pub connections_rejected: u32,
+    /// traffic of all connections, including the closed ones
+    pub traffic: Traffic,
+    /// messages that could not be delivered to all of their subscribers
+    pub dropped: u32,
+    /// messages queued right now
+    pub queue_used: usize,
+    /// most messages that have been queued at the same time
+    pub queue_high_water: usize,
+    /// capacity of the queue, `QUEUE_LEN`
+    pub queue_capacity: usize,
+    /// nodes of the subscription tree in use
+    pub tree_used: usize,
+    /// capacity of the subscription tree, `TREE_SIZE`
+    pub tree_capacity: usize,
+    /// counters of every socket
+    pub clients: [ClientStats; N],
+}
+
+impl<const N: usize> Stats<N> {
+    /// clients connected right now
+    pub fn connected(&self) -> usize {
+        self.clients.iter().filter(|c| c.connected).count()
+    }
+}
+
+/// Counters maintained by the distributor
+pub(crate) struct DistributorCounters<const N: usize> {
+    pub(crate) connections_accepted: u32,
+    pub(crate) connections_rejected: u32,
+    pub(crate) queue_high_water: usize,
+    /// traffic of the connections that have been closed
+    pub(crate) closed: Traffic,
+    /// sockets with a client that completed the handshake
+    pub(crate) connected: [bool; N],
+    /// messages every socket missed during its current connection
+    pub(crate) dropped: [u32; N],
+}
+
+impl<const N: usize> DistributorCounters<N> {
+    pub(crate) fn new() -> Self {
+        Self {
+            connections_accepted: 0,
+            connections_rejected: 0,
+            queue_high_water: 0,
+            closed: Traffic::default(),
+            connected: [false; N],
+            dropped: [0; N],
+        }
+    }
+    /// adds the traffic of a closed connection to the total
+    pub(crate) fn close(&mut self, traffic: &Traffic) {
+        self.closed.add(traffic);
+    }
+    pub(crate) fn total(&self, open: impl Iterator<Item = Traffic>) -> Traffic {
+        let mut total = self.closed;
+        for traffic in open {
+            total.add(&traffic);
+        }
+        total
+    }
+}
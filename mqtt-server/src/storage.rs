@@ -0,0 +1,352 @@
+//! Retained messages and persistent sessions
+//!
+//! The distributor keeps them in a key-value [`Storage`]. Keys of retained messages are
+//! [`RETAINED_PREFIX`] followed by the topic, keys of sessions are [`SESSION_PREFIX`] followed by
+//! the mount point, a zero and the client id. Messages queued for a session while its client is
+//! offline use [`QUEUED_PREFIX`], the rest of the session key, a zero and their sequence number in
+//! hex, they are removed once they have been written to the reconnected client.
+//! [`RamStorage`] keeps them in memory and tracks which entries changed, so they can be saved to
+//! flash, see `persistence::FlashStore`.
+use core::cell::RefCell;
+use core::fmt::Write;
+use core::future::poll_fn;
+use core::task::Poll;
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::waitqueue::WakerRegistration;
+use heapless::{String, Vec};
+
+use crate::config::{DEFAULT_KEY_LENGTH, DEFAULT_MAX_MESSAGE_SIZE};
+use crate::errors::DistributorError;
+
+pub const RETAINED_PREFIX: &str = "r";
+pub const SESSION_PREFIX: &str = "s";
+pub const QUEUED_PREFIX: &str = "q";
+
+/// Key of up to LENGTH bytes, the distributor uses `key_length` of its `MAX_TOPIC_LENGTH`
+pub type Key<const LENGTH: usize = DEFAULT_KEY_LENGTH> = String<LENGTH>;
+
+/// Key of the retained message of `topic`
+pub(crate) fn retained_key<const L: usize>(topic: &str) -> Option<Key<L>> {
+    let mut key = String::new();
+    key.push_str(RETAINED_PREFIX).ok()?;
+    key.push_str(topic).ok()?;
+    Some(key)
+}
+
+/// Key of the session of `client_id`, clients of different mount points have separate sessions
+/// the two are separated by a zero, which can not be part of a client id
+/// `None` if the keys of the messages queued for the session would not fit
+pub(crate) fn session_key<const L: usize>(
+    mount_point: Option<&str>,
+    client_id: &str,
+) -> Option<Key<L>> {
+    let mut key = String::new();
+    key.push_str(SESSION_PREFIX).ok()?;
+    key.push_str(mount_point.unwrap_or_default()).ok()?;
+    key.push('\0').ok()?;
+    key.push_str(client_id).ok()?;
+    queued_key::<L>(&key, 0)?;
+    Some(key)
+}
+
+/// Key of a session from the rest of its key, as passed to [`Storage::for_each`]
+pub(crate) fn stored_session_key<const L: usize>(rest: &str) -> Option<Key<L>> {
+    let mut key = String::new();
+    key.push_str(SESSION_PREFIX).ok()?;
+    key.push_str(rest).ok()?;
+    Some(key)
+}
+
+/// Prefix of the messages queued for the session `session_key`
+pub(crate) fn queued_prefix<const L: usize>(session_key: &str) -> Option<Key<L>> {
+    let mut key = String::new();
+    key.push_str(QUEUED_PREFIX).ok()?;
+    key.push_str(session_key.strip_prefix(SESSION_PREFIX)?)
+        .ok()?;
+    key.push('\0').ok()?;
+    Some(key)
+}
+
+/// Key of the message `sequence` queued for the session `session_key`
+pub(crate) fn queued_key<const L: usize>(session_key: &str, sequence: u32) -> Option<Key<L>> {
+    let mut key = queued_prefix(session_key)?;
+    write!(key, "{:08x}", sequence).ok()?;
+    Some(key)
+}
+
+/// Key-value store used by the distributor
+pub trait Storage {
+    /// calls `f` with the value of `key`, returns false if there is none
+    fn get(&self, key: &str, f: &mut dyn FnMut(&[u8])) -> bool;
+    /// stores `value` under `key`, an empty value removes the key
+    fn set(&self, key: &str, value: &[u8]) -> Result<(), DistributorError>;
+    /// calls `f` with every key starting with `prefix`, without the prefix, and its value
+    fn for_each(&self, prefix: &str, f: &mut dyn FnMut(&str, &[u8]));
+}
+
+struct Entry<const SIZE: usize, const KEY_LENGTH: usize> {
+    key: Key<KEY_LENGTH>,
+    /// empty if the key was removed, but the removal has not been saved yet
+    value: Vec<u8, SIZE>,
+    /// changed since it was saved
+    dirty: bool,
+}
+
+struct State<const COUNT: usize, const SIZE: usize, const KEY_LENGTH: usize> {
+    entries: Vec<Entry<SIZE, KEY_LENGTH>, COUNT>,
+    /// removed keys are only kept till they are saved if changes are tracked
+    tracking: bool,
+    waker: WakerRegistration,
+}
+
+/// Keeps up to COUNT values of up to SIZE bytes in RAM
+/// KEY_LENGTH has to be at least `key_length` of the `MAX_TOPIC_LENGTH` of the distributor,
+/// longer keys are rejected
+pub struct RamStorage<
+    M: RawMutex,
+    const COUNT: usize,
+    const SIZE: usize = DEFAULT_MAX_MESSAGE_SIZE,
+    const KEY_LENGTH: usize = DEFAULT_KEY_LENGTH,
+> {
+    state: BlockingMutex<M, RefCell<State<COUNT, SIZE, KEY_LENGTH>>>,
+}
+
+impl<M: RawMutex, const COUNT: usize, const SIZE: usize, const KEY_LENGTH: usize> Default
+    for RamStorage<M, COUNT, SIZE, KEY_LENGTH>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: RawMutex, const COUNT: usize, const SIZE: usize, const KEY_LENGTH: usize>
+    RamStorage<M, COUNT, SIZE, KEY_LENGTH>
+{
+    pub fn new() -> Self {
+        Self {
+            state: BlockingMutex::new(RefCell::new(State {
+                entries: Vec::new(),
+                tracking: false,
+                waker: WakerRegistration::new(),
+            })),
+        }
+    }
+
+    /// amount of stored keys
+    pub fn len(&self) -> usize {
+        self.state.lock(|state| {
+            let state = state.borrow();
+            state.entries.iter().filter(|e| !e.value.is_empty()).count()
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// inserts a value that has already been saved, used while loading
+    pub fn insert_saved(&self, key: &str, value: &[u8]) -> Result<(), DistributorError> {
+        self.insert(key, value, false)
+    }
+
+    /// keeps changes until they are marked with [`RamStorage::saved`]
+    pub fn track_changes(&self) {
+        self.state.lock(|state| state.borrow_mut().tracking = true);
+    }
+
+    /// copies a changed entry into `key` and `value`, the value is empty if the key was removed
+    /// the entry stays changed till it is marked with [`RamStorage::saved`]
+    /// returns false if there are no more changes
+    pub fn next_change(&self, key: &mut Key<KEY_LENGTH>, value: &mut Vec<u8, SIZE>) -> bool {
+        self.state.lock(|state| {
+            let state = state.borrow();
+            let Some(entry) = state.entries.iter().find(|e| e.dirty) else {
+                return false;
+            };
+            key.clone_from(&entry.key);
+            value.clone_from(&entry.value);
+            true
+        })
+    }
+
+    /// marks the change of `key` to `value` as saved, an entry that changed again in the
+    /// meantime stays changed
+    pub fn saved(&self, key: &str, value: &[u8]) {
+        self.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            let Some(i) = state
+                .entries
+                .iter()
+                .position(|e| e.key == key && e.value.as_slice() == value)
+            else {
+                return;
+            };
+            if value.is_empty() {
+                state.entries.swap_remove(i);
+            } else {
+                state.entries[i].dirty = false;
+            }
+        })
+    }
+
+    /// waits till an entry changed
+    pub async fn changed(&self) {
+        poll_fn(|cx| {
+            self.state.lock(|state| {
+                let mut state = state.borrow_mut();
+                if state.entries.iter().any(|e| e.dirty) {
+                    Poll::Ready(())
+                } else {
+                    state.waker.register(cx.waker());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+
+    fn insert(&self, key: &str, value: &[u8], dirty: bool) -> Result<(), DistributorError> {
+        let value = Vec::from_slice(value).map_err(|()| DistributorError::MessageTooLong)?;
+        self.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            let tracking = state.tracking;
+            match state.entries.iter().position(|e| e.key == key) {
+                Some(i) if value.is_empty() && !tracking => {
+                    state.entries.swap_remove(i);
+                }
+                Some(i) => {
+                    let entry = &mut state.entries[i];
+                    entry.value = value;
+                    entry.dirty = dirty;
+                }
+                None if value.is_empty() => return Ok(()),
+                None => {
+                    let key = Key::<KEY_LENGTH>::try_from(key)
+                        .map_err(|()| DistributorError::TopicTooLong)?;
+                    state
+                        .entries
+                        .push(Entry { key, value, dirty })
+                        .map_err(|_| DistributorError::QueueFull)?;
+                }
+            }
+            if dirty && tracking {
+                state.waker.wake();
+            }
+            Ok(())
+        })
+    }
+}
+
+impl<M: RawMutex, const COUNT: usize, const SIZE: usize, const KEY_LENGTH: usize> Storage
+    for RamStorage<M, COUNT, SIZE, KEY_LENGTH>
+{
+    fn get(&self, key: &str, f: &mut dyn FnMut(&[u8])) -> bool {
+        self.state.lock(|state| {
+            let state = state.borrow();
+            match state
+                .entries
+                .iter()
+                .find(|e| e.key == key && !e.value.is_empty())
+            {
+                Some(entry) => {
+                    f(&entry.value);
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+
+    fn set(&self, key: &str, value: &[u8]) -> Result<(), DistributorError> {
+        self.insert(key, value, true)
+    }
+
+    fn for_each(&self, prefix: &str, f: &mut dyn FnMut(&str, &[u8])) {
+        self.state.lock(|state| {
+            let state = state.borrow();
+            for entry in state.entries.iter().filter(|e| !e.value.is_empty()) {
+                if let Some(key) = entry.key.strip_prefix(prefix) {
+                    f(key, &entry.value);
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DEFAULT_MAX_TOPIC_LENGTH, MAX_CLIENT_ID_LENGTH};
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    #[test]
+    fn test_changes() {
+        let storage = RamStorage::<NoopRawMutex, 2, 8>::new();
+        storage.insert_saved("ra", b"1").unwrap();
+        storage.set("rb", b"2").unwrap();
+        assert!(matches!(
+            storage.set("rc", b"3"),
+            Err(DistributorError::QueueFull)
+        ));
+        // removed right away as long as changes are not tracked
+        storage.set("rb", b"").unwrap();
+        assert_eq!(storage.len(), 1);
+
+        storage.track_changes();
+        storage.set("ra", b"").unwrap();
+        storage.set("rb", b"4").unwrap();
+        assert!(!storage.get("ra", &mut |_| {}));
+        let mut key = Key::new();
+        let mut value = Vec::new();
+        let mut changes = Vec::<(Key, Vec<u8, 8>), 4>::new();
+        while storage.next_change(&mut key, &mut value) {
+            changes.push((key.clone(), value.clone())).unwrap();
+            // a change that is not marked as saved, like after a failed write, is taken again
+            if changes.len() > 1 {
+                storage.saved(&key, &value);
+            }
+        }
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[0], changes[1]);
+        assert!(changes.iter().any(|(k, v)| k == "ra" && v.is_empty()));
+        assert!(changes
+            .iter()
+            .any(|(k, v)| k == "rb" && v.as_slice() == b"4"));
+
+        let mut topics = Vec::<Key, 2>::new();
+        storage.for_each(RETAINED_PREFIX, &mut |topic, _| {
+            topics.push(topic.try_into().unwrap()).unwrap()
+        });
+        assert_eq!(topics.as_slice(), ["b"]);
+    }
+
+    #[test]
+    fn test_session_keys() {
+        let key = |mount_point, client_id| -> Option<Key> { session_key(mount_point, client_id) };
+        // the mount point and the client id can not be shifted into each other
+        assert_ne!(key(Some("a"), "bc"), key(Some("ab"), "c"));
+        assert_ne!(key(None, "ab/c"), key(Some("ab/"), "c"));
+        let session = key(Some("ab/"), "c").unwrap();
+        let queued: Key = queued_key(&session, 1).unwrap();
+        assert_ne!(
+            Some(&queued),
+            queued_key(&key(None, "ab/c").unwrap(), 1).as_ref()
+        );
+
+        // the session is found again from the key of a queued message
+        let rest = queued.strip_prefix(QUEUED_PREFIX).unwrap();
+        let (stored, sequence) = rest.rsplit_once('\0').unwrap();
+        assert_eq!(stored_session_key(stored), Some(session));
+        assert_eq!(sequence, "00000001");
+
+        // keys that do not fit are not truncated
+        let mut mount_point = String::<DEFAULT_MAX_TOPIC_LENGTH>::new();
+        while mount_point.push('m').is_ok() {}
+        let mut client_id = String::<{ MAX_CLIENT_ID_LENGTH + 1 }>::new();
+        while client_id.push('c').is_ok() {}
+        assert_eq!(key(Some(&mount_point), &client_id), None);
+        let session = key(Some(&mount_point), &client_id[1..]).unwrap();
+        assert!(queued_key::<DEFAULT_KEY_LENGTH>(&session, u32::MAX).is_some());
+    }
+}
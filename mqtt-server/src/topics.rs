@@ -1,201 +1,348 @@
-use core::usize;
-use heapless::{FnvIndexSet, String, Vec};
 use crate::bitset::BitSet;
+use crate::config::{SubscriberBitSet, DEFAULT_MAX_TOPIC_LENGTH, DEFAULT_SUBSCRIBER_WORDS};
+use crate::errors::TopicsError;
+use heapless::{String, Vec};
 
+/// Maximum amount of levels a subscription can have
 const MAX_DEPTH: usize = 16;
+/// Index of the root node, it stands for the start of a topic and has no subscribers
+const ROOT: usize = 0;
+/// Matches the remaining levels of a topic, including none
+const MULTI_LEVEL: &str = "#";
+/// Matches a single level of a topic
+const SINGLE_LEVEL: &str = "+";
 
-// cant use const generics because https://blog.rust-lang.org/inside-rust/2021/09/06/Splitting-const-generics.html#featuregeneric_const_exprs
-#[derive(Debug, Default)]
-struct Node<const N: usize> {
-    name: String<64>,
-    subscribers: BitSet<64>, // max amount of subscribers
+#[derive(Debug)]
+struct Node<const SUBSCRIBER_WORDS: usize, const MAX_TOPIC_LENGTH: usize> {
+    /// topic level, can be `+` or `#` as well
+    name: String<MAX_TOPIC_LENGTH>,
+    subscribers: SubscriberBitSet<SUBSCRIBER_WORDS>,
+    /// subscribers that do not get their own publishes
+    no_local: SubscriberBitSet<SUBSCRIBER_WORDS>,
     parent: Option<usize>,
-    children: BitSet<64>, // max amount of nodes
+    /// children are a linked list, so the amount of nodes is only limited by the tree size
+    first_child: Option<usize>,
+    next_sibling: Option<usize>,
 }
 
-impl<const N: usize> Node<N> {
-    fn new(name: &str, parent: Option<usize>) -> Self {
+impl<const SUBSCRIBER_WORDS: usize, const MAX_TOPIC_LENGTH: usize>
+    Node<SUBSCRIBER_WORDS, MAX_TOPIC_LENGTH>
+{
+    fn new(name: String<MAX_TOPIC_LENGTH>, parent: Option<usize>) -> Self {
         Self {
-            name: String::try_from(name).unwrap(),
+            name,
             subscribers: BitSet::default(),
+            no_local: BitSet::default(),
             parent,
-            children: BitSet::default(),
+            first_child: None,
+            next_sibling: None,
         }
     }
 }
 
+/// Subscription index, every topic level is a node of the tree
+/// N is the maximum amount of nodes, including the root
+/// SUBSCRIBER_WORDS is the size of the subscriber bitsets
+/// MAX_TOPIC_LENGTH is the maximum length of a subscription
 #[derive(Debug)]
-pub struct Tree<const N: usize> {
-    nodes: Vec<Option<Node<N>>, N>,
+pub struct Tree<
+    const N: usize,
+    const SUBSCRIBER_WORDS: usize = DEFAULT_SUBSCRIBER_WORDS,
+    const MAX_TOPIC_LENGTH: usize = DEFAULT_MAX_TOPIC_LENGTH,
+> {
+    nodes: [Option<Node<SUBSCRIBER_WORDS, MAX_TOPIC_LENGTH>>; N],
 }
 
-impl<const N: usize> Tree<N> {
-    pub fn new() -> Self {
-        let mut nodes = Vec::new();
-        let root = Node::new("", None);
-        nodes.push(Some(root)).unwrap();
-        for _ in 1..N {
-            nodes
-                .push(None)
-                .unwrap_or_else(|_| panic!("Failed to create tree"));
-        }
+impl<const N: usize, const SUBSCRIBER_WORDS: usize, const MAX_TOPIC_LENGTH: usize> Default
+    for Tree<N, SUBSCRIBER_WORDS, MAX_TOPIC_LENGTH>
+{
+    fn default() -> Self {
+        let mut nodes: [Option<Node<SUBSCRIBER_WORDS, MAX_TOPIC_LENGTH>>; N] =
+            core::array::from_fn(|_| None);
+        nodes[ROOT] = Some(Node::new(String::new(), None));
         Self { nodes }
     }
-    fn insert_node(&mut self, val: &str, parent: Option<usize>) -> Option<usize> {
-        let node = Node::new(val, parent);
-        // find first free slot
-        let mut id = None;
-        for (i, n) in self.nodes.iter_mut().enumerate() {
-            if n.is_none() {
-                *n = Some(node);
-                id = Some(i);
-                break;
+}
+
+impl<const N: usize, const SUBSCRIBER_WORDS: usize, const MAX_TOPIC_LENGTH: usize>
+    Tree<N, SUBSCRIBER_WORDS, MAX_TOPIC_LENGTH>
+{
+    /// takes the first free slot for a new node
+    fn insert_node(&mut self, name: &str, parent: usize) -> Result<usize, TopicsError> {
+        let name = String::try_from(name).map_err(|_| TopicsError::TopicTooLong)?;
+        let id = self
+            .nodes
+            .iter()
+            .position(|n| n.is_none())
+            .ok_or(TopicsError::Full)?;
+        let mut node = Node::new(name, Some(parent));
+        node.next_sibling = self.get_node(parent).first_child;
+        self.nodes[id] = Some(node);
+        self.get_mut_node(parent).first_child = Some(id);
+        Ok(id)
+    }
+    pub(crate) fn insert(&mut self, topic: &str, id: usize) -> Result<(), TopicsError> {
+        if topic.len() > MAX_TOPIC_LENGTH {
+            return Err(TopicsError::TopicTooLong);
+        }
+        if !is_valid_filter(topic) {
+            return Err(TopicsError::InvalidFilter);
+        }
+        let mut node = ROOT;
+        for (depth, name) in levels(topic).enumerate() {
+            if depth >= MAX_DEPTH {
+                self.remove_if_not_needed(node);
+                return Err(TopicsError::TopicTooLong);
             }
+            node = match self.get_child_id(node, name) {
+                Some(child) => child,
+                None => match self.insert_node(name, node) {
+                    Ok(child) => child,
+                    Err(e) => {
+                        // nodes created for this subscription are not needed anymore
+                        self.remove_if_not_needed(node);
+                        return Err(e);
+                    }
+                },
+            };
         }
-        if let Some(parent) = parent {
-            self.get_mut_node(parent)
-                .children
-                .set(id.unwrap());
+        let node = self.get_mut_node(node);
+        node.subscribers.set(id);
+        node.no_local.unset(id);
+        Ok(())
+    }
+    /// makes an inserted subscription of socket `id` skip the publishes of the socket itself
+    pub(crate) fn set_no_local(&mut self, topic: &str, id: usize) {
+        if let Some(node) = self.find(topic) {
+            self.get_mut_node(node).no_local.set(id);
         }
-        id
     }
-    pub fn insert(&mut self, topic: &str, subscription: usize) {
-        let mut parent = 0; // root
-        for name in topic.split('/') {
-            if name.is_empty() {
-                continue;
-            }
-            if let Some(child_id) = self.get_child_id(parent, name) {
-                parent = child_id;
-            } else {
-                let child_id = self.insert_node(name, Some(parent));
-                parent = child_id.unwrap();
-            }
+    /// finds the node of a subscription, wildcards are compared by name
+    fn find(&self, topic: &str) -> Option<usize> {
+        let mut node = ROOT;
+        for name in levels(topic) {
+            node = self.get_child_id(node, name)?;
         }
-        self.get_mut_node(parent)
-            .subscribers
-            .set(subscription);
-
+        Some(node)
     }
-    pub fn get_ancestors(&self, topic: &str) -> Vec<usize, MAX_DEPTH> {
-        let mut node = 0; // root
-        let mut ancestors = Vec::<usize, MAX_DEPTH>::new();
-        for name in topic.split('/') {
-            if name.is_empty() {
-                continue;
-            }
-            if let Some(child_id) = self.get_child_id(node, name) {
-                node = child_id;
-                ancestors.push(node).unwrap();
-            } else {
-                ancestors.clear();
-                return ancestors;
-            }
+    fn get_node(&self, id: usize) -> &Node<SUBSCRIBER_WORDS, MAX_TOPIC_LENGTH> {
+        self.nodes[id].as_ref().unwrap()
+    }
+    fn get_mut_node(&mut self, id: usize) -> &mut Node<SUBSCRIBER_WORDS, MAX_TOPIC_LENGTH> {
+        self.nodes[id].as_mut().unwrap()
+    }
+    fn children(&self, parent: usize) -> impl Iterator<Item = usize> + '_ {
+        core::iter::successors(self.get_node(parent).first_child, |child| {
+            self.get_node(*child).next_sibling
+        })
+    }
+    fn get_child_id(&self, parent: usize, name: &str) -> Option<usize> {
+        self.children(parent)
+            .find(|child| self.get_node(*child).name == name)
+    }
+    /// takes a node out of the children of its parent
+    fn unlink(&mut self, parent: usize, node_id: usize) {
+        let next = self.get_node(node_id).next_sibling;
+        if self.get_node(parent).first_child == Some(node_id) {
+            self.get_mut_node(parent).first_child = next;
+            return;
         }
-        ancestors
-    }
-    fn get_mut_node(&mut self, id: usize) -> &mut Node<N> {
-        self.nodes.get_mut(id).unwrap().as_mut().unwrap()
-    }
-    fn remove_if_not_needed(&mut self, node_id: usize) {
-        let node = self.get_mut_node(node_id);
-        if node.subscribers.is_empty() && node.children.is_empty() {
-            // if not root
-            if let Some(parent) = node.parent {
-                let parent_node = self.get_mut_node(parent);
-                parent_node.children.unset(node_id);
-                // recursion
-                *self.nodes.get_mut(node_id).unwrap() = None;
-                self.remove_if_not_needed(parent);
+        let previous = self
+            .children(parent)
+            .find(|child| self.get_node(*child).next_sibling == Some(node_id))
+            .unwrap();
+        self.get_mut_node(previous).next_sibling = next;
+    }
+    /// removes the node and all its ancestors that have neither subscribers nor children
+    fn remove_if_not_needed(&mut self, mut node_id: usize) {
+        loop {
+            let node = self.get_node(node_id);
+            if !node.subscribers.is_empty() || node.first_child.is_some() {
+                return;
             }
+            // root is never removed
+            let Some(parent) = node.parent else {
+                return;
+            };
+            self.unlink(parent, node_id);
+            self.nodes[node_id] = None;
+            node_id = parent;
         }
     }
-    pub fn remove(&mut self, topic: &str, subscription: usize) {
-        let ancestors = self.get_ancestors(topic);
-        if ancestors.is_empty() {
-            return;
+    pub(crate) fn remove(&mut self, topic: &str, id: usize) {
+        if let Some(node) = self.find(topic) {
+            let node_ref = self.get_mut_node(node);
+            node_ref.subscribers.unset(id);
+            node_ref.no_local.unset(id);
+            self.remove_if_not_needed(node);
         }
-
-        if let Some(last) = ancestors.iter().last() {
-            let subscribers = self.get_mut_node(*last);
-            subscribers.subscribers.unset(subscription);
-            self.remove_if_not_needed(*last);
-        }
-    }
-    pub fn remove_all_subscriptions(&mut self, id: usize) {
-        for i in 1..N {
-            if let Some(node) = self.nodes.get_mut(i).as_mut() {
-                if let Some(node) = node {
-                    node.subscribers.unset(id);
-                    self.remove_if_not_needed(i);
-                }
+    }
+    pub(crate) fn remove_all_subscriptions(&mut self, id: usize) {
+        for i in 0..N {
+            if let Some(node) = self.nodes[i].as_mut() {
+                node.subscribers.unset(id);
+                node.no_local.unset(id);
+                self.remove_if_not_needed(i);
             }
         }
     }
-    fn get_child_id(&self, parent: usize, name: &str) -> Option<usize> {
-        let parent = self.nodes[parent].as_ref()?;
-        for child_id in parent.children.iter_ones() {
-            let child = self.nodes[child_id].as_ref()?;
-            if child.name == name {
-                return Some(child_id);
+    pub(crate) fn get_subscribed(&self, topic: &str) -> SubscriberBitSet<SUBSCRIBER_WORDS> {
+        let mut subscribers = BitSet::default();
+        self.collect_subscribers(ROOT, levels(topic), &mut |node| {
+            subscribers.union(&node.subscribers)
+        });
+        subscribers
+    }
+    /// true if socket `id` subscribed to `topic` only with subscriptions that skip its own
+    /// publishes, see [`Self::set_no_local`]
+    pub(crate) fn no_local(&self, topic: &str, id: usize) -> bool {
+        let (mut subscribed, mut local) = (false, false);
+        self.collect_subscribers(ROOT, levels(topic), &mut |node| {
+            if node.subscribers.get(id) {
+                subscribed = true;
+                local |= !node.no_local.get(id);
+            }
+        });
+        subscribed && !local
+    }
+    /// calls `f` with every node whose subscription matches the topic, a subscription matches
+    /// topics with the same amount of levels, `+` matches any single level and `#` the remaining
+    /// levels, so `a/#` matches `a` as well, see [`matches`]
+    fn collect_subscribers<'a>(
+        &self,
+        node_id: usize,
+        mut levels: impl Iterator<Item = &'a str> + Clone,
+        f: &mut impl FnMut(&Node<SUBSCRIBER_WORDS, MAX_TOPIC_LENGTH>),
+    ) {
+        let node = self.get_node(node_id);
+        let level = levels.next();
+        if level.is_none() {
+            f(node);
+        }
+        // wildcards on the first level do not match topics starting with `$`, like `$SYS`
+        let system = node_id == ROOT && level.is_some_and(|l| l.starts_with('$'));
+        for child in self.children(node_id) {
+            let child_node = self.get_node(child);
+            match (child_node.name.as_str(), level) {
+                (MULTI_LEVEL | SINGLE_LEVEL, _) if system => {}
+                // `#` is always the last level of a subscription
+                (MULTI_LEVEL, _) => f(child_node),
+                (name, Some(level)) if name == level || name == SINGLE_LEVEL => {
+                    self.collect_subscribers(child, levels.clone(), f)
+                }
+                _ => {}
             }
         }
-        return None;
     }
-    fn get_node(&self, id: usize) -> &Node<N> {
-        self.nodes[id].as_ref().unwrap()
+    /// amount of nodes in use, including the root
+    pub(crate) fn len(&self) -> usize {
+        self.nodes.iter().filter(|n| n.is_some()).count()
+    }
+    /// amount of topic filters socket `id` subscribed to
+    pub(crate) fn subscriptions(&self, id: usize) -> usize {
+        self.nodes
+            .iter()
+            .flatten()
+            .filter(|n| n.subscribers.get(id))
+            .count()
     }
-    pub fn get_subscribed(&self, topic: &str) -> FnvIndexSet<usize, N> {
-        let mut parent = 0; // root
-        let mut subscribers = FnvIndexSet::new();
-        for name in topic.split('/') {
-            if name.is_empty() {
+    /// calls `f` for every subscribed topic filter, rebuilt from the levels in the tree
+    pub(crate) fn for_each_subscription(
+        &self,
+        mut f: impl FnMut(&String<MAX_TOPIC_LENGTH>, &SubscriberBitSet<SUBSCRIBER_WORDS>),
+    ) {
+        for (id, node) in self.nodes.iter().enumerate() {
+            let Some(node) = node else {
+                continue;
+            };
+            if node.subscribers.is_empty() {
                 continue;
             }
-            if let Some(child_id) = self.get_child_id(parent, name) {
-                parent = child_id;
-                self.get_node(parent)
-                    .subscribers
-                    .iter_ones()
-                    .for_each(|s| {
-                        subscribers.insert(s).unwrap();
-                    });
-            } else {
-                // return no subscribers if no child found
-                return subscribers;
+            f(&self.path(id), &node.subscribers);
+        }
+    }
+    fn path(&self, mut node_id: usize) -> String<MAX_TOPIC_LENGTH> {
+        let mut ancestors = Vec::<usize, MAX_DEPTH>::new();
+        while let Some(parent) = self.get_node(node_id).parent {
+            // insert makes sure a subscription is at most MAX_DEPTH levels deep
+            ancestors.push(node_id).unwrap();
+            node_id = parent;
+        }
+        let mut path = String::new();
+        for (i, id) in ancestors.iter().rev().enumerate() {
+            if i > 0 {
+                // insert makes sure the subscription is at most MAX_TOPIC_LENGTH long
+                path.push('/').unwrap();
             }
+            path.push_str(&self.get_node(*id).name).unwrap();
         }
+        path
+    }
+}
 
-        subscribers
+/// splits a topic into its levels, empty levels are levels as well, so `/a` and `a` differ
+fn levels(topic: &str) -> impl Iterator<Item = &str> + Clone {
+    topic.split('/')
+}
+
+/// Whether `filter` matches `topic`, the tree gives the same result for all of its filters
+pub(crate) fn matches(filter: &str, topic: &str) -> bool {
+    // wildcards on the first level do not match topics starting with `$`, like `$SYS`
+    if topic.starts_with('$') && filter.starts_with(['#', '+']) {
+        return false;
+    }
+    let mut filter = levels(filter);
+    let mut topic = levels(topic);
+    loop {
+        match (filter.next(), topic.next()) {
+            // matches the remaining levels, including none
+            (Some(MULTI_LEVEL), _) => return true,
+            (Some(f), Some(t)) if f == SINGLE_LEVEL || f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// wildcards have to fill a whole level and `#` has to be the last one
+pub(crate) fn is_valid_filter(filter: &str) -> bool {
+    if filter.is_empty() {
+        return false;
+    }
+    let mut levels = levels(filter).peekable();
+    while let Some(level) = levels.next() {
+        let last = levels.peek().is_none();
+        match level {
+            MULTI_LEVEL if !last => return false,
+            MULTI_LEVEL | SINGLE_LEVEL => {}
+            _ if level.contains(['+', '#']) => return false,
+            _ => {}
+        }
     }
+    true
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use log;
-    fn to_vec<const N: usize>(set: FnvIndexSet<usize, N>) -> Vec<usize, N> {
-        let mut vec = Vec::new();
-        for s in set.iter() {
-            vec.push(*s).unwrap();
-        }
-        vec.sort_unstable();
-        vec
+    use crate::config::Topic;
+    use core::fmt::Write;
+
+    fn to_vec(set: SubscriberBitSet) -> Vec<usize, 64> {
+        set.iter_ones().collect()
     }
     #[test]
     fn test_tree() {
-        let mut tree = Tree::<64>::new();
-        tree.insert("/a/b", 1);
-        tree.insert("/a/b", 3);
-        tree.insert("/a/b", 4);
-        tree.insert("/a/b", 6);
-        tree.insert("/c/b", 7);
+        let mut tree = Tree::<64>::default();
+        tree.insert("/a/b", 1).unwrap();
+        tree.insert("/a/b", 3).unwrap();
+        tree.insert("/a/b", 4).unwrap();
+        tree.insert("/a/b", 6).unwrap();
+        tree.insert("/c/b", 7).unwrap();
         assert_eq!(to_vec(tree.get_subscribed("/a/b")).as_slice(), [1, 3, 4, 6]);
-        assert_eq!(
-            to_vec(tree.get_subscribed("/a/b/c")).as_slice(),
-            [1, 3, 4, 6]
-        );
-        assert_eq!(to_vec(tree.get_subscribed("/a/d")).as_slice(), []);
+        // subscriptions do not match the levels below them
+        assert!(to_vec(tree.get_subscribed("/a/b/c")).is_empty());
+        assert!(to_vec(tree.get_subscribed("/a/d")).is_empty());
         assert_eq!(to_vec(tree.get_subscribed("/c/b")).as_slice(), [7]);
         tree.remove("/a/b", 6);
         assert_eq!(to_vec(tree.get_subscribed("/a/b")).as_slice(), [1, 3, 4]);
@@ -204,16 +351,83 @@ mod tests {
         tree.remove("/a/b", 4);
         assert_eq!(to_vec(tree.get_subscribed("/c/b")).as_slice(), [7]);
         tree.remove("/c/b", 7);
-        assert_eq!(to_vec(tree.get_subscribed("/c/b")).as_slice(), []);
+        assert!(to_vec(tree.get_subscribed("/c/b")).is_empty());
+    }
+    #[test]
+    fn test_wildcards() {
+        let mut tree = Tree::<64>::default();
+        tree.insert("/a/+/c", 1).unwrap();
+        tree.insert("/a/#", 2).unwrap();
+        tree.insert("/", 3).unwrap();
+        tree.insert("/a/b/c", 4).unwrap();
+        tree.insert("#", 5).unwrap();
+        tree.insert("/a", 6).unwrap();
+        assert_eq!(
+            to_vec(tree.get_subscribed("/a/b/c")).as_slice(),
+            [1, 2, 4, 5]
+        );
+        // `+` matches exactly one level
+        assert_eq!(to_vec(tree.get_subscribed("/a/x/c/d")).as_slice(), [2, 5]);
+        assert_eq!(to_vec(tree.get_subscribed("/a/x/d")).as_slice(), [2, 5]);
+        // `#` matches its parent level as well
+        assert_eq!(to_vec(tree.get_subscribed("/a")).as_slice(), [2, 5, 6]);
+        assert_eq!(to_vec(tree.get_subscribed("/b")).as_slice(), [5]);
+        assert_eq!(to_vec(tree.get_subscribed("/")).as_slice(), [3, 5]);
+        // wildcards are removed by name
+        tree.remove("/a/+/c", 1);
+        assert_eq!(to_vec(tree.get_subscribed("/a/x/c")).as_slice(), [2, 5]);
+
+        for filter in ["/a/#/c", "/a+", "#/a", "a/b#"] {
+            assert!(
+                matches!(tree.insert(filter, 7), Err(TopicsError::InvalidFilter)),
+                "{}",
+                filter
+            );
+        }
+    }
+    #[test]
+    fn test_same_as_matches() {
+        let subscriptions = [
+            "/a/b/c", "/", "/a/b//c", "/a/+/c", "/a/b/c", "a/#", "/d", "/a/b/d", "+/+",
+        ];
+        let topics = [
+            "/a/b/c",
+            "//a/b/c",
+            "/a/b/c/d/e/f",
+            "/a/b/d",
+            "/",
+            "/d",
+            "/a",
+            "e/f",
+            "$SYS/a",
+        ];
+        let mut tree = Tree::<64>::default();
+        for (id, subscription) in subscriptions.iter().enumerate() {
+            tree.insert(subscription, id).unwrap();
+        }
+        for topic in topics {
+            let mut expected = SubscriberBitSet::default();
+            for (id, subscription) in subscriptions.iter().enumerate() {
+                if matches(subscription, topic) {
+                    expected.set(id);
+                }
+            }
+            assert_eq!(
+                to_vec(tree.get_subscribed(topic)),
+                to_vec(expected),
+                "topic {}",
+                topic
+            );
+        }
     }
     #[test]
     fn test_cleanup() {
-        let mut tree = Tree::<64>::new();
-        tree.insert("/a/b", 1);
-        tree.insert("/a/b", 2);
-        tree.insert("/a/b", 3);
-        tree.insert("/c/b", 1);
-        tree.insert("/b/b/c/d", 1);
+        let mut tree = Tree::<64>::default();
+        tree.insert("/a/b", 1).unwrap();
+        tree.insert("/a/b", 2).unwrap();
+        tree.insert("/a/b", 3).unwrap();
+        tree.insert("/c/b", 1).unwrap();
+        tree.insert("/b/b/c/d", 1).unwrap();
         tree.remove_all_subscriptions(1);
         assert_eq!(to_vec(tree.get_subscribed("/a/b")).as_slice(), [2, 3]);
         tree.remove("/a/b", 2);
@@ -221,4 +435,99 @@ mod tests {
         // only root should be left
         assert_eq!(tree.nodes.iter().filter(|n| n.is_some()).count(), 1);
     }
+    #[test]
+    fn test_full() {
+        let mut tree = Tree::<4>::default();
+        tree.insert("a/b/c", 1).unwrap();
+        assert!(matches!(tree.insert("d/e", 1), Err(TopicsError::Full)));
+        // the partially inserted subscription has been cleaned up
+        assert_eq!(tree.nodes.iter().filter(|n| n.is_some()).count(), 4);
+        tree.remove("a/b/c", 1);
+        tree.insert("d/e", 1).unwrap();
+        assert_eq!(to_vec(tree.get_subscribed("d/e")).as_slice(), [1]);
+    }
+    #[test]
+    fn test_many_nodes() {
+        let mut tree = Tree::<128>::default();
+        for id in 0..100 {
+            let mut topic = Topic::new();
+            write!(topic, "{}", id).unwrap();
+            tree.insert(&topic, id % 64).unwrap();
+        }
+        assert_eq!(tree.len(), 101);
+        assert_eq!(to_vec(tree.get_subscribed("99")).as_slice(), [35]);
+        for id in (0..100).step_by(2) {
+            let mut topic = Topic::new();
+            write!(topic, "{}", id).unwrap();
+            tree.remove(&topic, id % 64);
+        }
+        assert_eq!(tree.len(), 51);
+        assert!(to_vec(tree.get_subscribed("98")).is_empty());
+        assert_eq!(to_vec(tree.get_subscribed("97")).as_slice(), [33]);
+    }
+    #[test]
+    fn test_for_each_subscription() {
+        let mut tree = Tree::<64>::default();
+        tree.insert("/a/+/c", 1).unwrap();
+        tree.insert("/", 2).unwrap();
+        let mut subscriptions = Vec::<Topic, 4>::new();
+        tree.for_each_subscription(|topic, _| subscriptions.push(topic.clone()).unwrap());
+        assert_eq!(subscriptions.as_slice(), ["/a/+/c", "/"]);
+    }
+    #[test]
+    fn test_empty_levels() {
+        let mut tree = Tree::<64>::default();
+        tree.insert("/a", 1).unwrap();
+        tree.insert("a", 2).unwrap();
+        tree.insert("a//b", 3).unwrap();
+        tree.insert("+/a", 4).unwrap();
+        assert_eq!(to_vec(tree.get_subscribed("/a")).as_slice(), [1, 4]);
+        assert_eq!(to_vec(tree.get_subscribed("a")).as_slice(), [2]);
+        assert_eq!(to_vec(tree.get_subscribed("a//b")).as_slice(), [3]);
+        assert!(to_vec(tree.get_subscribed("a/b")).is_empty());
+        assert!(!matches("/a", "a"));
+        assert!(!matches("a//b", "a/b"));
+        assert!(matches("+/a", "/a"));
+    }
+    #[test]
+    fn test_system_topics() {
+        let mut tree = Tree::<64>::default();
+        tree.insert("#", 1).unwrap();
+        tree.insert("+/status", 2).unwrap();
+        tree.insert("$SYS/#", 3).unwrap();
+        tree.insert("$CONTROL/+", 4).unwrap();
+        tree.insert("a/#", 5).unwrap();
+        assert_eq!(to_vec(tree.get_subscribed("$SYS/status")).as_slice(), [3]);
+        assert_eq!(
+            to_vec(tree.get_subscribed("$CONTROL/clients")).as_slice(),
+            [4]
+        );
+        assert_eq!(
+            to_vec(tree.get_subscribed("a/status")).as_slice(),
+            [1, 2, 5]
+        );
+        // `$` is only special at the start of a topic
+        assert_eq!(to_vec(tree.get_subscribed("a/$SYS")).as_slice(), [1, 5]);
+        assert!(!matches("#", "$SYS/status"));
+        assert!(!matches("+/status", "$SYS/status"));
+        assert!(matches("$SYS/#", "$SYS/status"));
+    }
+    #[test]
+    fn test_no_local() {
+        let mut tree = Tree::<64>::default();
+        tree.insert("a/#", 1).unwrap();
+        tree.set_no_local("a/#", 1);
+        tree.insert("a/b", 2).unwrap();
+        tree.set_no_local("a/b", 2);
+        tree.insert("+/b", 2).unwrap();
+        assert!(tree.no_local("a/b", 1));
+        // another subscription of socket 2 gets its own publishes
+        assert!(!tree.no_local("a/b", 2));
+        assert!(tree.no_local("a/c", 1));
+        assert!(!tree.no_local("b/b", 1));
+        // subscribing again replaces the option
+        tree.insert("a/#", 1).unwrap();
+        assert!(!tree.no_local("a/b", 1));
+        assert_eq!(to_vec(tree.get_subscribed("a/b")).as_slice(), [1, 2]);
+    }
 }
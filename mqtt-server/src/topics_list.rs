@@ -1,13 +1,21 @@
+#[cfg(feature = "benchmarks")]
 use crate::config::{SubscriberBitSet, Topic};
+#[cfg(feature = "benchmarks")]
 use crate::errors::TopicsError;
+#[cfg(feature = "benchmarks")]
 use heapless::{FnvIndexSet, String};
 
+/// Linear subscription index, replaced by [`Tree`](crate::topics::Tree)
+/// only kept as reference for the benchmarks, it matches subscriptions as prefixes and ignores
+/// empty levels, see [`matches`](crate::topics::matches) for the MQTT rules
+#[cfg(feature = "benchmarks")]
 #[derive(Debug, Default)]
-pub struct TopicsList<const N: usize, const MAX_SUBS: usize> {
+pub struct TopicsList<const N: usize> {
     topics: FnvIndexSet<(Topic, usize), N>,
 }
 
-impl<const N: usize, const MAX_SUBS: usize> TopicsList<N, MAX_SUBS> {
+#[cfg(feature = "benchmarks")]
+impl<const N: usize> TopicsList<N> {
     pub(crate) fn insert(&mut self, topic: &str, id: usize) -> Result<(), TopicsError> {
         let topic = String::try_from(topic).map_err(|_| TopicsError::TopicTooLong)?;
         self.topics
@@ -15,9 +23,6 @@ impl<const N: usize, const MAX_SUBS: usize> TopicsList<N, MAX_SUBS> {
             .map_err(|_| TopicsError::Full)?;
         Ok(())
     }
-    pub(crate) fn remove(&mut self, topic: &str, id: usize) {
-        self.topics.retain(|(t, i)| t.as_str() != topic || *i != id);
-    }
     pub(crate) fn get_subscribed(&self, topic: &str) -> SubscriberBitSet {
         let mut subscribers = SubscriberBitSet::default();
         for (t, i) in self.topics.iter() {
@@ -27,12 +32,9 @@ impl<const N: usize, const MAX_SUBS: usize> TopicsList<N, MAX_SUBS> {
         }
         subscribers
     }
-    pub(crate) fn remove_all_subscriptions(&mut self, id: usize) {
-        self.topics.retain(|(_, i)| *i != id);
-    }
 }
 
-fn listens_to_topic(subscription: &str, topic: &str) -> bool {
+pub(crate) fn listens_to_topic(subscription: &str, topic: &str) -> bool {
     let sub_iter = subscription.split('/');
     let topic_iter = topic.split('/');
 
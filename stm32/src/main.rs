@@ -11,15 +11,29 @@ use embassy_stm32::peripherals::ETH;
 use embassy_stm32::rng::Rng;
 use embassy_stm32::time::Hertz;
 use embassy_stm32::{bind_interrupts, eth, peripherals, rng, Config};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_time::Timer;
-use mqtt_server::config::InnerDistributorMutex;
+use mqtt_server::config::{subscriber_words, InnerDistributorMutex};
 use mqtt_server::distributor::InnerDistributor;
-use mqtt_server::socket::listen;
+use mqtt_server::acceptor::{ListenerConfig, DEFAULT_SOCKET_BUFFER_SIZE};
 use rand_core::RngCore;
 use static_cell::{make_static, StaticCell};
 use {defmt_rtt as _, panic_probe as _};
 
 const MAX_CONNECTIONS: usize = 3;
+// memory budget of the broker, see `mqtt_server::config` for the defaults
+const QUEUE_LEN: usize = 1;
+const TREE_SIZE: usize = 32;
+const MAX_MESSAGE_SIZE: usize = 1024;
+type Distributor = InnerDistributorMutex<
+    NoopRawMutex,
+    MAX_CONNECTIONS,
+    { subscriber_words(MAX_CONNECTIONS) },
+    QUEUE_LEN,
+    TREE_SIZE,
+    MAX_MESSAGE_SIZE,
+>;
+type Acceptor = mqtt_server::acceptor::Acceptor<NoopRawMutex, MAX_CONNECTIONS, MAX_CONNECTIONS>;
 
 bind_interrupts!(struct Irqs {
     ETH => eth::InterruptHandler;
@@ -107,10 +121,21 @@ async fn main(spawner: Spawner) -> ! {
 
     info!("Network task initialized");
 
-    let distributor = &*make_static!(InnerDistributorMutex::new(InnerDistributor::default()));
-    // spawn listeners for concurrent connections
-    for i in 0..MAX_CONNECTIONS {
-        spawner.spawn(listen_task(stack, i, 1883, distributor)).ok();
+    let distributor: &'static Distributor =
+        make_static!(InnerDistributorMutex::new(InnerDistributor::default()));
+    // every accepted connection leases a distributor slot and socket buffers from the acceptor
+    let buffers = make_static!(
+        [([0; DEFAULT_SOCKET_BUFFER_SIZE], [0; DEFAULT_SOCKET_BUFFER_SIZE]); MAX_CONNECTIONS]
+    );
+    let listener = ListenerConfig {
+        port: 1883,
+        ..Default::default()
+    };
+    let acceptor: &'static Acceptor =
+        make_static!(Acceptor::new(0, buffers).with_listener(listener).unwrap());
+    // spawn workers for concurrent connections
+    for _ in 0..MAX_CONNECTIONS {
+        spawner.spawn(listen_task(stack, acceptor, distributor)).ok();
     }
 
     if let Some(ip_config) = stack.config_v4() {
@@ -126,9 +151,8 @@ async fn main(spawner: Spawner) -> ! {
 #[embassy_executor::task(pool_size = MAX_CONNECTIONS)]
 async fn listen_task(
     stack: &'static Stack<Device>,
-    id: usize,
-    port: u16,
-    distributor: &'static InnerDistributorMutex<MAX_CONNECTIONS>,
+    acceptor: &'static Acceptor,
+    distributor: &'static Distributor,
 ) {
-    listen(stack, id, port, distributor).await
+    acceptor.work(stack, distributor).await
 }